@@ -0,0 +1,326 @@
+//! Spreads requests over slaves round-robin instead of letting every slave
+//! see every packet.
+//!
+//! Subscribes to the request topic (`data/request` unless `--request-topic`
+//! says otherwise) and forwards each message unchanged to one live slave's
+//! own request topic. Slaves are learned from `slaves/online/+` and kept alive
+//! by `slaves/heartbeat`; a slave that misses three heartbeats, announces
+//! itself on `slaves/offline` or clears its announcement is left out until
+//! it is heard from again.
+//! Responses don't pass through here: slaves publish them on the response
+//! topic as usual. With no live slave a message is dead-lettered
+//! (`--dead-letter <topic|off>`, `data/deadletter` by default).
+//!
+//! Slaves behind the balancer must not listen on its request topic, or they
+//! would also get every message directly; start them with another one, e.g.
+//! `slave --request-topic data/slaves`. Only slaves whose announcement gave
+//! their request topic are forwarded to; a heartbeat from a slave that hasn't
+//! announced itself is ignored.
+
+use base64::Engine;
+use mqtt::common::{connect, init_logging, leading_packet_id, Backoff, BrokerArgs, DeadLetter, Heartbeat, SlaveOnline};
+use rumqttc::{Client, Event, Packet, QoS};
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// What the connection thread hands to the dispatcher.
+enum Message {
+    Request(Vec<u8>),
+    Online(SlaveOnline),
+    Heartbeat(Heartbeat),
+    Offline(String),
+}
+
+struct Slave {
+    request_topic: String,
+    last_seen: Instant,
+    /// Expected time between heartbeats.
+    interval: Duration,
+}
+
+impl Slave {
+    fn is_alive(&self) -> bool {
+        self.last_seen.elapsed() <= self.interval * SlaveRegistry::MISSED_HEARTBEATS
+    }
+}
+
+/// The slaves the balancer can forward to, keyed by slave id.
+struct SlaveRegistry {
+    slaves: BTreeMap<String, Slave>,
+    next: usize,
+    /// The balancer's own request topic; slaves listening on it are skipped.
+    request_topic: String,
+}
+
+impl SlaveRegistry {
+    const MISSED_HEARTBEATS: u32 = 3;
+    /// Assumed until a slave's heartbeat says otherwise; the slave's default.
+    const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+    fn new(request_topic: String) -> Self {
+        Self { slaves: BTreeMap::new(), next: 0, request_topic }
+    }
+
+    fn online(&mut self, slave: SlaveOnline) {
+        let shared = slave.request_topic.strip_prefix(self.request_topic.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+        if shared {
+            warn!(
+                "Ignoring slave {}: it listens on {}, so it already gets every request; start it with another --request-topic",
+                slave.slave_id, self.request_topic
+            );
+            self.slaves.remove(&slave.slave_id);
+            return;
+        }
+        info!("Slave {} online at {}", slave.slave_id, slave.request_topic);
+        let entry = self.slaves.entry(slave.slave_id).or_insert_with(|| Slave {
+            request_topic: String::new(),
+            last_seen: Instant::now(),
+            interval: Self::DEFAULT_INTERVAL,
+        });
+        entry.request_topic = slave.request_topic;
+        entry.last_seen = Instant::now();
+    }
+
+    /// Keeps an announced slave alive. Without an announcement there is no
+    /// request topic to forward to, so the heartbeat is ignored.
+    fn heartbeat(&mut self, heartbeat: Heartbeat) {
+        let Some(slave) = self.slaves.get_mut(&heartbeat.slave_id) else {
+            debug!("Ignoring heartbeat from {}, which hasn't announced itself", heartbeat.slave_id);
+            return;
+        };
+        slave.last_seen = Instant::now();
+        slave.interval = match heartbeat.interval_secs {
+            0 => Self::DEFAULT_INTERVAL,
+            secs => Duration::from_secs(secs),
+        };
+    }
+
+    fn offline(&mut self, slave_id: &str) {
+        if self.slaves.remove(slave_id).is_some() {
+            info!("Slave {} went offline ({} left)", slave_id, self.slaves.len());
+        }
+    }
+
+    /// The next live slave, round-robin, as `(slave id, request topic)`.
+    fn next(&mut self) -> Option<(&str, &str)> {
+        let alive: Vec<_> = self.slaves.iter().filter(|(_, slave)| slave.is_alive()).collect();
+        if alive.is_empty() {
+            return None;
+        }
+        let (slave_id, slave) = alive[self.next % alive.len()];
+        self.next = self.next.wrapping_add(1);
+        Some((slave_id.as_str(), slave.request_topic.as_str()))
+    }
+}
+
+struct BalancerArgs {
+    dead_letter_topic: Option<String>,
+}
+
+fn balancer_args(args: Vec<String>) -> Result<BalancerArgs, String> {
+    let mut parsed = BalancerArgs { dead_letter_topic: Some(DeadLetter::TOPIC.to_string()) };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--dead-letter" => {
+                parsed.dead_letter_topic = match value.as_str() {
+                    "off" => None,
+                    topic => {
+                        DeadLetter::check_topic(topic)?;
+                        Some(value)
+                    }
+                };
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Forwards one request, or dead-letters it when no slave is alive.
+fn dispatch(client: &Client, registry: &mut SlaveRegistry, qos: QoS, dead_letter_topic: Option<&str>, payload: Vec<u8>) {
+    let packet_id = leading_packet_id(&payload);
+    let id = packet_id.as_deref().unwrap_or("<unknown>");
+    match registry.next() {
+        Some((slave_id, topic)) => {
+            info!(packet_id = %id, "Forwarding to {} on {}", slave_id, topic);
+            if let Err(e) = client.publish(topic, qos, false, payload) {
+                error!(packet_id = %id, "Failed to forward to {}: {:?}", slave_id, e);
+            }
+        }
+        None => {
+            warn!(packet_id = %id, "No slaves online, dead-lettering");
+            let Some(topic) = dead_letter_topic else {
+                return;
+            };
+            let dead_letter = match String::from_utf8(payload) {
+                Ok(raw) => DeadLetter { packet_id: packet_id.clone(), reason: "no slaves online".to_string(), raw, encoding: None },
+                Err(e) => DeadLetter {
+                    packet_id: packet_id.clone(),
+                    reason: "no slaves online".to_string(),
+                    raw: base64::engine::general_purpose::STANDARD.encode(e.as_bytes()),
+                    encoding: Some("base64".to_string()),
+                },
+            };
+            match serde_json::to_string(&dead_letter) {
+                Ok(dead_letter) => {
+                    if let Err(e) = client.publish(topic, QoS::AtLeastOnce, false, dead_letter) {
+                        error!(packet_id = %id, "Failed to publish dead letter: {:?}", e);
+                    }
+                }
+                Err(e) => error!("Failed to serialize dead letter: {:?}", e),
+            }
+        }
+    }
+}
+
+fn main() {
+    let parsed = BrokerArgs::extract(std::env::args().skip(1))
+        .and_then(|(broker, rest)| Ok((broker, balancer_args(rest)?)));
+    let (broker, args) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Invalid arguments: {}", e);
+            std::process::exit(2);
+        }
+    };
+    init_logging(broker.log_format);
+    let transport = match broker.transport() {
+        Ok(transport) => transport,
+        Err(e) => {
+            error!("Invalid TLS configuration: {}", e);
+            return;
+        }
+    };
+
+    let client_id = broker.client_id("balancer-");
+    let (client, mut connection) = connect(&client_id, &broker, transport, 100, None, None);
+
+    // Publishing can block on a full request queue, so the event loop runs
+    // on its own thread and only forwards what it receives to this one.
+    let (sender, received) = mpsc::channel::<Message>();
+    let request_topic = broker.topics.request.clone();
+    thread::spawn(move || {
+        let mut backoff = Backoff::from_env();
+        for event in connection.iter() {
+            let message = match event {
+                Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == request_topic => {
+                    Message::Request(publish.payload.to_vec())
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) if SlaveOnline::slave_id_from_topic(&publish.topic).is_some() => {
+                    // An empty payload clears the retained announcement of
+                    // a slave that shut down.
+                    if publish.payload.is_empty() {
+                        let slave_id = SlaveOnline::slave_id_from_topic(&publish.topic).unwrap_or_default();
+                        Message::Offline(slave_id.to_string())
+                    } else {
+                        match serde_json::from_slice(&publish.payload) {
+                            Ok(slave) => Message::Online(slave),
+                            Err(e) => {
+                                warn!("Ignoring malformed slave announcement: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == Heartbeat::TOPIC => {
+                    match serde_json::from_slice(&publish.payload) {
+                        Ok(heartbeat) => Message::Heartbeat(heartbeat),
+                        Err(e) => {
+                            warn!("Ignoring malformed heartbeat: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == Heartbeat::OFFLINE_TOPIC => {
+                    // Sent by a slave shutting down, or by the broker as its last will.
+                    Message::Offline(String::from_utf8_lossy(&publish.payload).into_owned())
+                }
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    backoff.reset();
+                    continue;
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!("Connection error: {}; reconnect attempt {} in {:?}", e, backoff.attempt(), delay);
+                    thread::sleep(delay);
+                    continue;
+                }
+            };
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    for (topic, qos) in [
+        (SlaveOnline::FILTER, QoS::AtLeastOnce),
+        (Heartbeat::TOPIC, QoS::AtMostOnce),
+        (Heartbeat::OFFLINE_TOPIC, QoS::AtLeastOnce),
+        (broker.topics.request.as_str(), broker.qos),
+    ] {
+        if let Err(e) = client.subscribe(topic, qos) {
+            error!("Failed to subscribe to {}: {:?}", topic, e);
+            return;
+        }
+    }
+    info!("Balancing {} over the slaves online", broker.topics.request);
+
+    let mut registry = SlaveRegistry::new(broker.topics.request.clone());
+    for message in received {
+        match message {
+            Message::Request(payload) => {
+                dispatch(&client, &mut registry, broker.qos, args.dead_letter_topic.as_deref(), payload)
+            }
+            Message::Online(slave) => registry.online(slave),
+            Message::Heartbeat(heartbeat) => registry.heartbeat(heartbeat),
+            Message::Offline(slave_id) => registry.offline(&slave_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(slave_id: &str, request_topic: &str) -> SlaveOnline {
+        SlaveOnline { slave_id: slave_id.to_string(), request_topic: request_topic.to_string() }
+    }
+
+    fn heartbeat(slave_id: &str) -> Heartbeat {
+        Heartbeat { slave_id: slave_id.to_string(), uptime_secs: 1, processed_count: 0, interval_secs: 5 }
+    }
+
+    #[test]
+    fn only_announced_slaves_are_forwarded_to() {
+        let mut registry = SlaveRegistry::new("data/request".to_string());
+        registry.heartbeat(heartbeat("unannounced"));
+        assert!(registry.next().is_none());
+
+        registry.online(announcement("a", "data/slaves/a"));
+        registry.online(announcement("b", "data/slaves/b"));
+        registry.heartbeat(heartbeat("a"));
+        let picked: Vec<String> = (0..4).map(|_| registry.next().unwrap().1.to_string()).collect();
+        assert_eq!(picked, ["data/slaves/a", "data/slaves/b", "data/slaves/a", "data/slaves/b"]);
+    }
+
+    #[test]
+    fn slaves_on_the_balancers_own_topic_are_left_out() {
+        let mut registry = SlaveRegistry::new("data/request".to_string());
+        registry.online(announcement("direct", "data/request"));
+        registry.online(announcement("nested", "data/request/nested"));
+        registry.heartbeat(heartbeat("direct"));
+        assert!(registry.next().is_none());
+
+        registry.online(announcement("elsewhere", "data/requests"));
+        assert_eq!(registry.next(), Some(("elsewhere", "data/requests")));
+        registry.offline("elsewhere");
+        assert!(registry.next().is_none());
+    }
+}
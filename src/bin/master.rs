@@ -1,104 +1,1564 @@
-use mqtt::common::{DataPacket, DataPayload, DataResponse};
-use rumqttc::{Client, MqttOptions, QoS};
-use std::{time::Duration, collections::HashMap};
+use mqtt::common::{
+    compress, connect, env_var, init_logging, Ack, Backoff, BrokerArgs, CoordinateSystem, DataPacket, DataPayload, DataResponse, Heartbeat, LegacyResponse, payload_crc32, SlaveOnline, SlaveStatus, TimeSeriesPoint,
+    WireFormat,
+};
+use rumqttc::{Client, ClientError, QoS};
+use std::{time::Duration, collections::{HashMap, HashSet, VecDeque}};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use chrono::Utc;
+use std::time::Instant;
+use chrono::{DateTime, Utc};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use hdrhistogram::Histogram;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tracing::{error, info, warn};
 
 
+/// What to do when the pending map reaches `MAX_PENDING` entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OverflowPolicy {
+    /// Hold back new publishes until a response frees a slot.
+    Block,
+    /// Drop the oldest outstanding request to make room.
+    EvictOldest,
+}
+
+impl OverflowPolicy {
+    fn from_env() -> Self {
+        match std::env::var("PENDING_POLICY").as_deref() {
+            Ok("block") => OverflowPolicy::Block,
+            Ok("evict") | Err(_) => OverflowPolicy::EvictOldest,
+            Ok(other) => {
+                warn!("Unknown PENDING_POLICY {:?}, falling back to evict", other);
+                OverflowPolicy::EvictOldest
+            }
+        }
+    }
+}
+
+/// A published packet awaiting its response. The packet itself is kept so it
+/// can be replayed after a reconnect.
+struct PendingRequest {
+    packet: DataPacket,
+    sent_at: Instant,
+    /// When the latest copy went out; retries restart the timeout from here.
+    last_sent_at: Instant,
+    retries: u32,
+    /// When a slave acknowledged picking the request up, if one has.
+    acked_at: Option<Instant>,
+    /// Out of retries; still kept so a late response is recognised.
+    gave_up: bool,
+}
+
+/// Requests that have been published but not yet answered, keyed by packet id.
+///
+/// Evicted entries are removed from the map outright, so anything that later
+/// sweeps the map for timeouts never sees (and never reports) them a second time.
+struct PendingRequests {
+    entries: HashMap<String, PendingRequest>,
+    max_pending: usize,
+    policy: OverflowPolicy,
+    evicted_count: u64,
+}
+
+impl PendingRequests {
+    fn new(max_pending: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            entries: HashMap::new(),
+            max_pending,
+            policy,
+            evicted_count: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.entries.len() >= self.max_pending
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self.entries
+            .iter()
+            .min_by_key(|(_, request)| request.sent_at)
+            .map(|(id, _)| id.clone());
+        if let Some(id) = oldest {
+            self.entries.remove(&id);
+            self.evicted_count += 1;
+            warn!(
+                "Pending map full ({} entries), evicted oldest request {} ({} evicted so far)",
+                self.max_pending, id, self.evicted_count
+            );
+        }
+    }
+
+    fn expire(&mut self, max_age: Duration) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, r| r.sent_at.elapsed() <= max_age);
+        before - self.entries.len()
+    }
+}
+
+/// Shared between the send loop and the response thread; the condvar wakes a
+/// blocked sender when a response frees up a slot.
+struct PendingTracker {
+    requests: Mutex<PendingRequests>,
+    slot_freed: Condvar,
+}
+
+impl PendingTracker {
+    /// How often a sender blocked on a full map checks for expired requests
+    /// and shutdown.
+    const BLOCK_POLL: Duration = Duration::from_millis(100);
+
+    fn new(max_pending: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            requests: Mutex::new(PendingRequests::new(max_pending, policy)),
+            slot_freed: Condvar::new(),
+        }
+    }
+
+    /// Makes room for one more request according to the overflow policy.
+    /// Under `Block` this waits until a response arrives or a request
+    /// outlives `max_age`, since no one else expires them while the sender
+    /// waits. Returns `false` if `shutdown` is raised before there is room.
+    fn reserve_slot(&self, max_age: Duration, shutdown: &AtomicBool) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+        if !requests.is_full() {
+            return true;
+        }
+        match requests.policy {
+            OverflowPolicy::Block => {
+                info!("Pending map full ({} entries), waiting for responses", requests.max_pending);
+                loop {
+                    let expired = requests.expire(max_age);
+                    if expired > 0 {
+                        warn!("Gave up on {} requests with no response after {:?}", expired, max_age);
+                    }
+                    if !requests.is_full() {
+                        return true;
+                    }
+                    if shutdown.load(Ordering::Relaxed) {
+                        return false;
+                    }
+                    requests = self.slot_freed
+                        .wait_timeout_while(requests, Self::BLOCK_POLL, |r| r.is_full())
+                        .unwrap()
+                        .0;
+                }
+            }
+            OverflowPolicy::EvictOldest => {
+                requests.evict_oldest();
+                true
+            }
+        }
+    }
+
+    fn insert(&self, packet: DataPacket) {
+        let now = Instant::now();
+        let request = PendingRequest {
+            packet,
+            sent_at: now,
+            last_sent_at: now,
+            retries: 0,
+            acked_at: None,
+            gave_up: false,
+        };
+        self.requests.lock().unwrap().entries.insert(request.packet.id.clone(), request);
+    }
+
+    /// Requests still waiting for a response.
+    fn outstanding(&self) -> usize {
+        self.requests.lock().unwrap().entries.values().filter(|request| !request.gave_up).count()
+    }
+
+    /// Records that a slave has received the request. Returns how long that
+    /// took for the first ack of a still-pending request.
+    fn ack(&self, packet_id: &str) -> Option<Duration> {
+        let mut requests = self.requests.lock().unwrap();
+        let request = requests.entries.get_mut(packet_id)?;
+        if request.acked_at.is_some() {
+            return None;
+        }
+        request.acked_at = Some(Instant::now());
+        Some(request.sent_at.elapsed())
+    }
+
+    fn complete(&self, packet_id: &str) -> Option<Instant> {
+        let request = self.requests.lock().unwrap().entries.remove(packet_id);
+        if request.is_some() {
+            self.slot_freed.notify_one();
+        }
+        request.map(|r| r.sent_at)
+    }
+
+    /// Finds requests whose latest copy has gone `timeout` without a
+    /// response. Those with retries left are returned for resending, the
+    /// rest are reported once and then left for `expire`.
+    fn timed_out(&self, timeout: Duration, max_retries: u32) -> Vec<DataPacket> {
+        let mut requests = self.requests.lock().unwrap();
+        let mut resend = Vec::new();
+        for (id, request) in requests.entries.iter_mut() {
+            if request.gave_up || request.last_sent_at.elapsed() < timeout {
+                continue;
+            }
+            // Without an ack no slave has seen the request; with one, a
+            // slave has it but hasn't finished processing.
+            let state = match request.acked_at {
+                Some(_) => "acked but not answered",
+                None => "never acked",
+            };
+            if request.retries < max_retries {
+                request.retries += 1;
+                request.last_sent_at = Instant::now();
+                warn!(
+                    "No response for {} within {:?} ({}); retrying ({}/{})",
+                    id, timeout, state, request.retries, max_retries
+                );
+                resend.push(request.packet.clone());
+            } else {
+                request.gave_up = true;
+                warn!(
+                    "No response for {} within {:?} ({}) after {} retries",
+                    id, timeout, state, request.retries
+                );
+            }
+        }
+        resend
+    }
+
+    /// Drops requests that have waited longer than `max_age` for a response,
+    /// so the map doesn't grow without bound while no slave is answering.
+    fn expire(&self, max_age: Duration) -> usize {
+        let expired = self.requests.lock().unwrap().expire(max_age);
+        if expired > 0 {
+            self.slot_freed.notify_all();
+        }
+        expired
+    }
+
+    /// Returns up to `limit` still-pending packets sent within `max_age`,
+    /// oldest first, for resending after a reconnect.
+    fn replayable(&self, max_age: Duration, limit: usize) -> Vec<DataPacket> {
+        let requests = self.requests.lock().unwrap();
+        let mut fresh: Vec<&PendingRequest> = requests.entries
+            .values()
+            .filter(|r| r.sent_at.elapsed() <= max_age)
+            .collect();
+        let skipped = requests.entries.len() - fresh.len();
+        if skipped > 0 {
+            info!("Skipping {} pending packets older than {:?} for backfill", skipped, max_age);
+        }
+        fresh.sort_by_key(|r| r.sent_at);
+        fresh.into_iter().take(limit).map(|r| r.packet.clone()).collect()
+    }
+}
+
+/// Correlates critical publishes with the broker's acknowledgements.
+///
+/// rumqttc assigns packet identifiers inside the event loop, so the sender
+/// never learns them directly. Every QoS 1 or 2 publish is registered here in the
+/// order it's handed to the client, and the event loop reports
+/// `Outgoing::Publish(pkid)` in that same order, which pairs each pkid with
+/// its ticket. The matching `PubAck` (or `PubComp`) then confirms it.
+struct PublishConfirms {
+    /// `--qos`. At 0 nothing is acknowledged, so nothing is registered.
+    qos: QoS,
+    /// Held across registration and `client.publish` so concurrent senders
+    /// can't enqueue in a different order than they registered.
+    send_order: Mutex<()>,
+    state: Mutex<ConfirmState>,
+    acked: Condvar,
+}
+
+#[derive(Default)]
+struct ConfirmState {
+    next_ticket: u64,
+    /// Publishes handed to the client but not yet given a pkid; `None` for
+    /// non-critical ones, which are tracked only to keep the order aligned.
+    unassigned: VecDeque<Option<u64>>,
+    /// Publishes sent but not yet acknowledged, by pkid.
+    unacked: HashMap<u16, Option<u64>>,
+    confirmed: HashSet<u64>,
+    /// Every publish the client accepted, and every one the broker then
+    /// acknowledged; the difference is what's still undelivered.
+    attempted_count: u64,
+    acked_count: u64,
+    confirmed_count: u64,
+    timed_out_count: u64,
+    total_confirm_ms: u64,
+}
+
+impl PublishConfirms {
+    fn new(qos: QoS) -> Self {
+        Self {
+            qos,
+            send_order: Mutex::new(()),
+            state: Mutex::new(ConfirmState::default()),
+            acked: Condvar::new(),
+        }
+    }
+
+    /// Publishes at the configured QoS, returning a ticket to wait on when
+    /// `critical` and the broker will acknowledge it.
+    fn publish(&self, client: &Client, topic: &str, payload: Vec<u8>, critical: bool) -> Result<Option<u64>, ClientError> {
+        if self.qos == QoS::AtMostOnce {
+            client.publish(topic, self.qos, false, payload)?;
+            self.state.lock().unwrap().attempted_count += 1;
+            return Ok(None);
+        }
+        let _order = self.send_order.lock().unwrap();
+        let ticket = {
+            let mut state = self.state.lock().unwrap();
+            let ticket = critical.then(|| {
+                state.next_ticket += 1;
+                state.next_ticket
+            });
+            state.unassigned.push_back(ticket);
+            ticket
+        };
+        // The state lock is released here: the event loop needs it to make
+        // room in the request channel if this publish has to block.
+        if let Err(e) = client.publish(topic, self.qos, false, payload) {
+            self.state.lock().unwrap().unassigned.pop_back();
+            return Err(e);
+        }
+        self.state.lock().unwrap().attempted_count += 1;
+        Ok(ticket)
+    }
+
+    fn on_outgoing_publish(&self, pkid: u16) {
+        let mut state = self.state.lock().unwrap();
+        // Packets resent after a reconnect keep their pkid and are already known.
+        if pkid == 0 || state.unacked.contains_key(&pkid) {
+            return;
+        }
+        if let Some(ticket) = state.unassigned.pop_front() {
+            state.unacked.insert(pkid, ticket);
+        }
+    }
+
+    fn on_ack(&self, pkid: u16) {
+        let mut state = self.state.lock().unwrap();
+        match state.unacked.remove(&pkid) {
+            Some(Some(ticket)) => {
+                state.acked_count += 1;
+                state.confirmed.insert(ticket);
+                self.acked.notify_all();
+            }
+            Some(None) => state.acked_count += 1,
+            None => {}
+        }
+    }
+
+    /// Waits for the broker to acknowledge `ticket`, returning how long it
+    /// took, or `None` on timeout.
+    fn wait(&self, ticket: u64, timeout: Duration) -> Option<Duration> {
+        let started = Instant::now();
+        let state = self.state.lock().unwrap();
+        let (mut state, _) = self.acked
+            .wait_timeout_while(state, timeout, |s| !s.confirmed.contains(&ticket))
+            .unwrap();
+        if state.confirmed.remove(&ticket) {
+            let elapsed = started.elapsed();
+            state.confirmed_count += 1;
+            state.total_confirm_ms += elapsed.as_millis() as u64;
+            Some(elapsed)
+        } else {
+            state.timed_out_count += 1;
+            None
+        }
+    }
+
+    /// Publishes attempted (queued with the client) and acknowledged by the broker.
+    fn delivery(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.attempted_count, state.acked_count)
+    }
+
+    /// Confirmed count, timed-out count and average confirm latency in ms.
+    fn stats(&self) -> (u64, u64, u64) {
+        let state = self.state.lock().unwrap();
+        let average = state.total_confirm_ms.checked_div(state.confirmed_count).unwrap_or(0);
+        (state.confirmed_count, state.timed_out_count, average)
+    }
+}
+
+/// Data types listed in `CRITICAL_TYPES` (comma separated) are sent with a
+/// wait for the broker's acknowledgement.
+fn critical_types_from_env() -> HashSet<String> {
+    std::env::var("CRITICAL_TYPES")
+        .map(|raw| {
+            raw.split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Resends requests that timed out, marked as replays since they repeat
+/// an already-used sequence number.
+fn retry_timed_out(
+    client: &Client,
+    confirms: &PublishConfirms,
+    pending: &PendingTracker,
+    encoder: PacketEncoder,
+    request_topic: &str,
+    timeout: Duration,
+    max_retries: u32,
+) {
+    for mut packet in pending.timed_out(timeout, max_retries) {
+        packet.metadata.insert("replay".to_string(), "true".to_string());
+        match encoder.encode(&packet) {
+            Ok(payload) => {
+                if let Err(e) = confirms.publish(client, request_topic, payload, false) {
+                    error!(packet_id = %packet.id, "Failed to retry packet: {:?}", e);
+                }
+            }
+            Err(e) => error!("Failed to serialize packet: {:?}", e),
+        }
+    }
+}
+
+/// Turns packets into publish payloads: `--format`, then gzip above
+/// `--compress-threshold-bytes`.
+#[derive(Clone, Copy)]
+struct PacketEncoder {
+    format: WireFormat,
+    compress_threshold: Option<usize>,
+}
+
+impl PacketEncoder {
+    fn encode(&self, packet: &DataPacket) -> Result<Vec<u8>, String> {
+        let encoded = self.format.encode(packet)?;
+        match self.compress_threshold {
+            Some(threshold) if encoded.len() > threshold => compress(&encoded),
+            _ => Ok(encoded),
+        }
+    }
+}
+
+/// Options taken from the command line after the broker flags.
+struct MasterArgs {
+    request_timeout: Duration,
+    max_retries: u32,
+    format: WireFormat,
+    /// Encoded packets larger than this are gzipped; `None` never compresses.
+    compress_threshold: Option<usize>,
+    /// `--rate` in packets per second; `Some(0.0)` publishes as fast as the
+    /// client's request channel allows, `None` keeps the random 1-3s gap.
+    rate: Option<f64>,
+    /// `--jitter-ms`: up to this much random delay added to each gap.
+    jitter: Duration,
+    /// `--batch-size`: generated payloads per packet; above 1 they are sent
+    /// as one `Batch`.
+    batch_size: usize,
+    target: Target,
+    /// `--latency-report`: print round-trip percentiles every
+    /// [`LatencyRecorder::REPORT_INTERVAL`].
+    latency_report: bool,
+    /// `--replay`: send the packets recorded in this file instead of
+    /// generating them.
+    replay: Option<String>,
+    /// `--preserve-timing`: space replayed packets as they were recorded
+    /// rather than by `--rate`.
+    preserve_timing: bool,
+    /// `--loop`: start the replay file over instead of exiting at its end.
+    replay_loop: bool,
+    /// `--record`: append every packet sent to this file, in the format
+    /// `--replay` reads.
+    record: Option<String>,
+    /// `--bench`: send this many packets back to back, wait for their
+    /// responses, print throughput and latency, and exit.
+    bench: Option<u64>,
+    /// `--seed`: generate the same sequence of payloads on every run (ids
+    /// and timestamps still differ). Unseeded runs draw from OS entropy.
+    seed: Option<u64>,
+    /// `--payload-types`: the types generated packets are drawn from, all of
+    /// [`GENERATED_TYPES`] by default. Kept in flag order so seeded runs
+    /// repeat.
+    payload_types: Vec<&'static str>,
+    /// `--mqtt-cap`: how many requests the MQTT client queues before a
+    /// publish blocks, [`DEFAULT_MQTT_CAP`] by default. Raising it helps
+    /// sustain high `--rate`s at the cost of memory.
+    mqtt_cap: usize,
+    /// `--status`: ask the slaves for their counters instead of sending
+    /// packets, print the answers that arrive within `--request-timeout-ms`,
+    /// and exit.
+    status: bool,
+}
+
+const DEFAULT_MQTT_CAP: usize = 10;
+
+/// Round-trip times of answered requests, reported and cleared every
+/// [`LatencyRecorder::REPORT_INTERVAL`] so each report covers one window.
+struct LatencyRecorder {
+    histogram: Mutex<Histogram<u64>>,
+}
+
+impl LatencyRecorder {
+    const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+    const MAX_MS: u64 = 60_000;
+
+    fn new() -> Self {
+        // 1ms to 60s at 3 significant digits.
+        let histogram = Histogram::new_with_bounds(1, Self::MAX_MS, 3).expect("valid histogram bounds");
+        Self { histogram: Mutex::new(histogram) }
+    }
+
+    /// Times outside the tracked range are clamped into it.
+    fn record(&self, rtt: Duration) {
+        let ms = (rtt.as_millis() as u64).clamp(1, Self::MAX_MS);
+        self.histogram.lock().unwrap().saturating_record(ms);
+    }
+
+    fn report(&self) {
+        let mut histogram = self.histogram.lock().unwrap();
+        if histogram.is_empty() {
+            info!("Latency: no responses in the last {:?}", Self::REPORT_INTERVAL);
+            return;
+        }
+        info!(
+            "Latency over {} responses: p50={}ms p90={}ms p99={}ms max={}ms",
+            histogram.len(),
+            histogram.value_at_quantile(0.5),
+            histogram.value_at_quantile(0.9),
+            histogram.value_at_quantile(0.99),
+            histogram.max()
+        );
+        histogram.reset();
+    }
+
+    /// Responses recorded since the last report.
+    fn count(&self) -> u64 {
+        self.histogram.lock().unwrap().len()
+    }
+
+    /// Mean and p99 round trip in ms, or `None` with nothing recorded.
+    fn mean_and_p99(&self) -> Option<(f64, u64)> {
+        let histogram = self.histogram.lock().unwrap();
+        (!histogram.is_empty()).then(|| (histogram.mean(), histogram.value_at_quantile(0.99)))
+    }
+}
+
+/// Which request topic new packets go to (`--target-slave`).
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    /// The shared request topic; the broker hands each packet to every slave.
+    Broadcast,
+    /// One slave's own request topic, by client id.
+    Slave(String),
+    /// `auto`: rotate through the slaves announced on `slaves/online/+`,
+    /// broadcasting until one shows up.
+    Discovered,
+}
+
+/// When each slave last sent a heartbeat, and how often it promised to.
+#[derive(Default)]
+struct SlaveLiveness {
+    slaves: Mutex<HashMap<String, LastSeen>>,
+}
+
+struct LastSeen {
+    at: Instant,
+    interval: Duration,
+    /// Set once the slave has been reported missing, so it's reported once.
+    missing: bool,
+}
+
+impl SlaveLiveness {
+    /// Heartbeats missed in a row before a slave is reported missing.
+    const MISSED_HEARTBEATS: u32 = 3;
+
+    fn heartbeat(&self, heartbeat: &Heartbeat) {
+        // Slaves that predate `interval_secs` send the default 5s.
+        let interval = Duration::from_secs(if heartbeat.interval_secs == 0 { 5 } else { heartbeat.interval_secs });
+        let seen = LastSeen { at: Instant::now(), interval, missing: false };
+        match self.slaves.lock().unwrap().insert(heartbeat.slave_id.clone(), seen) {
+            None => info!("Slave {} is alive ({} processed)", heartbeat.slave_id, heartbeat.processed_count),
+            Some(previous) if previous.missing => info!("Slave {} is back", heartbeat.slave_id),
+            Some(_) => {}
+        }
+    }
+
+    fn offline(&self, slave_id: &str) {
+        if self.slaves.lock().unwrap().remove(slave_id).is_some() {
+            info!("Slave {} went offline", slave_id);
+        }
+    }
+
+    /// Warns about slaves that have missed [`Self::MISSED_HEARTBEATS`]
+    /// heartbeats in a row.
+    fn check(&self) {
+        for (slave_id, seen) in self.slaves.lock().unwrap().iter_mut() {
+            if !seen.missing && seen.at.elapsed() > seen.interval * Self::MISSED_HEARTBEATS {
+                warn!(
+                    "Slave {} missed {} heartbeats (last seen {:?} ago)",
+                    slave_id,
+                    Self::MISSED_HEARTBEATS,
+                    seen.at.elapsed()
+                );
+                seen.missing = true;
+            }
+        }
+    }
+}
+
+/// Slaves announced on `slaves/online/<slave id>`, for [`Target::Discovered`].
+#[derive(Default)]
+struct SlaveDirectory {
+    slaves: Mutex<Vec<SlaveOnline>>,
+    next: AtomicUsize,
+}
+
+impl SlaveDirectory {
+    fn add(&self, slave: SlaveOnline) {
+        let mut slaves = self.slaves.lock().unwrap();
+        if !slaves.iter().any(|known| known.slave_id == slave.slave_id) {
+            info!("Discovered slave {} ({} known)", slave.slave_id, slaves.len() + 1);
+            slaves.push(slave);
+        }
+    }
+
+    fn remove(&self, slave_id: &str) {
+        let mut slaves = self.slaves.lock().unwrap();
+        let known = slaves.len();
+        slaves.retain(|slave| slave.slave_id != slave_id);
+        if slaves.len() < known {
+            info!("Slave {} went offline ({} known)", slave_id, slaves.len());
+        }
+    }
+
+    /// The next slave's request topic, round-robin.
+    fn next_topic(&self) -> Option<String> {
+        let slaves = self.slaves.lock().unwrap();
+        if slaves.is_empty() {
+            return None;
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % slaves.len();
+        Some(slaves[index].request_topic.clone())
+    }
+}
+
+impl MasterArgs {
+    fn publish_delay(&self) -> Duration {
+        let jitter = match self.jitter.as_millis() as u64 {
+            0 => Duration::ZERO,
+            ms => Duration::from_millis(rand::random::<u64>() % (ms + 1)),
+        };
+        match self.rate {
+            None => Duration::from_millis(rand::random::<u64>() % 2000 + 1000) + jitter,
+            Some(rate) if rate > 0.0 => Duration::from_secs_f64(1.0 / rate) + jitter,
+            Some(_) => jitter,
+        }
+    }
+}
+
+fn master_args(args: Vec<String>) -> Result<MasterArgs, String> {
+    let mut parsed = MasterArgs {
+        request_timeout: Duration::from_millis(5000),
+        max_retries: 0,
+        format: WireFormat::Json,
+        compress_threshold: Some(4096),
+        rate: None,
+        jitter: Duration::ZERO,
+        batch_size: 1,
+        target: Target::Broadcast,
+        latency_report: false,
+        replay: None,
+        preserve_timing: false,
+        replay_loop: false,
+        record: None,
+        bench: None,
+        seed: None,
+        payload_types: GENERATED_TYPES.to_vec(),
+        mqtt_cap: DEFAULT_MQTT_CAP,
+        status: false,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let switch = match arg.as_str() {
+            "--latency-report" => Some(&mut parsed.latency_report),
+            "--preserve-timing" => Some(&mut parsed.preserve_timing),
+            "--loop" => Some(&mut parsed.replay_loop),
+            "--status" => Some(&mut parsed.status),
+            _ => None,
+        };
+        if let Some(switch) = switch {
+            *switch = true;
+            continue;
+        }
+        let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--request-timeout-ms" => {
+                let ms = value.parse::<u64>().map_err(|_| format!("invalid {} {:?}", arg, value))?;
+                parsed.request_timeout = Duration::from_millis(ms.max(1));
+            }
+            "--max-retries" => {
+                parsed.max_retries = value.parse().map_err(|_| format!("invalid {} {:?}", arg, value))?;
+            }
+            "--format" => parsed.format = value.parse()?,
+            "--compress-threshold-bytes" => {
+                let bytes = value.parse::<usize>().map_err(|_| format!("invalid {} {:?}", arg, value))?;
+                parsed.compress_threshold = (bytes > 0).then_some(bytes);
+            }
+            "--rate" => {
+                let rate = value.parse::<f64>().ok().filter(|rate| rate.is_finite() && *rate >= 0.0);
+                parsed.rate = Some(rate.ok_or_else(|| format!("invalid {} {:?}", arg, value))?);
+            }
+            "--batch-size" => {
+                let size = value.parse::<usize>().ok().filter(|size| *size > 0);
+                parsed.batch_size = size.ok_or_else(|| format!("invalid {} {:?}", arg, value))?;
+            }
+            "--target-slave" => {
+                parsed.target = match value.as_str() {
+                    "" => return Err(format!("{} needs a slave id or auto", arg)),
+                    "auto" => Target::Discovered,
+                    id => Target::Slave(id.to_string()),
+                };
+            }
+            "--replay" => parsed.replay = Some(value),
+            "--record" => parsed.record = Some(value),
+            "--mqtt-cap" => {
+                let cap = value.parse::<usize>().ok().filter(|cap| *cap > 0);
+                parsed.mqtt_cap = cap.ok_or_else(|| format!("--mqtt-cap must be a positive number, got {:?}", value))?;
+            }
+            "--payload-types" => parsed.payload_types = payload_types(&value)?,
+            "--seed" => parsed.seed = Some(value.parse().map_err(|_| format!("invalid {} {:?}", arg, value))?),
+            "--bench" => {
+                let count = value.parse::<u64>().ok().filter(|count| *count > 0);
+                parsed.bench = Some(count.ok_or_else(|| format!("invalid {} {:?}", arg, value))?);
+            }
+            "--jitter-ms" => {
+                let ms = value.parse::<u64>().map_err(|_| format!("invalid {} {:?}", arg, value))?;
+                parsed.jitter = Duration::from_millis(ms);
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    if parsed.replay.is_none() && (parsed.preserve_timing || parsed.replay_loop) {
+        return Err("--preserve-timing and --loop need --replay".to_string());
+    }
+    Ok(parsed)
+}
 
-fn generate_random_data() -> DataPayload {
-    let choice = rand::random::<u8>() % 6;
-    match choice {
-        0 => DataPayload::Text(format!("Random text message {}", rand::random::<u16>())),
-        1 => DataPayload::Number(rand::random::<f64>() * 100.0),
-        2 => DataPayload::Coordinates {
-            x: rand::random::<f64>() * 100.0,
-            y: rand::random::<f64>() * 100.0,
-            z: rand::random::<f64>() * 100.0,
+/// Resends in-flight packets after a reconnect, tagged with `replay=true` so
+/// the slave can recognise them as possible duplicates.
+fn backfill(
+    client: &Client,
+    confirms: &PublishConfirms,
+    pending: &PendingTracker,
+    encoder: PacketEncoder,
+    request_topic: &str,
+    max_age: Duration,
+    limit: usize,
+) {
+    let packets = pending.replayable(max_age, limit);
+    info!("Backfilling {} pending packets after reconnect", packets.len());
+    for mut packet in packets {
+        packet.metadata.insert("replay".to_string(), "true".to_string());
+        match encoder.encode(&packet) {
+            Ok(payload) => {
+                if let Err(e) = confirms.publish(client, request_topic, payload, false) {
+                    error!(packet_id = %packet.id, "Failed to replay packet: {:?}", e);
+                } else {
+                    info!(packet_id = %packet.id, "Replayed {}", packet.data_type);
+                }
+            }
+            Err(e) => error!("Failed to serialize packet: {:?}", e),
+        }
+    }
+}
+
+/// The payload types [`generate_random_data`] can produce, and so the names
+/// `--payload-types` accepts.
+const GENERATED_TYPES: [&str; 8] =
+    ["text", "number", "coordinates", "sensor_data", "image_data", "log_entry", "json", "time_series"];
+
+/// A random payload of one of `types`, which must be non-empty and drawn
+/// from [`GENERATED_TYPES`].
+fn generate_random_data(rng: &mut impl Rng, types: &[&str]) -> DataPayload {
+    match types[rng.gen_range(0..types.len())] {
+        "text" => DataPayload::Text(format!("Random text message {}", rng.gen::<u16>())),
+        // Half integers, half floats, so slaves see both forms.
+        "number" => match serde_json::Number::from_f64(rng.gen::<f64>() * 100.0) {
+            Some(float) if rng.gen::<bool>() => DataPayload::Number(float),
+            _ => DataPayload::Number((rng.gen::<u8>() % 100).into()),
+        },
+        "coordinates" => DataPayload::Coordinates {
+            x: rng.gen::<f64>() * 100.0,
+            y: rng.gen::<f64>() * 100.0,
+            z: rng.gen::<f64>() * 100.0,
+            system: CoordinateSystem::Cartesian,
         },
-        3 => DataPayload::SensorData {
-            sensor_id: format!("SENSOR_{}", rand::random::<u16>()),
-            temperature: rand::random::<f64>() * 50.0,
-            humidity: rand::random::<f64>() * 100.0,
-            pressure: rand::random::<f64>() * 1013.0,
+        "sensor_data" => DataPayload::SensorData {
+            sensor_id: format!("SENSOR_{}", rng.gen::<u16>()),
+            temperature: rng.gen::<f64>() * 50.0,
+            humidity: rng.gen::<f64>() * 100.0,
+            pressure: 950.0 + rng.gen::<f64>() * 100.0,
         },
-        4 => DataPayload::ImageData {
-            width: 640,
-            height: 480,
+        // Small enough to send often, and a buffer that matches its size.
+        "image_data" => DataPayload::ImageData {
+            width: 16,
+            height: 12,
             format: "RGB".to_string(),
-            data: (0..100).map(|_| rand::random::<u8>()).collect(),
+            data: (0..16 * 12 * 3).map(|_| rng.gen::<u8>()).collect(),
         },
-        _ => DataPayload::LogEntry {
-            level: ["INFO", "WARN", "ERROR"][rand::random::<usize>() % 3].to_string(),
-            message: format!("Log message {}", rand::random::<u16>()),
+        "log_entry" => DataPayload::LogEntry {
+            level: ["INFO", "WARN", "ERROR"][rng.gen::<usize>() % 3].to_string(),
+            message: format!("Log message {}", rng.gen::<u16>()),
             timestamp: Utc::now().to_rfc3339(),
         },
+        "json" => DataPayload::Json(serde_json::json!({
+            "device": format!("DEVICE_{}", rng.gen::<u16>()),
+            "online": rng.gen::<bool>(),
+            "readings": (0..rng.gen::<usize>() % 4).map(|_| rng.gen::<u8>()).collect::<Vec<_>>(),
+        })),
+        // The only generated type left.
+        _ => DataPayload::TimeSeries {
+            series_id: format!("SERIES_{}", rng.gen::<u16>()),
+            points: (0..rng.gen::<usize>() % 10)
+                .map(|i| TimeSeriesPoint {
+                    timestamp: (Utc::now() - chrono::Duration::seconds(i as i64)).to_rfc3339(),
+                    value: rng.gen::<f64>() * 100.0,
+                })
+                .collect(),
+        },
+    }
+}
+
+/// Parses `--payload-types`, e.g. `text,sensor_data`.
+fn payload_types(list: &str) -> Result<Vec<&'static str>, String> {
+    let mut types = Vec::new();
+    for name in list.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        let Some(known) = GENERATED_TYPES.iter().find(|known| **known == name) else {
+            return Err(format!(
+                "unknown payload type {:?} in --payload-types, expected some of {}",
+                name,
+                GENERATED_TYPES.join(", ")
+            ));
+        };
+        if !types.contains(known) {
+            types.push(*known);
+        }
+    }
+    if types.is_empty() {
+        return Err("--payload-types needs at least one type".to_string());
+    }
+    Ok(types)
+}
+
+/// Reads recorded packets back from newline-delimited JSON, one
+/// `DataPacket` per line (`--replay`). Blank lines are skipped and lines
+/// that don't parse are logged and skipped. With `--loop`, every pass after
+/// the first sends its packets under fresh ids, keeping the recorded one in
+/// `metadata["original_id"]`, so the slaves' answers can still be told apart.
+struct Replay {
+    path: String,
+    lines: std::io::Lines<BufReader<File>>,
+    looping: bool,
+    /// Recorded timestamp of the previous packet, for `--preserve-timing`.
+    last_timestamp: Option<DateTime<chrono::FixedOffset>>,
+    /// Packets read since the file was last opened, so looping over a file
+    /// with none in it ends instead of spinning.
+    read_this_pass: usize,
+    /// Which pass over the file this is, counting from 1.
+    pass: u32,
+}
+
+impl Replay {
+    fn open(path: &str, looping: bool) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path, e))?;
+        Ok(Self {
+            path: path.to_string(),
+            lines: BufReader::new(file).lines(),
+            looping,
+            last_timestamp: None,
+            read_this_pass: 0,
+            pass: 1,
+        })
+    }
+
+    /// The next packet and how long after the previous one it was recorded.
+    /// The gap is zero for the first packet of each pass and for timestamps
+    /// that don't parse or go backwards.
+    fn next_packet(&mut self) -> Option<(DataPacket, Duration)> {
+        loop {
+            let line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => {
+                    error!("Failed to read {}: {}", self.path, e);
+                    return None;
+                }
+                None if self.looping && self.read_this_pass > 0 => {
+                    info!("Reached the end of {}, starting over", self.path);
+                    let pass = self.pass + 1;
+                    *self = Self::open(&self.path, true).map_err(|e| warn!("{}", e)).ok()?;
+                    self.pass = pass;
+                    continue;
+                }
+                None => return None,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut packet = match serde_json::from_str::<DataPacket>(&line) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    warn!("Skipping line of {} that is not a packet: {}", self.path, e);
+                    continue;
+                }
+            };
+            if self.pass > 1 {
+                let original_id = std::mem::replace(&mut packet.id, uuid::Uuid::new_v4().to_string());
+                packet.metadata.insert("original_id".to_string(), original_id);
+            }
+            self.read_this_pass += 1;
+            let timestamp = DateTime::parse_from_rfc3339(&packet.timestamp).ok();
+            let gap = match (self.last_timestamp, timestamp) {
+                (Some(last), Some(timestamp)) => (timestamp - last).to_std().unwrap_or_default(),
+                _ => Duration::ZERO,
+            };
+            if timestamp.is_some() {
+                self.last_timestamp = timestamp;
+            }
+            return Some((packet, gap));
+        }
+    }
+}
+
+/// Appends sent packets to a file as newline-delimited JSON (`--record`).
+struct Recorder {
+    path: String,
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    fn open(path: &str) -> Result<Self, String> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("failed to open {}: {}", path, e))?;
+        Ok(Self { path: path.to_string(), writer: BufWriter::new(file) })
+    }
+
+    fn record(&mut self, packet: &DataPacket) {
+        let written = serde_json::to_writer(&mut self.writer, packet)
+            .map_err(|e| e.to_string())
+            .and_then(|_| self.writer.write_all(b"\n").map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            error!(packet_id = %packet.id, "Failed to record packet to {}: {}", self.path, e);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("Failed to flush {}: {}", self.path, e);
+        }
     }
 }
 
+/// Publishes one status request and logs each answer until `timeout`; there
+/// is no telling how many slaves will answer, so it always waits that long.
+fn query_status(client: &Client, connection: &mut rumqttc::Connection, timeout: Duration) {
+    if let Err(e) = client.subscribe(SlaveStatus::RESPONSE_TOPIC, QoS::AtLeastOnce) {
+        error!("Failed to subscribe to {}: {:?}", SlaveStatus::RESPONSE_TOPIC, e);
+        return;
+    }
+    // Queued behind the subscription, so no answer can arrive before it.
+    if let Err(e) = client.publish(SlaveStatus::REQUEST_TOPIC, QoS::AtLeastOnce, false, Vec::new()) {
+        error!("Failed to request status: {:?}", e);
+        return;
+    }
+    let deadline = Instant::now() + timeout;
+    let mut answered = 0;
+    while let Ok(event) = connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        match event {
+            Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish))) if publish.topic == SlaveStatus::RESPONSE_TOPIC => {
+                match serde_json::from_slice::<SlaveStatus>(&publish.payload) {
+                    Ok(status) => {
+                        answered += 1;
+                        match serde_json::to_string_pretty(&status) {
+                            Ok(pretty) => info!("Status of {}:\n{}", status.slave_id, pretty),
+                            Err(e) => error!("Failed to serialize status: {:?}", e),
+                        }
+                    }
+                    Err(e) => warn!("Ignoring malformed status: {}", e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Connection error: {}", e);
+                return;
+            }
+        }
+    }
+    info!("{} slave(s) answered", answered);
+}
+
 fn main() {
-    let mut mqtt_options = MqttOptions::new(
-        format!("master-node-{}", uuid::Uuid::new_v4()),
-        "localhost",
-        1883,
-    );
-    mqtt_options.set_keep_alive(Duration::from_secs(5));
-
-    let (client, mut connection) = Client::new(mqtt_options, 10);
+    let parsed = BrokerArgs::extract(std::env::args().skip(1))
+        .and_then(|(broker, rest)| Ok((broker, master_args(rest)?)));
+    let (broker, args) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Invalid arguments: {}", e);
+            std::process::exit(2);
+        }
+    };
+    init_logging(broker.log_format);
+    let transport = match broker.transport() {
+        Ok(transport) => transport,
+        Err(e) => {
+            error!("Invalid TLS configuration: {}", e);
+            return;
+        }
+    };
+
+    let mut replay = match &args.replay {
+        Some(path) => match Replay::open(path, args.replay_loop) {
+            Ok(replay) => Some(replay),
+            Err(e) => {
+                error!("Cannot replay: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let mut recorder = match &args.record {
+        Some(path) => match Recorder::open(path) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                error!("Cannot record: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    // Ctrl-C and SIGTERM stop the send loop so the recording gets flushed.
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        if let Err(e) = signal_hook::flag::register(signal, shutdown.clone()) {
+            error!("Failed to install handler for signal {}: {:?}", signal, e);
+        }
+    }
+
+    let topics = broker.topics.clone();
+    info!("Publishing requests to {} and listening for responses on {}", topics.request, topics.response);
+
+    let master_id = broker.client_id("master-node-");
+    info!("MQTT request queue capacity {}", args.mqtt_cap);
+    let (client, mut connection) = connect(&master_id, &broker, transport, args.mqtt_cap, None, None);
+    if args.status {
+        query_status(&client, &mut connection, args.request_timeout);
+        return;
+    }
     let client_clone = client.clone();
 
+    let max_pending = env_var::<usize>("MAX_PENDING").unwrap_or(1000).max(1);
+    let pending = Arc::new(PendingTracker::new(max_pending, OverflowPolicy::from_env()));
+    let pending_clone = pending.clone();
+
+    // Requests unanswered for this long are forgotten.
+    let pending_max_age = Duration::from_secs(env_var("PENDING_MAX_AGE_SECS").unwrap_or(60));
+    let backfill_on_reconnect = env_var::<u8>("BACKFILL_ON_RECONNECT").unwrap_or(0) == 1;
+    let backfill_max_age = Duration::from_secs(env_var("BACKFILL_MAX_AGE_SECS").unwrap_or(60));
+    let backfill_limit = env_var::<usize>("BACKFILL_MAX").unwrap_or(100);
+    let replay_client = client.clone();
+
+    let critical_types = critical_types_from_env();
+    let confirm_timeout = Duration::from_millis(env_var("CONFIRM_TIMEOUT_MS").unwrap_or(5000));
+    let confirms = Arc::new(PublishConfirms::new(broker.qos));
+    let event_confirms = confirms.clone();
+
+    let encoder = PacketEncoder { format: args.format, compress_threshold: args.compress_threshold };
+
+    // Times out unanswered requests, resending them while retries remain.
+    let retry_client = client.clone();
+    let retry_pending = pending.clone();
+    let retry_confirms = confirms.clone();
+    let retry_topic = topics.request.clone();
+    thread::spawn(move || loop {
+        thread::sleep((args.request_timeout / 10).clamp(Duration::from_millis(10), Duration::from_millis(500)));
+        retry_timed_out(
+            &retry_client,
+            &retry_confirms,
+            &retry_pending,
+            encoder,
+            &retry_topic,
+            args.request_timeout,
+            args.max_retries,
+        );
+    });
+
+    let directory = Arc::new(SlaveDirectory::default());
+    let liveness = Arc::new(SlaveLiveness::default());
+    let check_liveness = liveness.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        check_liveness.check();
+    });
+
+    // A benchmark keeps every round trip for its final report.
+    let latency = (args.latency_report || args.bench.is_some()).then(|| Arc::new(LatencyRecorder::new()));
+    if let Some(latency) = latency.clone().filter(|_| args.bench.is_none()) {
+        thread::spawn(move || loop {
+            thread::sleep(LatencyRecorder::REPORT_INTERVAL);
+            latency.report();
+        });
+    }
+
     // Handle incoming responses
+    let event_topics = topics.clone();
+    let event_latency = latency.clone();
+    let event_directory = directory.clone();
+    let event_liveness = liveness.clone();
     thread::spawn(move || {
-        for notification in connection.iter() {
-            if let Ok(event) = notification {
-                if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) = event {
-                    if publish.topic == "data/response" {
-                        if let Ok(_response) = serde_json::from_slice::<DataResponse>(&publish.payload) {
+        let mut connected_before = false;
+        let mut backoff = Backoff::from_env();
+        for event in connection.iter() {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!("Connection error: {}; reconnect attempt {} in {:?}", e, backoff.attempt(), delay);
+                    thread::sleep(delay);
+                    continue;
+                }
+            };
+            match event {
+                rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => {
+                    backoff.reset();
+                    if connected_before && backfill_on_reconnect {
+                        // Publishing blocks once the request channel fills up, and
+                        // only this thread drains it, so replay from a helper thread.
+                        let client = replay_client.clone();
+                        let pending = pending_clone.clone();
+                        let confirms = event_confirms.clone();
+                        let request_topic = event_topics.request.clone();
+                        thread::spawn(move || {
+                            backfill(
+                                &client,
+                                &confirms,
+                                &pending,
+                                encoder,
+                                &request_topic,
+                                backfill_max_age,
+                                backfill_limit,
+                            )
+                        });
+                    }
+                    connected_before = true;
+                }
+                rumqttc::Event::Outgoing(rumqttc::Outgoing::Publish(pkid)) => {
+                    event_confirms.on_outgoing_publish(pkid);
+                }
+                rumqttc::Event::Incoming(rumqttc::Packet::PubAck(ack)) => event_confirms.on_ack(ack.pkid),
+                rumqttc::Event::Incoming(rumqttc::Packet::PubComp(comp)) => event_confirms.on_ack(comp.pkid),
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) if publish.topic == Heartbeat::TOPIC => {
+                    match serde_json::from_slice::<Heartbeat>(&publish.payload) {
+                        Ok(heartbeat) => event_liveness.heartbeat(&heartbeat),
+                        Err(e) => warn!("Ignoring malformed heartbeat: {}", e),
+                    }
+                }
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish))
+                    if publish.topic == Heartbeat::OFFLINE_TOPIC =>
+                {
+                    // Sent by a slave shutting down, or by the broker as its last will.
+                    let slave_id = String::from_utf8_lossy(&publish.payload);
+                    event_liveness.offline(&slave_id);
+                    event_directory.remove(&slave_id);
+                }
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish))
+                    if SlaveOnline::slave_id_from_topic(&publish.topic).is_some() =>
+                {
+                    // An empty payload clears the retained announcement of a
+                    // slave that shut down.
+                    if publish.payload.is_empty() {
+                        event_directory.remove(SlaveOnline::slave_id_from_topic(&publish.topic).unwrap_or_default());
+                    } else {
+                        match serde_json::from_slice::<SlaveOnline>(&publish.payload) {
+                            Ok(slave) => event_directory.add(slave),
+                            Err(e) => warn!("Ignoring malformed slave announcement: {}", e),
                         }
                     }
                 }
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) if publish.topic == "data/ack" => {
+                    match serde_json::from_slice::<Ack>(&publish.payload) {
+                        Ok(ack) => {
+                            if let Some(latency) = pending_clone.ack(&ack.packet_id) {
+                                info!(packet_id = %ack.packet_id, "packet acked after {}ms", latency.as_millis());
+                            }
+                        }
+                        Err(e) => warn!("Ignoring malformed ack: {}", e),
+                    }
+                }
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish))
+                    if event_topics.is_response(&publish.topic) =>
+                {
+                    let payload = match WireFormat::to_json(&publish.payload) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            error!("Failed to decode response: {}", e);
+                            continue;
+                        }
+                    };
+                    // Slaves running with RESPONSE_SCHEMA=legacy send the flat form.
+                    if let Ok(response) = serde_json::from_slice::<DataResponse>(&payload) {
+                        // Only the first response for a packet completes it, so
+                        // one arriving late after a retry isn't counted twice.
+                        if let Some(sent_at) = pending_clone.complete(&response.packet_id) {
+                            let rtt = sent_at.elapsed();
+                            if let Some(latency) = &event_latency {
+                                latency.record(rtt);
+                            }
+                            info!(
+                                packet_id = %response.packet_id,
+                                "packet round-tripped in {}ms (slave processing {}ms)",
+                                rtt.as_millis(),
+                                response.processing_time_ms
+                            );
+                            if !response.status.is_ok() {
+                                warn!(packet_id = %response.packet_id, "packet failed on the slave: {}", response.status);
+                            }
+                        }
+                    } else if let Ok(response) = serde_json::from_slice::<LegacyResponse>(&payload) {
+                        if let Some(sent_at) = pending_clone.complete(&response.id) {
+                            let rtt = sent_at.elapsed();
+                            if let Some(latency) = &event_latency {
+                                latency.record(rtt);
+                            }
+                            info!(packet_id = %response.id, "packet round-tripped in {}ms", rtt.as_millis());
+                        }
+                    }
+                }
+                _ => {}
             }
         }
     });
 
-    client.subscribe("data/response", QoS::AtLeastOnce).unwrap();
-
-    loop {
-        let data = generate_random_data();
-        let data_type = match &data {
-            DataPayload::Text(_) => "text",
-            DataPayload::Number(_) => "number",
-            DataPayload::Coordinates { .. } => "coordinates",
-            DataPayload::SensorData { .. } => "sensor_data",
-            DataPayload::ImageData { .. } => "image_data",
-            DataPayload::LogEntry { .. } => "log_entry",
-        };
+    client.subscribe(topics.response.as_str(), broker.qos).unwrap();
+    // Slaves running with ROUTING_KEY_IN_TOPIC publish under <response topic>/<key>.
+    client.subscribe(format!("{}/+", topics.response), broker.qos).unwrap();
+    client.subscribe("data/ack", broker.qos).unwrap();
+    client.subscribe(Heartbeat::TOPIC, QoS::AtMostOnce).unwrap();
+    client.subscribe(Heartbeat::OFFLINE_TOPIC, QoS::AtLeastOnce).unwrap();
+    if args.target == Target::Discovered {
+        client.subscribe(SlaveOnline::FILTER, QoS::AtLeastOnce).unwrap();
+    }
 
-        let packet = DataPacket {
-            id: uuid::Uuid::new_v4().to_string(),
-            timestamp: Utc::now().to_rfc3339(),
-            data_type: data_type.to_string(),
-            payload: data.clone(),
-            metadata: {
-                let mut map = HashMap::new();
-                map.insert("source".to_string(), "master-node".to_string());
-                map.insert("version".to_string(), "1.0".to_string());
-                map
+    // Stamped on every packet so slaves can detect loss and reordering. It
+    // restarts at 1 with each master run, alongside a fresh master id.
+    let mut seq: u64 = 0;
+    let mut sent: u64 = 0;
+    let started = Instant::now();
+    let mut rng = match args.seed {
+        Some(seed) => {
+            info!("Generating payloads from seed {}", seed);
+            StdRng::seed_from_u64(seed)
+        }
+        None => StdRng::from_entropy(),
+    };
+
+    while !shutdown.load(Ordering::Relaxed) && args.bench.is_none_or(|count| sent < count) {
+        seq += 1;
+        // Either the next recorded packet or a freshly generated one.
+        let mut packet = match &mut replay {
+            Some(replay) => match replay.next_packet() {
+                Some((packet, gap)) => {
+                    if args.preserve_timing && !gap.is_zero() {
+                        thread::sleep(gap);
+                    }
+                    packet
+                }
+                None => break,
             },
+            None => {
+                let data = match args.batch_size {
+                    1 => generate_random_data(&mut rng, &args.payload_types),
+                    size => DataPayload::Batch((0..size).map(|_| generate_random_data(&mut rng, &args.payload_types)).collect()),
+                };
+                DataPacket::builder(data).metadata("source", "master-node").metadata("version", "1.0").build()
+            }
+        };
+        let data_type = packet.data_type.clone();
+
+        // A batch is as critical as its most critical item.
+        let critical = match &packet.payload {
+            DataPayload::Batch(items) => items.iter().any(|item| critical_types.contains(item.type_name())),
+            payload => critical_types.contains(payload.type_name()),
         };
+        // Replayed packets are re-stamped as if this master sent them now.
+        packet.timestamp = Utc::now().to_rfc3339();
+        packet.metadata.insert("master_id".to_string(), master_id.clone());
+        packet.metadata.insert("seq".to_string(), seq.to_string());
+        if critical {
+            packet.metadata.insert("critical".to_string(), "true".to_string());
+        }
+        match serde_json::to_value(&packet.payload) {
+            Ok(payload) => {
+                packet.metadata.insert("crc32".to_string(), payload_crc32(&payload));
+            }
+            Err(e) => warn!("Sending without a checksum, payload didn't serialize: {}", e),
+        }
 
-        match serde_json::to_string(&packet) {
-            
+        let expired = pending.expire(pending_max_age);
+        if expired > 0 {
+            warn!("Gave up on {} requests with no response after {:?}", expired, pending_max_age);
+        }
+        if !pending.reserve_slot(pending_max_age, &shutdown) {
+            break;
+        }
+
+        match encoder.encode(&packet) {
             Ok(payload) => {
-                if let Err(e) = client_clone.publish("data/request", QoS::AtLeastOnce, false, payload) {
-                    eprintln!("Failed to send data packet: {:?}", e);
-                } else {
-                    println!("Sent {} : {:?}", data_type, packet.id);
+                pending.insert(packet.clone());
+                sent += 1;
+                // Retries and backfill always go to the shared topic, in
+                // case the slave this was addressed to is the one that's gone.
+                let topic = match &args.target {
+                    Target::Broadcast => topics.request.clone(),
+                    Target::Slave(id) => topics.direct_request(id),
+                    Target::Discovered => directory.next_topic().unwrap_or_else(|| topics.request.clone()),
+                };
+                let published = confirms.publish(&client_clone, &topic, payload, critical);
+                // The exact packet that was encoded, so a replay matches.
+                if let (Some(recorder), Ok(_)) = (&mut recorder, &published) {
+                    recorder.record(&packet);
+                }
+                match published {
+                    Err(e) => {
+                        pending.complete(&packet.id);
+                        error!("Failed to send data packet: {:?}", e);
+                    }
+                    Ok(None) => {
+                        // Queued with the client only; the broker hasn't seen it yet.
+                        let (attempted, acked) = confirms.delivery();
+                        info!(
+                            packet_id = %packet.id,
+                            "Queued {} ({} publishes attempted, {} acknowledged by the broker)",
+                            data_type, attempted, acked
+                        );
+                    }
+                    Ok(Some(ticket)) => match confirms.wait(ticket, confirm_timeout) {
+                        Some(latency) => {
+                            let (confirmed, timed_out, average) = confirms.stats();
+                            info!(
+                                packet_id = %packet.id,
+                                "Sent {} (broker confirmed in {} ms; {} confirmed, {} timed out, avg {} ms)",
+                                data_type, latency.as_millis(), confirmed, timed_out, average
+                            );
+                        }
+                        None => {
+                            let (confirmed, timed_out, _) = confirms.stats();
+                            warn!(
+                                packet_id = %packet.id,
+                                "Broker did not confirm {} within {:?} ({} confirmed, {} timed out)",
+                                data_type, confirm_timeout, confirmed, timed_out
+                            );
+                        }
+                    },
+                }
+            }
+            Err(e) => error!("Failed to serialize packet: {:?}", e),
+        }
+
+        if args.bench.is_none() && (replay.is_none() || !args.preserve_timing) {
+            let delay = args.publish_delay();
+            if !delay.is_zero() {
+                thread::sleep(delay);
+            }
+        }
+    }
+
+    if let Some(recorder) = &mut recorder {
+        recorder.flush();
+    }
+    info!("Stopped sending, waiting up to {:?} for outstanding responses", args.request_timeout);
+    let deadline = Instant::now() + args.request_timeout;
+    while pending.outstanding() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    if let Err(e) = client.disconnect() {
+        error!("Failed to disconnect: {:?}", e);
+    }
+
+    if let (Some(_), Some(latency)) = (args.bench, &latency) {
+        let elapsed = started.elapsed();
+        let answered = latency.count();
+        info!(
+            "Benchmark: {} packets sent, {} answered in {:.3}s ({:.1} msg/s)",
+            sent,
+            answered,
+            elapsed.as_secs_f64(),
+            answered as f64 / elapsed.as_secs_f64()
+        );
+        if let Some((mean, p99)) = latency.mean_and_p99() {
+            info!("Benchmark latency: mean={:.1}ms p99={}ms", mean, p99);
+        }
+        if answered < sent {
+            warn!("Benchmark incomplete: {} of {} responses missing", sent - answered, sent);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("master-test-{}-{}", uuid::Uuid::new_v4(), name)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn replay_reads_the_sample_recording() {
+        let path = format!("{}/fixtures/replay-sample.jsonl", env!("CARGO_MANIFEST_DIR"));
+        let expected = std::fs::read_to_string(&path).unwrap().lines().filter(|line| !line.trim().is_empty()).count();
+        let mut replay = Replay::open(&path, false).unwrap();
+        let packets: Vec<(DataPacket, Duration)> = std::iter::from_fn(|| replay.next_packet()).collect();
+        assert_eq!(packets.len(), expected);
+        assert_eq!(packets[0].0.id, "sample-1");
+        assert_eq!(packets[0].1, Duration::ZERO);
+        assert_eq!(packets[1].0.id, "sample-2");
+        assert_eq!(packets[1].1, Duration::from_millis(500));
+        assert!(packets.iter().all(|(packet, _)| !packet.metadata.contains_key("original_id")));
+    }
+
+    #[test]
+    fn looping_replay_renames_packets_after_the_first_pass() {
+        let path = temp_path("loop.jsonl");
+        let recorded: Vec<DataPacket> =
+            (0..2).map(|n| DataPacket::builder(DataPayload::Number(n.into())).id(format!("recorded-{}", n)).build()).collect();
+        let lines: Vec<String> = recorded.iter().map(|packet| serde_json::to_string(packet).unwrap()).collect();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let mut replay = Replay::open(&path, true).unwrap();
+        let packets: Vec<DataPacket> = (0..6).map(|_| replay.next_packet().unwrap().0).collect();
+        assert_eq!(packets[0].id, "recorded-0");
+        assert_eq!(packets[1].id, "recorded-1");
+        let ids: HashSet<&str> = packets.iter().map(|packet| packet.id.as_str()).collect();
+        assert_eq!(ids.len(), 6, "ids repeated across passes: {:?}", ids);
+        for (index, packet) in packets.iter().enumerate().skip(2) {
+            assert_eq!(packet.metadata["original_id"], format!("recorded-{}", index % 2));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recorder_writes_one_packet_per_line() {
+        let path = temp_path("record.jsonl");
+        let mut rng = StdRng::seed_from_u64(7);
+        let packets: Vec<DataPacket> =
+            (0..20).map(|_| DataPacket::builder(generate_random_data(&mut rng, &GENERATED_TYPES)).build()).collect();
+        let mut recorder = Recorder::open(&path).unwrap();
+        for packet in &packets {
+            recorder.record(packet);
+        }
+        recorder.flush();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), packets.len());
+        for (line, packet) in lines.iter().zip(&packets) {
+            let recorded: DataPacket = serde_json::from_str(line).unwrap();
+            assert_eq!(serde_json::to_value(&recorded).unwrap(), serde_json::to_value(packet).unwrap());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A payload as JSON without its timestamps, which come from the clock
+    /// rather than the generator.
+    fn without_timestamps(payload: &DataPayload) -> serde_json::Value {
+        fn strip(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Object(map) => {
+                    map.remove("timestamp");
+                    map.values_mut().for_each(strip);
                 }
+                serde_json::Value::Array(items) => items.iter_mut().for_each(strip),
+                _ => {}
             }
-            Err(e) => eprintln!("Failed to serialize packet: {:?}", e),
         }
+        let mut value = serde_json::to_value(payload).unwrap();
+        strip(&mut value);
+        value
+    }
 
-        thread::sleep(Duration::from_millis(rand::random::<u64>() % 2000 + 1000));
+    #[test]
+    fn the_same_seed_generates_the_same_payloads() {
+        let generate = |seed: u64| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..200).map(|_| without_timestamps(&generate_random_data(&mut rng, &GENERATED_TYPES))).collect::<Vec<_>>()
+        };
+        assert_eq!(generate(42), generate(42));
+        assert_ne!(generate(42), generate(43));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn payload_types_are_parsed_trimmed_and_deduplicated() {
+        assert_eq!(payload_types("text").unwrap(), ["text"]);
+        assert_eq!(payload_types(" sensor_data, text ,sensor_data,").unwrap(), ["sensor_data", "text"]);
+        assert_eq!(payload_types(&GENERATED_TYPES.join(",")).unwrap(), GENERATED_TYPES);
+        assert!(payload_types("").is_err());
+        assert!(payload_types(" , ").is_err());
+    }
+
+    #[test]
+    fn unknown_payload_types_list_the_valid_names() {
+        for list in ["hologram", "text,Text", "batch"] {
+            let error = payload_types(list).unwrap_err();
+            assert!(error.starts_with("unknown payload type"), "{}", error);
+            assert!(error.ends_with(&GENERATED_TYPES.join(", ")), "{}", error);
+        }
+    }
+
+    fn pending_packet(id: &str) -> DataPacket {
+        DataPacket::builder(DataPayload::Text("waiting".to_string())).id(id).build()
+    }
+
+    #[test]
+    fn blocked_sender_frees_a_slot_once_a_request_expires() {
+        let pending = PendingTracker::new(2, OverflowPolicy::Block);
+        pending.insert(pending_packet("a"));
+        pending.insert(pending_packet("b"));
+        let shutdown = AtomicBool::new(false);
+        let started = Instant::now();
+        assert!(pending.reserve_slot(Duration::from_millis(200), &shutdown));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(pending.outstanding(), 0);
+    }
+
+    #[test]
+    fn blocked_sender_gives_up_on_shutdown() {
+        let pending = Arc::new(PendingTracker::new(1, OverflowPolicy::Block));
+        pending.insert(pending_packet("a"));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let sender = {
+            let (pending, shutdown) = (pending.clone(), shutdown.clone());
+            thread::spawn(move || pending.reserve_slot(Duration::from_secs(3600), &shutdown))
+        };
+        thread::sleep(Duration::from_millis(50));
+        shutdown.store(true, Ordering::Relaxed);
+        assert!(!sender.join().unwrap());
+        assert_eq!(pending.outstanding(), 1);
+    }
+
+    #[test]
+    fn the_generator_only_emits_the_allowed_types() {
+        let allowed = ["text", "sensor_data"];
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut seen = HashSet::new();
+        for _ in 0..5000 {
+            let name = generate_random_data(&mut rng, &allowed).type_name();
+            assert!(allowed.contains(&name), "generated {}", name);
+            seen.insert(name);
+        }
+        assert_eq!(seen.len(), allowed.len());
+    }
+}
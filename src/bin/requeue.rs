@@ -0,0 +1,205 @@
+//! Sends dead-lettered packets back to the request topic (`data/request`
+//! unless `--request-topic` says otherwise) for another attempt.
+//!
+//! With `--input <file.jsonl>` it reads one `DeadLetter` per line from a
+//! file and exits when done; otherwise it subscribes to `data/deadletter`
+//! (or the `--dead-letter` topic) and requeues as dead letters arrive. Each requeued packet is tagged with
+//! `requeued=true` and a `requeue_attempts` counter, and packets that have
+//! already been requeued `REQUEUE_MAX_ATTEMPTS` times are left alone so a
+//! permanently bad packet can't cycle forever.
+
+use base64::Engine;
+use mqtt::common::{connect, env_var, init_logging, Backoff, BrokerArgs, DeadLetter};
+use rumqttc::{Client, QoS};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Why a dead letter was not requeued.
+enum Skip {
+    /// The raw payload isn't a JSON object we can tag, e.g. invalid UTF-8
+    /// or malformed JSON; resending it untagged could loop forever.
+    NotAPacket(String),
+    TooManyAttempts(u32),
+}
+
+/// Rebuilds the original packet from a dead letter, with the requeue
+/// metadata added.
+fn prepare(dead_letter: &DeadLetter, max_attempts: u32) -> Result<String, Skip> {
+    let raw = match dead_letter.encoding.as_deref() {
+        None => dead_letter.raw.clone().into_bytes(),
+        Some("base64") => base64::engine::general_purpose::STANDARD
+            .decode(&dead_letter.raw)
+            .map_err(|e| Skip::NotAPacket(format!("bad base64: {}", e)))?,
+        Some(other) => return Err(Skip::NotAPacket(format!("unknown encoding {}", other))),
+    };
+    let mut packet: Value = serde_json::from_slice(&raw)
+        .map_err(|e| Skip::NotAPacket(format!("not JSON: {}", e)))?;
+    let Value::Object(fields) = &mut packet else {
+        return Err(Skip::NotAPacket("not a JSON object".to_string()));
+    };
+
+    let metadata = fields
+        .entry("metadata")
+        .or_insert_with(|| Value::Object(Default::default()));
+    if metadata.is_null() {
+        *metadata = Value::Object(Default::default());
+    }
+    let Value::Object(metadata) = metadata else {
+        return Err(Skip::NotAPacket("metadata is not an object".to_string()));
+    };
+    let attempts = metadata
+        .get("requeue_attempts")
+        .and_then(Value::as_str)
+        .and_then(|attempts| attempts.parse::<u32>().ok())
+        .unwrap_or(0);
+    if attempts >= max_attempts {
+        return Err(Skip::TooManyAttempts(attempts));
+    }
+    metadata.insert("requeued".to_string(), Value::String("true".to_string()));
+    metadata.insert("requeue_attempts".to_string(), Value::String((attempts + 1).to_string()));
+
+    serde_json::to_string(&packet).map_err(|e| Skip::NotAPacket(e.to_string()))
+}
+
+fn requeue(client: &Client, request_topic: &str, qos: QoS, line: &[u8], max_attempts: u32, interval: Duration) -> bool {
+    let dead_letter = match serde_json::from_slice::<DeadLetter>(line) {
+        Ok(dead_letter) => dead_letter,
+        Err(e) => {
+            warn!("Skipping line that is not a dead letter: {}", e);
+            return false;
+        }
+    };
+    let id = dead_letter.packet_id.as_deref().unwrap_or("<unknown>");
+    match prepare(&dead_letter, max_attempts) {
+        Ok(payload) => {
+            if let Err(e) = client.publish(request_topic, qos, false, payload) {
+                error!(packet_id = %id, "Failed to requeue: {:?}", e);
+                return false;
+            }
+            info!(packet_id = %id, "Requeued (was: {})", dead_letter.reason);
+            // Bounds the requeue rate so a large backlog doesn't flood the slaves.
+            thread::sleep(interval);
+            true
+        }
+        Err(Skip::NotAPacket(reason)) => {
+            warn!(packet_id = %id, "Not requeueing: {}", reason);
+            false
+        }
+        Err(Skip::TooManyAttempts(attempts)) => {
+            warn!(packet_id = %id, "Not requeueing: already requeued {} times", attempts);
+            false
+        }
+    }
+}
+
+/// Parses `--input <file>` and `--dead-letter <topic>`, returning the input
+/// file, if any, and the topic to requeue from.
+fn requeue_args(args: Vec<String>) -> Result<(Option<String>, String), String> {
+    let mut input = None;
+    let mut topic = DeadLetter::TOPIC.to_string();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => input = Some(args.next().ok_or_else(|| "--input needs a file path".to_string())?),
+            "--dead-letter" => {
+                topic = args.next().ok_or_else(|| "--dead-letter needs a topic".to_string())?;
+                DeadLetter::check_topic(&topic)?;
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    Ok((input, topic))
+}
+
+fn main() {
+    let parsed = BrokerArgs::extract(std::env::args().skip(1))
+        .and_then(|(broker, rest)| Ok((broker, requeue_args(rest)?)));
+    let (broker, (input, dead_letter_topic)) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Invalid arguments: {}", e);
+            std::process::exit(2);
+        }
+    };
+    init_logging(broker.log_format);
+    let transport = match broker.transport() {
+        Ok(transport) => transport,
+        Err(e) => {
+            error!("Invalid TLS configuration: {}", e);
+            return;
+        }
+    };
+
+    let max_attempts = env_var("REQUEUE_MAX_ATTEMPTS").unwrap_or(3);
+    let rate = env_var::<f64>("REQUEUE_RATE").filter(|rate| *rate > 0.0).unwrap_or(10.0);
+    let interval = Duration::from_secs_f64(1.0 / rate);
+
+    info!("Requeueing to {}", broker.topics.request);
+    let client_id = broker.client_id("requeue-");
+    let (client, mut connection) = connect(&client_id, &broker, transport, 10, None, None);
+
+    // Publishing can block on a full request queue, so the event loop runs
+    // on its own thread and only forwards dead letters to this one.
+    let (dead_letters, received) = mpsc::channel::<Vec<u8>>();
+    let connection_thread = thread::spawn(move || {
+        let mut backoff = Backoff::from_env();
+        for event in connection.iter() {
+            match event {
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish))) => {
+                    let _ = dead_letters.send(publish.payload.to_vec());
+                }
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => backoff.reset(),
+                Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!("Connection error: {}; reconnect attempt {} in {:?}", e, backoff.attempt(), delay);
+                    thread::sleep(delay);
+                }
+            }
+        }
+    });
+
+    match input {
+        Some(path) => {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Failed to open {}: {}", path, e);
+                    return;
+                }
+            };
+            let mut requeued = 0;
+            for line in BufReader::new(file).split(b'\n') {
+                match line {
+                    Ok(line) if line.iter().all(u8::is_ascii_whitespace) => {}
+                    Ok(line) => requeued += requeue(&client, &broker.topics.request, broker.qos, &line, max_attempts, interval) as u64,
+                    Err(e) => {
+                        error!("Failed to read {}: {}", path, e);
+                        break;
+                    }
+                }
+            }
+            info!("Requeued {} dead letters from {}", requeued, path);
+            if let Err(e) = client.disconnect() {
+                error!("Failed to disconnect: {:?}", e);
+            }
+            let _ = connection_thread.join();
+        }
+        None => {
+            if let Err(e) = client.subscribe(dead_letter_topic.as_str(), QoS::AtLeastOnce) {
+                error!("Failed to subscribe to {}: {:?}", dead_letter_topic, e);
+                return;
+            }
+            info!("Requeueing dead letters from {} at up to {} per second", dead_letter_topic, rate);
+            for line in received {
+                requeue(&client, &broker.topics.request, broker.qos, &line, max_attempts, interval);
+            }
+        }
+    }
+}
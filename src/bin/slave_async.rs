@@ -0,0 +1,30 @@
+use mqtt::common::init_logging;
+use mqtt::hooks::ProcessingHooks;
+use mqtt::processing::DefaultProcessor;
+use mqtt::slave::{run_slave_async, AsyncSlaveConfig};
+use mqtt::validation::ValidatorChain;
+use tracing::error;
+
+#[tokio::main]
+async fn main() {
+    let config = match AsyncSlaveConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid arguments: {}", e);
+            std::process::exit(2);
+        }
+    };
+    init_logging(config.broker.log_format);
+    let validators = match ValidatorChain::from_env() {
+        Ok(validators) => validators,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let processor = Box::new(DefaultProcessor::from_env());
+    if let Err(e) = run_slave_async(config, processor, ProcessingHooks::new(), validators).await {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
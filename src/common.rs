@@ -1,11 +1,24 @@
+use chrono::{DateTime, Utc};
+use rumqttc::{Client, Connection, LastWill, MqttOptions, QoS, TlsConfiguration, Transport};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum DataPayload {
     Text(String),
-    Number(f64),
-    Coordinates { x: f64, y: f64, z: f64 },
+    /// Integers stay integers: `42` round-trips as `42`, not `42.0`.
+    Number(serde_json::Number),
+    /// `x`, `y` and `z` are the three components in the order `system`
+    /// names them, e.g. `(r, θ, φ)` for spherical.
+    Coordinates {
+        x: f64,
+        y: f64,
+        z: f64,
+        #[serde(default, skip_serializing_if = "CoordinateSystem::is_cartesian")]
+        system: CoordinateSystem,
+    },
     SensorData {
         sensor_id: String,
         temperature: f64,
@@ -23,21 +36,1650 @@ pub enum DataPayload {
         message: String,
         timestamp: String,
     },
+    TimeSeries {
+        series_id: String,
+        points: Vec<TimeSeriesPoint>,
+    },
+    /// Points at data stored out of band, e.g. in object storage.
+    /// `checksum` is `sha256:<hex>` over the referenced bytes.
+    Reference {
+        uri: String,
+        size: u64,
+        content_type: String,
+        checksum: String,
+    },
+    /// Arbitrary structured data with no fixed schema.
+    Json(serde_json::Value),
+    /// Several payloads sent as one packet.
+    Batch(Vec<DataPayload>),
+}
+
+/// How a `Coordinates` payload's components are to be read. Angles are in
+/// radians.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum CoordinateSystem {
+    /// `(x, y, z)`.
+    #[default]
+    Cartesian,
+    /// `(r, θ, φ)`: radius, polar angle from the +z axis, and azimuth from
+    /// the +x axis.
+    Spherical,
+    /// `(ρ, φ, z)`: distance from the z axis, azimuth from the +x axis,
+    /// and height.
+    Cylindrical,
+}
+
+impl CoordinateSystem {
+    pub fn is_cartesian(&self) -> bool {
+        *self == CoordinateSystem::Cartesian
+    }
+
+    /// Converts three components in this system to Cartesian `(x, y, z)`.
+    /// Rounding error from the trigonometry (e.g. `cos(π/2)`) is snapped to
+    /// zero.
+    pub fn to_cartesian(self, a: f64, b: f64, c: f64) -> (f64, f64, f64) {
+        let snap = |value: f64| if value.abs() <= a.abs() * 4.0 * f64::EPSILON { 0.0 } else { value };
+        match self {
+            CoordinateSystem::Cartesian => (a, b, c),
+            CoordinateSystem::Spherical => {
+                (snap(a * b.sin() * c.cos()), snap(a * b.sin() * c.sin()), snap(a * b.cos()))
+            }
+            CoordinateSystem::Cylindrical => (snap(a * b.cos()), snap(a * b.sin()), c),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimeSeriesPoint {
+    pub timestamp: String,
+    pub value: f64,
+}
+
+/// The `data_type` names of every payload variant.
+pub const PAYLOAD_TYPE_NAMES: [&str; 10] = [
+    "text",
+    "number",
+    "coordinates",
+    "sensor_data",
+    "image_data",
+    "log_entry",
+    "time_series",
+    "reference",
+    "json",
+    "batch",
+];
+
+impl DataPayload {
+    /// The `data_type` name used on the wire for this variant.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            DataPayload::Text(_) => "text",
+            DataPayload::Number(_) => "number",
+            DataPayload::Coordinates { .. } => "coordinates",
+            DataPayload::SensorData { .. } => "sensor_data",
+            DataPayload::ImageData { .. } => "image_data",
+            DataPayload::LogEntry { .. } => "log_entry",
+            DataPayload::TimeSeries { .. } => "time_series",
+            DataPayload::Reference { .. } => "reference",
+            DataPayload::Json(_) => "json",
+            DataPayload::Batch(_) => "batch",
+        }
+    }
+}
+
+/// 64-bit FNV-1a hash. Unlike `DefaultHasher` it is stable across runs and
+/// Rust versions, so it can be used for keys that leave the process.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A deterministic JSON encoding of a payload for hashing and dedup: object
+/// keys sorted, no whitespace. Doesn't depend on serde_json's map ordering,
+/// which changes with its `preserve_order` feature.
+pub fn canonical_bytes(payload: &DataPayload) -> Vec<u8> {
+    canonical_value_bytes(&serde_json::to_value(payload).unwrap_or(serde_json::Value::Null))
+}
+
+/// CRC32 of a payload's [`canonical_value_bytes`], as carried in
+/// `metadata["crc32"]` (8 lowercase hex digits). Hashing the canonical form
+/// lets the slave check the payload it parsed, whatever wire format or key
+/// order it arrived in.
+pub fn payload_crc32(payload: &serde_json::Value) -> String {
+    format!("{:08x}", crc32fast::hash(&canonical_value_bytes(payload)))
+}
+
+/// [`canonical_bytes`] for an arbitrary JSON value.
+pub fn canonical_value_bytes(value: &serde_json::Value) -> Vec<u8> {
+    fn write(value: &serde_json::Value, out: &mut Vec<u8>) {
+        match value {
+            serde_json::Value::Array(items) => {
+                out.push(b'[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(b',');
+                    }
+                    write(item, out);
+                }
+                out.push(b']');
+            }
+            serde_json::Value::Object(map) => {
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                out.push(b'{');
+                for (i, (key, item)) in entries.into_iter().enumerate() {
+                    if i > 0 {
+                        out.push(b',');
+                    }
+                    out.extend_from_slice(serde_json::Value::String(key.clone()).to_string().as_bytes());
+                    out.push(b':');
+                    write(item, out);
+                }
+                out.push(b'}');
+            }
+            scalar => out.extend_from_slice(scalar.to_string().as_bytes()),
+        }
+    }
+
+    let mut out = Vec::new();
+    write(value, &mut out);
+    out
+}
+
+/// Formats a float in plain fixed notation with about `significant_digits`
+/// significant digits, never falling back to scientific notation. Trailing
+/// zeros are trimmed, so exact integers print without a fractional part and
+/// large magnitudes keep all their integer digits.
+pub fn format_float(value: f64, significant_digits: usize) -> String {
+    if !value.is_finite() {
+        return value.to_string();
+    }
+    if value == 0.0 {
+        return "0".to_string();
+    }
+    let magnitude = value.abs().log10().floor() as i64;
+    let decimals = (significant_digits.max(1) as i64 - 1 - magnitude).max(0) as usize;
+    let formatted = format!("{:.*}", decimals, value);
+    if formatted.contains('.') {
+        formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        formatted
+    }
+}
+
+/// Derives a key from the payload content that downstream consumers can
+/// partition on:
+///
+/// - `SensorData`: the `sensor_id`
+/// - `LogEntry`: the log `level`
+/// - `Text`: `text-` followed by a hex FNV-1a hash of the text
+/// - `TimeSeries`: the `series_id`
+/// - `Reference`: the `uri`
+/// - `Number`, `Coordinates`, `ImageData`, `Json`, `Batch`: no natural key, so `packet_id`
+pub fn routing_key(payload: &DataPayload, packet_id: &str) -> String {
+    match payload {
+        DataPayload::SensorData { sensor_id, .. } => sensor_id.clone(),
+        DataPayload::LogEntry { level, .. } => level.clone(),
+        DataPayload::Text(text) => format!("text-{:016x}", fnv1a(text.as_bytes())),
+        DataPayload::TimeSeries { series_id, .. } => series_id.clone(),
+        DataPayload::Reference { uri, .. } => uri.clone(),
+        DataPayload::Number(_)
+        | DataPayload::Coordinates { .. }
+        | DataPayload::ImageData { .. }
+        | DataPayload::Json(_)
+        | DataPayload::Batch(_) => {
+            packet_id.to_string()
+        }
+    }
+}
+
+/// The packet format this build speaks. A slave refuses packets whose
+/// major version differs.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DataPacket {
     pub id: String,
     pub timestamp: String,
     pub data_type: String,
     pub payload: DataPayload,
     pub metadata: HashMap<String, String>,
+    /// 0 on packets from masters that predate the field.
+    #[serde(default)]
+    pub protocol_version: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl DataPacket {
+    /// Starts a packet around `payload`. `data_type` always comes from the
+    /// payload's variant, so the two can't disagree.
+    pub fn builder(payload: DataPayload) -> DataPacketBuilder {
+        DataPacketBuilder { payload, id: None, timestamp: None, metadata: HashMap::new() }
+    }
+}
+
+/// Builds a [`DataPacket`]; a fresh uuid and the current time are filled in
+/// unless given.
+pub struct DataPacketBuilder {
+    payload: DataPayload,
+    id: Option<String>,
+    timestamp: Option<DateTime<Utc>>,
+    metadata: HashMap<String, String>,
+}
+
+impl DataPacketBuilder {
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: DateTime<Utc>) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn build(self) -> DataPacket {
+        DataPacket {
+            id: self.id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            timestamp: self.timestamp.unwrap_or_else(Utc::now).to_rfc3339(),
+            data_type: self.payload.type_name().to_string(),
+            payload: self.payload,
+            metadata: self.metadata,
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// The `id` of a JSON packet that starts with it, as `DataPacket` is
+/// serialized, found without parsing the rest of the message.
+pub fn leading_packet_id(payload: &[u8]) -> Option<String> {
+    let rest = payload.strip_prefix(br#"{"id":""#)?;
+    let end = rest.iter().take(256).position(|&b| b == b'"' || b == b'\\')?;
+    (rest[end] == b'"').then(|| String::from_utf8_lossy(&rest[..end]).into_owned())
+}
+
+/// `packet_id` of the response to a message too broken to carry an id.
+pub const UNPARSED_PACKET_ID: &str = "unknown";
+
+/// Outcome of a packet, carried in [`DataResponse::status`].
+///
+/// `Ok` is sent as a plain string, exactly as every status used to be, so a
+/// plain-string status from an older slave reads back as `Ok`. The failure
+/// variants are sent tagged, e.g. `{"ValidationError": "..."}`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(from = "StatusRepr", into = "StatusRepr")]
+pub enum ResponseStatus {
+    Ok(String),
+    /// The packet couldn't be parsed, with the parser's error.
+    ParseError(String),
+    /// The payload matched no known variant.
+    ConversionError,
+    ValidationError(String),
+    /// Refused without processing, e.g. under memory pressure.
+    Rejected(String),
+    /// The payload doesn't match its `crc32` metadata.
+    ChecksumError(String),
+}
+
+impl ResponseStatus {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, ResponseStatus::Ok(_))
+    }
+}
+
+impl From<String> for ResponseStatus {
+    fn from(status: String) -> Self {
+        ResponseStatus::Ok(status)
+    }
+}
+
+impl From<&str> for ResponseStatus {
+    fn from(status: &str) -> Self {
+        ResponseStatus::Ok(status.to_string())
+    }
+}
+
+/// The status text used where only a string fits, such as the legacy
+/// response schema.
+impl std::fmt::Display for ResponseStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResponseStatus::Ok(status) => write!(f, "{}", status),
+            ResponseStatus::ParseError(e) => write!(f, "PARSE ERROR: {}", e),
+            ResponseStatus::ConversionError => write!(f, "CONVERSION ERROR: unknown payload"),
+            ResponseStatus::ValidationError(e) => write!(f, "INVALID: {}", e),
+            ResponseStatus::Rejected(reason) => write!(f, "REJECTED: {}", reason),
+            ResponseStatus::ChecksumError(e) => write!(f, "CHECKSUM ERROR: {}", e),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum StatusRepr {
+    Plain(String),
+    Tagged(TaggedStatus),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+enum TaggedStatus {
+    ParseError(String),
+    ConversionError(()),
+    ValidationError(String),
+    Rejected(String),
+    ChecksumError(String),
+}
+
+impl From<StatusRepr> for ResponseStatus {
+    fn from(repr: StatusRepr) -> Self {
+        match repr {
+            StatusRepr::Plain(status) => ResponseStatus::Ok(status),
+            StatusRepr::Tagged(TaggedStatus::ParseError(e)) => ResponseStatus::ParseError(e),
+            StatusRepr::Tagged(TaggedStatus::ConversionError(())) => ResponseStatus::ConversionError,
+            StatusRepr::Tagged(TaggedStatus::ValidationError(e)) => ResponseStatus::ValidationError(e),
+            StatusRepr::Tagged(TaggedStatus::Rejected(reason)) => ResponseStatus::Rejected(reason),
+            StatusRepr::Tagged(TaggedStatus::ChecksumError(e)) => ResponseStatus::ChecksumError(e),
+        }
+    }
+}
+
+impl From<ResponseStatus> for StatusRepr {
+    fn from(status: ResponseStatus) -> Self {
+        match status {
+            ResponseStatus::Ok(status) => StatusRepr::Plain(status),
+            ResponseStatus::ParseError(e) => StatusRepr::Tagged(TaggedStatus::ParseError(e)),
+            ResponseStatus::ConversionError => StatusRepr::Tagged(TaggedStatus::ConversionError(())),
+            ResponseStatus::ValidationError(e) => StatusRepr::Tagged(TaggedStatus::ValidationError(e)),
+            ResponseStatus::Rejected(reason) => StatusRepr::Tagged(TaggedStatus::Rejected(reason)),
+            ResponseStatus::ChecksumError(e) => StatusRepr::Tagged(TaggedStatus::ChecksumError(e)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DataResponse {
     pub packet_id: String,
     pub received_at: String,
-    pub status: String,
+    pub status: ResponseStatus,
     pub processing_time_ms: u64,
+    /// Structured output derived from the payload (e.g. an image thumbnail).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// Content-derived partitioning key, see [`routing_key`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_key: Option<String>,
+    /// Set when optional fields were dropped to fit the response size cap.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Position of the packet in the slave's receive order, starting at 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receive_index: Option<u64>,
+}
+
+impl DataResponse {
+    /// Builds the response for a processed (or refused) packet, stamped with
+    /// the current time. Optional fields start empty.
+    pub fn from_outcome(packet_id: String, status: impl Into<ResponseStatus>, processing_time_ms: u64) -> Self {
+        Self::from_outcome_at(packet_id, status, processing_time_ms, Utc::now())
+    }
+
+    /// Like [`DataResponse::from_outcome`], with an explicit `received_at`.
+    pub fn from_outcome_at(
+        packet_id: String,
+        status: impl Into<ResponseStatus>,
+        processing_time_ms: u64,
+        received_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            packet_id,
+            received_at: received_at.to_rfc3339(),
+            status: status.into(),
+            processing_time_ms,
+            result: None,
+            routing_key: None,
+            truncated: false,
+            receive_index: None,
+        }
+    }
+
+    pub fn with_result(mut self, result: Option<serde_json::Value>) -> Self {
+        self.result = result;
+        self
+    }
+
+    pub fn with_routing_key(mut self, routing_key: impl Into<String>) -> Self {
+        self.routing_key = Some(routing_key.into());
+        self
+    }
+
+    pub fn with_receive_index(mut self, receive_index: u64) -> Self {
+        self.receive_index = Some(receive_index);
+        self
+    }
+
+    /// Drops the heavy optional fields and marks the response as truncated.
+    pub fn truncate(&mut self) {
+        self.result = None;
+        self.truncated = true;
+    }
+}
+
+
+/// The flat response shape older consumers expect: just the packet id and
+/// the status text.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LegacyResponse {
+    pub id: String,
+    pub result: String,
+}
+
+/// Wire format for responses, chosen with `RESPONSE_SCHEMA`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ResponseSchema {
+    /// `{"id": ..., "result": ...}`, for consumers predating `DataResponse`.
+    Legacy,
+    /// The full `DataResponse`.
+    #[default]
+    V2,
+}
+
+impl std::str::FromStr for ResponseSchema {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" => Ok(ResponseSchema::Legacy),
+            "v2" => Ok(ResponseSchema::V2),
+            other => Err(format!("unknown response schema {:?}, expected legacy or v2", other)),
+        }
+    }
+}
+
+/// How packets and responses are encoded on the wire (`--format`). JSON
+/// goes out as plain text, as it always has; the binary formats are
+/// prefixed with a tag byte that can't start a JSON document, so a receiver
+/// can tell them apart without knowing the sender's setting.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl std::str::FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(WireFormat::Json),
+            "cbor" => Ok(WireFormat::Cbor),
+            "msgpack" => Ok(WireFormat::MsgPack),
+            other => Err(format!("unknown format {:?}, expected json, cbor or msgpack", other)),
+        }
+    }
+}
+
+impl WireFormat {
+    const CBOR_TAG: u8 = 0x01;
+    const MSGPACK_TAG: u8 = 0x02;
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            WireFormat::Cbor => {
+                let mut out = vec![Self::CBOR_TAG];
+                ciborium::into_writer(value, &mut out).map_err(|e| e.to_string())?;
+                Ok(out)
+            }
+            WireFormat::MsgPack => {
+                let mut out = vec![Self::MSGPACK_TAG];
+                rmp_serde::encode::write_named(&mut out, value).map_err(|e| e.to_string())?;
+                Ok(out)
+            }
+        }
+    }
+
+    /// Re-encodes an already serialized JSON document, e.g. a response
+    /// shaped by [`ResponseSchema`].
+    pub fn encode_json(self, json: String) -> Result<Vec<u8>, String> {
+        match self {
+            WireFormat::Json => Ok(json.into_bytes()),
+            format => {
+                let value: serde_json::Value = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+                format.encode(&value)
+            }
+        }
+    }
+
+    /// Detects the format of a received payload from its tag byte.
+    pub fn detect(payload: &[u8]) -> Self {
+        match payload.first() {
+            Some(&Self::CBOR_TAG) => WireFormat::Cbor,
+            Some(&Self::MSGPACK_TAG) => WireFormat::MsgPack,
+            _ => WireFormat::Json,
+        }
+    }
+
+    /// Returns a received payload as JSON bytes, transcoding binary formats
+    /// so everything downstream only has to deal with JSON.
+    pub fn to_json(payload: &[u8]) -> Result<std::borrow::Cow<'_, [u8]>, String> {
+        let value: serde_json::Value = match Self::detect(payload) {
+            WireFormat::Json => return Ok(std::borrow::Cow::Borrowed(payload)),
+            WireFormat::Cbor => ciborium::from_reader(&payload[1..]).map_err(|e| format!("invalid CBOR: {}", e))?,
+            WireFormat::MsgPack => {
+                rmp_serde::from_slice(&payload[1..]).map_err(|e| format!("invalid MessagePack: {}", e))?
+            }
+        };
+        serde_json::to_vec(&value).map(std::borrow::Cow::Owned).map_err(|e| e.to_string())
+    }
+}
+
+/// Gzip-compresses an encoded payload. The gzip magic bytes (0x1f 0x8b)
+/// can't start a JSON document or a format tag, so [`decompress`] needs no
+/// extra marker to recognise it.
+pub fn compress(payload: &[u8]) -> Result<Vec<u8>, String> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(payload).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+/// Undoes [`compress`] when the payload is gzipped, refusing to inflate
+/// past `max_bytes`; anything else is returned as is.
+pub fn decompress(payload: &[u8], max_bytes: u64) -> Result<std::borrow::Cow<'_, [u8]>, String> {
+    use std::io::Read;
+    if !payload.starts_with(&[0x1f, 0x8b]) {
+        return Ok(std::borrow::Cow::Borrowed(payload));
+    }
+    let mut out = Vec::new();
+    flate2::read::GzDecoder::new(payload)
+        .take(max_bytes + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| format!("invalid gzip: {}", e))?;
+    if out.len() as u64 > max_bytes {
+        return Err(format!("decompressed payload exceeds {} bytes", max_bytes));
+    }
+    Ok(std::borrow::Cow::Owned(out))
+}
+
+impl ResponseSchema {
+    pub fn serialize(self, response: &DataResponse) -> serde_json::Result<String> {
+        match self {
+            ResponseSchema::Legacy => serde_json::to_string(&LegacyResponse {
+                id: response.packet_id.clone(),
+                result: response.status.to_string(),
+            }),
+            ResponseSchema::V2 => serde_json::to_string(response),
+        }
+    }
+}
+
+/// Sent by the slave on `data/ack` as soon as it picks up a packet, before
+/// processing, so the master can tell a slave that never got a request from
+/// one that got it but hasn't answered.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ack {
+    pub packet_id: String,
+    pub received_at: DateTime<Utc>,
+}
+
+/// A slave's answer on [`SlaveStatus::RESPONSE_TOPIC`] to any message on
+/// [`SlaveStatus::REQUEST_TOPIC`]: its counters as of the request, without
+/// waiting for the next `data/metrics` publish. Every slave answers, so
+/// `slave_id` tells the replies apart.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SlaveStatus {
+    pub slave_id: String,
+    pub uptime_secs: u64,
+    pub metrics: MetricsSnapshot,
+}
+
+impl SlaveStatus {
+    pub const REQUEST_TOPIC: &'static str = "slaves/status/request";
+    pub const RESPONSE_TOPIC: &'static str = "slaves/status/response";
+}
+
+/// Announced by a slave on its own [`SlaveOnline::topic`] each time it
+/// connects, so masters and balancers can discover slaves to address
+/// directly. The announcement is retained, so a subscriber that starts after
+/// the slave still finds it; a slave shutting down gracefully clears it with
+/// an empty retained message, which subscribers take as the slave going
+/// offline.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SlaveOnline {
+    pub slave_id: String,
+    /// Where to publish to reach only this slave.
+    pub request_topic: String,
+}
+
+impl SlaveOnline {
+    /// Matches every slave's announcement topic.
+    pub const FILTER: &'static str = "slaves/online/+";
+
+    /// Where `slave_id` announces itself: `slaves/online/<slave id>`.
+    pub fn topic(slave_id: &str) -> String {
+        format!("slaves/online/{}", slave_id)
+    }
+
+    /// The slave id of an announcement topic, or `None` for any other topic.
+    pub fn slave_id_from_topic(topic: &str) -> Option<&str> {
+        topic.strip_prefix("slaves/online/").filter(|id| !id.is_empty() && !id.contains('/'))
+    }
+}
+
+/// Published by each slave on [`Heartbeat::TOPIC`] every `--heartbeat-secs`.
+/// A slave that stops without a graceful shutdown has its last will
+/// published on [`Heartbeat::OFFLINE_TOPIC`] by the broker instead, carrying
+/// its id just as a graceful shutdown does.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Heartbeat {
+    pub slave_id: String,
+    pub uptime_secs: u64,
+    pub processed_count: u64,
+    /// Seconds until the next heartbeat is due.
+    #[serde(default)]
+    pub interval_secs: u64,
+}
+
+impl Heartbeat {
+    pub const TOPIC: &'static str = "slaves/heartbeat";
+    pub const OFFLINE_TOPIC: &'static str = "slaves/offline";
+
+    /// The last will a slave connects with.
+    pub fn last_will(slave_id: &str) -> LastWill {
+        LastWill::new(Self::OFFLINE_TOPIC, slave_id.to_string(), QoS::AtLeastOnce, false)
+    }
+}
+
+/// A message the slave could not handle, republished with the reason so
+/// operators can triage bad producers.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetter {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packet_id: Option<String>,
+    pub reason: String,
+    pub raw: String,
+    /// Set to `"base64"` when `raw` holds base64-encoded bytes that weren't
+    /// valid UTF-8; absent when `raw` is the original text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+impl DeadLetter {
+    /// Where dead letters go unless `--dead-letter` names another topic.
+    pub const TOPIC: &'static str = "data/deadletter";
+
+    /// Checks a `--dead-letter` topic: dead letters are published to it, so
+    /// it can't be empty or contain wildcards.
+    pub fn check_topic(topic: &str) -> Result<(), String> {
+        if topic.is_empty() || topic.contains(['+', '#']) {
+            return Err(format!("invalid dead-letter topic {:?}", topic));
+        }
+        Ok(())
+    }
+}
+
+/// An operator command sent to slaves on `slave/command`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Command {
+    pub action: String,
+}
+
+/// Result of running the processing pipeline over one sample payload.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SelfTestResult {
+    pub payload_type: String,
+    pub passed: bool,
+    /// The processing status on success, or what went wrong.
+    pub detail: String,
+    pub duration_us: u64,
+}
+
+/// Published on `slave/selftest` after a `self_test` command.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SelfTestReport {
+    pub slave_id: String,
+    pub ran_at: DateTime<Utc>,
+    pub passed: bool,
+    pub results: Vec<SelfTestResult>,
+}
+
+/// A point-in-time copy of the slave's processing counters, as published on
+/// `data/metrics`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub processed_count: u64,
+    pub total_processing_time_ms: u64,
+    pub text_count: u64,
+    pub number_count: u64,
+    pub coordinates_count: u64,
+    pub sensor_count: u64,
+    pub image_count: u64,
+    pub log_count: u64,
+    #[serde(default)]
+    pub time_series_count: u64,
+    #[serde(default)]
+    pub reference_count: u64,
+    #[serde(default)]
+    pub json_count: u64,
+    /// Messages dead-lettered because they weren't valid UTF-8.
+    #[serde(default)]
+    pub bad_utf8: u64,
+    /// Images rejected because their declared dimensions exceed `MAX_IMAGE_DIM`.
+    #[serde(default)]
+    pub rejected_image_dims: u64,
+    /// Sequence numbers skipped in a master's stream.
+    #[serde(default)]
+    pub seq_gaps: u64,
+    /// Packets that arrived with a lower sequence number than one already seen.
+    #[serde(default)]
+    pub seq_reorders: u64,
+    /// Packets skipped because their id was already processed (`DEDUP_BY_ID`).
+    #[serde(default)]
+    pub duplicate_ids: u64,
+    /// Packets skipped because identical content was already processed (`DEDUP_BY_CONTENT`).
+    #[serde(default)]
+    pub duplicate_contents: u64,
+    /// Packets dead-lettered for a metadata `version` outside `SUPPORTED_VERSIONS`.
+    #[serde(default)]
+    pub unsupported_versions: u64,
+    /// Payloads whose re-serialized form differed from what was received (`VERIFY_ROUNDTRIP`).
+    #[serde(default)]
+    pub roundtrip_mismatches: u64,
+    /// Individual points emitted by `EXPLODE_TIMESERIES`.
+    #[serde(default)]
+    pub exploded_points: u64,
+    /// Total time the CPU throttle has slept to stay under `CPU_TARGET_PCT`.
+    #[serde(default)]
+    pub throttle_sleep_ms: u64,
+    /// Packets dead-lettered by `STRICT_FIELDS` for carrying unknown fields.
+    #[serde(default)]
+    pub unknown_fields: u64,
+    /// Responses emitted behind one with a later receive index; only
+    /// checked on a single-worker slave, where it should never happen.
+    #[serde(default)]
+    pub out_of_order_responses: u64,
+    /// Images being processed right now, bounded by `MAX_CONCURRENT_IMAGES`.
+    #[serde(default)]
+    pub images_in_progress: u64,
+    /// Resident memory at the last `MEMORY_CAP_MB` check; 0 when not monitored.
+    #[serde(default)]
+    pub rss_bytes: u64,
+    /// Average number of busy workers across saturation samples.
+    #[serde(default)]
+    pub avg_busy_workers: f64,
+    /// Fraction of samples where every worker was busy with packets queued.
+    #[serde(default)]
+    pub saturated_fraction: f64,
+    /// Approximate time spent saturated, in sample-interval steps.
+    #[serde(default)]
+    pub saturated_ms: u64,
+    /// Packets rejected because RSS was over the cap.
+    #[serde(default)]
+    pub shed_memory: u64,
+    /// Log entries below `--min-log-level`, counted here instead of `log_count`.
+    #[serde(default)]
+    pub log_filtered: u64,
+    /// Messages refused unread for exceeding `--max-payload-bytes`.
+    #[serde(default)]
+    pub rejected_oversize: u64,
+    /// Packets refused for a protocol version this slave doesn't speak.
+    #[serde(default)]
+    pub protocol_mismatches: u64,
+    /// Packets whose payload didn't match their `crc32` metadata.
+    #[serde(default)]
+    pub checksum_failures: u64,
+    /// Packets whose declared `data_type` didn't match their payload; they
+    /// are still processed by payload.
+    #[serde(default)]
+    pub data_type_mismatches: u64,
+    /// QoS 1/2 publishes handed to the client. Being queued locally says
+    /// nothing about delivery; compare with `publish_confirms`.
+    #[serde(default)]
+    pub publish_attempts: u64,
+    /// `PubAck`/`PubComp` packets received from the broker.
+    #[serde(default)]
+    pub publish_confirms: u64,
+    /// Packets that failed a `VALIDATORS` check, or were answered `INVALID:`
+    /// because a sensor reading was out of range.
+    #[serde(default)]
+    pub validation_failures: u64,
+    /// How often the broker connection dropped, by reason.
+    #[serde(default)]
+    pub disconnect_reasons: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub connection: ConnectionMetrics,
+    /// Per-tenant breakdown for packets whose metadata names a `tenant`.
+    /// Tenants beyond the tracking limit are pooled under `_other`.
+    #[serde(default)]
+    pub tenants: BTreeMap<String, TenantMetrics>,
+}
+
+impl MetricsSnapshot {
+    /// Processed counts by payload `data_type` name, in
+    /// [`PAYLOAD_TYPE_NAMES`] order. Batches are counted by their items, so
+    /// `batch` is left out.
+    pub fn type_counts(&self) -> Vec<(&'static str, u64)> {
+        PAYLOAD_TYPE_NAMES.iter().filter_map(|&name| Some((name, self.type_count(name)?))).collect()
+    }
+
+    /// The processed count for one `data_type` name; `None` for `batch` and
+    /// for names that aren't payload types.
+    pub fn type_count(&self, name: &str) -> Option<u64> {
+        match name {
+            "text" => Some(self.text_count),
+            "number" => Some(self.number_count),
+            "coordinates" => Some(self.coordinates_count),
+            "sensor_data" => Some(self.sensor_count),
+            "image_data" => Some(self.image_count),
+            "log_entry" => Some(self.log_count),
+            "time_series" => Some(self.time_series_count),
+            "reference" => Some(self.reference_count),
+            "json" => Some(self.json_count),
+            _ => None,
+        }
+    }
+}
+
+/// Processing counters for one tenant.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TenantMetrics {
+    pub processed_count: u64,
+    pub total_processing_time_ms: u64,
+    /// Processed packets by payload type name.
+    pub type_counts: BTreeMap<String, u64>,
+}
+
+/// Broker connection stability, to tell a flaky broker from a flaky slave.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionMetrics {
+    pub connected: bool,
+    /// Successful connects after the first one.
+    pub reconnects: u64,
+    pub last_connected_at: Option<DateTime<Utc>>,
+    /// Total time spent connected, including the current connection.
+    pub connected_uptime_secs: u64,
+}
+
+/// Why the slave declined to process a packet. Every policy that can skip a
+/// message reports through this, so the response and metrics follow from the
+/// reason alone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+    /// The metadata version isn't in `SUPPORTED_VERSIONS`.
+    UnsupportedVersion(String),
+    /// A packet with this id was already processed within the dedup window.
+    DuplicateId,
+    /// A packet with the same payload was already processed within the dedup window.
+    DuplicateContent,
+    /// The image's declared dimensions exceed `MAX_IMAGE_DIM`.
+    ImageTooLarge(String),
+    /// Resident memory is over `MEMORY_CAP_MB`; new work is shed until it recovers.
+    MemoryPressure,
+    /// A log entry below `--min-log-level`.
+    LogLevelFiltered(String),
+    /// The packet's major protocol version differs from [`PROTOCOL_VERSION`].
+    ProtocolMismatch(String),
+    /// The payload doesn't match the `crc32` in its metadata.
+    ChecksumMismatch(String),
+}
+
+impl SkipReason {
+    /// Short label used in logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::UnsupportedVersion(_) => "unsupported_version",
+            SkipReason::DuplicateId => "duplicate_id",
+            SkipReason::DuplicateContent => "duplicate_content",
+            SkipReason::ImageTooLarge(_) => "image_too_large",
+            SkipReason::MemoryPressure => "memory_pressure",
+            SkipReason::LogLevelFiltered(_) => "log_filtered",
+            SkipReason::ProtocolMismatch(_) => "protocol_mismatch",
+            SkipReason::ChecksumMismatch(_) => "checksum_mismatch",
+        }
+    }
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::UnsupportedVersion(detail)
+            | SkipReason::ImageTooLarge(detail)
+            | SkipReason::LogLevelFiltered(detail)
+            | SkipReason::ProtocolMismatch(detail)
+            | SkipReason::ChecksumMismatch(detail) => f.write_str(detail),
+            SkipReason::DuplicateId => f.write_str("duplicate packet id"),
+            SkipReason::DuplicateContent => f.write_str("duplicate content"),
+            SkipReason::MemoryPressure => f.write_str("memory pressure"),
+        }
+    }
+}
+
+/// Severity of a `LogEntry`, least severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// The severity of a producer's level string, ignoring case. Levels it
+    /// doesn't recognise rank as `Error`, so a filter never drops them.
+    pub fn of(level: &str) -> LogLevel {
+        level.parse().unwrap_or(LogLevel::Error)
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_uppercase().as_str() {
+            "TRACE" => Ok(LogLevel::Trace),
+            "DEBUG" => Ok(LogLevel::Debug),
+            "INFO" => Ok(LogLevel::Info),
+            "WARN" | "WARNING" => Ok(LogLevel::Warn),
+            "ERROR" => Ok(LogLevel::Error),
+            _ => Err(format!("unknown log level {:?}, expected TRACE, DEBUG, INFO, WARN or ERROR", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogLevel::Trace => "TRACE",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        })
+    }
+}
+
+/// Parses a QoS level given as 0, 1 or 2.
+pub fn parse_qos(level: &str) -> Result<QoS, String> {
+    level.trim()
+        .parse::<u8>()
+        .ok()
+        .and_then(|level| rumqttc::qos(level).ok())
+        .ok_or_else(|| format!("invalid QoS level {:?}, expected 0, 1 or 2", level))
+}
+
+/// How log lines are written (`--log-format`): readable text, or one JSON
+/// object per line for log collectors.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format {:?}, expected text or json", other)),
+        }
+    }
+}
+
+/// Installs the global log subscriber. `RUST_LOG` picks the levels
+/// (default `info`); closing a span logs how long it was open.
+pub fn init_logging(format: LogFormat) {
+    use tracing_subscriber::fmt::format::FmtSpan;
+    use tracing_subscriber::EnvFilter;
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stdout()));
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Reads an environment variable and parses it, warning about values that
+/// are present but can't be parsed.
+pub fn env_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let raw = std::env::var(name).ok()?;
+    match raw.trim().parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            tracing::warn!("Ignoring invalid value for {}: {:?}", name, raw);
+            None
+        }
+    }
+}
+
+/// Parses a comma-separated list of payload type names such as
+/// `QUIET_TYPES`, dropping (and warning about) names that aren't known types.
+pub fn type_list_from_env(var: &str) -> HashSet<String> {
+    let raw = std::env::var(var).unwrap_or_default();
+    raw.split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .filter(|name| {
+            let known = PAYLOAD_TYPE_NAMES.contains(name);
+            if !known {
+                tracing::warn!("Ignoring unknown type in {}: {}", var, name);
+            }
+            known
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+
+/// Builds the broker transport. Connections are plain TCP unless a PEM CA
+/// bundle is given (`--ca-cert` or `MQTT_CA_CERT`); adding a client
+/// certificate and key (`--client-cert`/`--client-key` or
+/// `MQTT_CLIENT_CERT`/`MQTT_CLIENT_KEY`) authenticates with it (mTLS).
+/// Flags win over the environment.
+fn build_transport(broker: &BrokerArgs) -> Result<Transport, String> {
+    let from_env = |var: &str| std::env::var(var).ok();
+    let ca_path = broker.ca_cert.clone().or_else(|| from_env("MQTT_CA_CERT"));
+    let cert_path = broker.client_cert.clone().or_else(|| from_env("MQTT_CLIENT_CERT"));
+    let key_path = broker.client_key.clone().or_else(|| from_env("MQTT_CLIENT_KEY"));
+
+    let client_auth = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => Some(load_client_auth(&cert_path, &key_path)?),
+        (None, None) => None,
+        (Some(_), None) => return Err("a client certificate was given without a client key".to_string()),
+        (None, Some(_)) => return Err("a client key was given without a client certificate".to_string()),
+    };
+
+    let Some(ca_path) = ca_path else {
+        return match (client_auth, broker.tls) {
+            (Some(_), _) => Err("a client certificate requires a CA certificate to verify the broker".to_string()),
+            (None, true) => Err("--tls needs --ca-cert (or MQTT_CA_CERT) to verify the broker".to_string()),
+            (None, false) => Ok(Transport::Tcp),
+        };
+    };
+
+    let ca = read_pem(&ca_path)?;
+    let ca_certs = rustls_pemfile::certs(&mut ca.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("failed to parse {}: {}", ca_path, e))?;
+    if ca_certs.is_empty() {
+        return Err(format!("{} contains no certificates", ca_path));
+    }
+
+    Ok(Transport::Tls(TlsConfiguration::Simple { ca, alpn: None, client_auth }))
+}
+
+/// The broker port for a transport: 8883 for TLS, 1883 otherwise.
+pub fn default_port(transport: &Transport) -> u16 {
+    match transport {
+        Transport::Tls(_) => 8883,
+        _ => 1883,
+    }
+}
+
+/// Where to connect, from `--broker-host`, `--broker-port`, `--client-id`
+/// and `--client-id-prefix`.
+pub struct BrokerArgs {
+    pub host: String,
+    /// `None` means the transport's default port.
+    pub port: Option<u16>,
+    /// `--client-id`: the exact id to connect with, the same on every start.
+    pub client_id: Option<String>,
+    pub client_id_prefix: Option<String>,
+    /// `--tls`: refuse to fall back to plain TCP.
+    pub tls: bool,
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// Username and password, from `--username`/`--password` or
+    /// `MQTT_USERNAME`/`MQTT_PASSWORD`.
+    pub credentials: Option<(String, String)>,
+    pub topics: Topics,
+    /// `--qos`: level for the request and response traffic, both publishing
+    /// and subscribing (the broker delivers at the lower of the two). 0 is
+    /// fire-and-forget: nothing is acknowledged, so a lost packet only shows
+    /// up as a request timeout and the master can't confirm critical
+    /// publishes. 2 is exactly-once: every publish takes a PUBREC, PUBREL,
+    /// PUBCOMP exchange instead of a single PUBACK, doubling the round trips
+    /// to the broker. Defaults to 1.
+    pub qos: QoS,
+    /// `--keep-alive-secs`: how long the connection may sit idle before the
+    /// client pings the broker; the broker drops a client it hasn't heard
+    /// from in one and a half times this. 0 turns keep-alive off. Defaults
+    /// to 5.
+    pub keep_alive: Duration,
+    /// `--clean-session <true|false>`: `false` asks the broker to keep the
+    /// session, subscriptions included, while the client is away, and to
+    /// queue the messages it would have received. Only QoS 1 and 2 messages
+    /// are queued (QoS 0 is dropped, so pair it with `--qos 1` or 2), and
+    /// only a client coming back with the same id gets them: that holds
+    /// when this process reconnects, and across restarts only with a fixed
+    /// `--client-id`. Defaults to `true`.
+    pub clean_session: bool,
+    /// `--log-format`; not a broker setting, but every binary takes it.
+    pub log_format: LogFormat,
+}
+
+/// The topic pair one master/slave deployment talks over
+/// (`--request-topic`, `--response-topic`). Separate pairs let several
+/// deployments share a broker.
+#[derive(Debug, Clone)]
+pub struct Topics {
+    pub request: String,
+    pub response: String,
+}
+
+impl Default for Topics {
+    fn default() -> Self {
+        Self { request: "data/request".to_string(), response: "data/response".to_string() }
+    }
+}
+
+impl Topics {
+    /// The request topic of a single slave, `<request topic>/<slave id>`.
+    /// Every slave listens on its own as well as the shared request topic,
+    /// so a master can send a packet to exactly one slave instead of having
+    /// the broker fan it out to all of them.
+    pub fn direct_request(&self, slave_id: &str) -> String {
+        format!("{}/{}", self.request, slave_id)
+    }
+
+    /// Whether `topic` is the response topic or one of its per-key subtopics.
+    pub fn is_response(&self, topic: &str) -> bool {
+        topic.strip_prefix(self.response.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    fn check(&self) -> Result<(), String> {
+        for (flag, topic) in [("--request-topic", &self.request), ("--response-topic", &self.response)] {
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                return Err(format!("{} must be a non-empty topic without wildcards, got {:?}", flag, topic));
+            }
+        }
+        if self.request == self.response {
+            return Err(format!("request and response topics are both {:?}", self.request));
+        }
+        Ok(())
+    }
+}
+
+impl BrokerArgs {
+    /// Takes the broker flags out of `args`, returning them along with the
+    /// arguments left for the binary to interpret.
+    pub fn extract(args: impl IntoIterator<Item = String>) -> Result<(Self, Vec<String>), String> {
+        let mut broker = BrokerArgs {
+            host: "localhost".to_string(),
+            port: None,
+            client_id: None,
+            client_id_prefix: None,
+            tls: false,
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            credentials: None,
+            topics: Topics::default(),
+            qos: QoS::AtLeastOnce,
+            keep_alive: Duration::from_secs(5),
+            clean_session: true,
+            log_format: LogFormat::default(),
+        };
+        let mut username = None;
+        let mut password = None;
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--broker-host" => broker.host = value()?,
+                "--broker-port" => {
+                    let raw = value()?;
+                    let port = raw.parse::<u16>().ok().filter(|port| *port != 0).ok_or_else(|| {
+                        format!("--broker-port must be a port number between 1 and 65535, got {:?}", raw)
+                    })?;
+                    broker.port = Some(port);
+                }
+                "--client-id" => {
+                    let client_id = value()?;
+                    if client_id.is_empty() {
+                        return Err("--client-id must not be empty".to_string());
+                    }
+                    broker.client_id = Some(client_id);
+                }
+                "--client-id-prefix" => broker.client_id_prefix = Some(value()?),
+                "--tls" => broker.tls = true,
+                "--ca-cert" => broker.ca_cert = Some(value()?),
+                "--client-cert" => broker.client_cert = Some(value()?),
+                "--client-key" => broker.client_key = Some(value()?),
+                "--request-topic" => broker.topics.request = value()?,
+                "--response-topic" => broker.topics.response = value()?,
+                "--username" => username = Some(value()?),
+                "--password" => password = Some(value()?),
+                "--qos" => broker.qos = parse_qos(&value()?)?,
+                "--keep-alive-secs" => {
+                    let raw = value()?;
+                    let secs = raw.parse::<u16>().map_err(|_| {
+                        format!("--keep-alive-secs must be a number of seconds between 0 and 65535, got {:?}", raw)
+                    })?;
+                    broker.keep_alive = Duration::from_secs(secs.into());
+                }
+                "--clean-session" => {
+                    let raw = value()?;
+                    broker.clean_session = raw.parse().map_err(|_| format!("--clean-session must be true or false, got {:?}", raw))?;
+                }
+                "--log-format" => broker.log_format = value()?.parse()?,
+                _ => rest.push(arg),
+            }
+        }
+        broker.topics.check()?;
+        let username = username.or_else(|| std::env::var("MQTT_USERNAME").ok());
+        let password = password.or_else(|| std::env::var("MQTT_PASSWORD").ok());
+        broker.credentials = match (username, password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            (None, None) => None,
+            (Some(_), None) => return Err("a username was given without a password (--password or MQTT_PASSWORD)".to_string()),
+            (None, Some(_)) => return Err("a password was given without a username (--username or MQTT_USERNAME)".to_string()),
+        };
+        Ok((broker, rest))
+    }
+
+    /// The `--client-id` if one was given, otherwise a client id made of the
+    /// configured prefix, or `default_prefix`, and a fresh uuid.
+    pub fn client_id(&self, default_prefix: &str) -> String {
+        if let Some(client_id) = &self.client_id {
+            return client_id.clone();
+        }
+        let prefix = self.client_id_prefix.as_deref().unwrap_or(default_prefix);
+        format!("{}{}", prefix, uuid::Uuid::new_v4())
+    }
+
+    /// The transport these flags and the environment ask for, with the
+    /// certificates loaded and checked.
+    pub fn transport(&self) -> Result<Transport, String> {
+        build_transport(self)
+    }
+
+    pub fn port_for(&self, transport: &Transport) -> u16 {
+        self.port.unwrap_or_else(|| default_port(transport))
+    }
+}
+
+/// Builds the MQTT client every binary uses: `client_id` at the configured
+/// broker, with its keep-alive and clean-session settings, and a request
+/// queue of `capacity`.
+/// `max_packet_size` raises rumqttc's 10 KiB packet limit, in both
+/// directions; a bigger packet from the broker drops the connection.
+pub fn connect(
+    client_id: &str,
+    broker: &BrokerArgs,
+    transport: Transport,
+    capacity: usize,
+    last_will: Option<LastWill>,
+    max_packet_size: Option<usize>,
+) -> (Client, Connection) {
+    Client::new(mqtt_options(client_id, broker, transport, last_will, max_packet_size), capacity)
+}
+
+/// The options [`connect`] uses, for callers that build their own client
+/// (e.g. an `AsyncClient`).
+pub fn mqtt_options(
+    client_id: &str,
+    broker: &BrokerArgs,
+    transport: Transport,
+    last_will: Option<LastWill>,
+    max_packet_size: Option<usize>,
+) -> MqttOptions {
+    let mut mqtt_options = MqttOptions::new(client_id, broker.host.as_str(), broker.port_for(&transport));
+    mqtt_options
+        .set_keep_alive(broker.keep_alive)
+        .set_clean_session(broker.clean_session)
+        .set_transport(transport);
+    if let Some(last_will) = last_will {
+        mqtt_options.set_last_will(last_will);
+    }
+    if let Some(size) = max_packet_size {
+        mqtt_options.set_max_packet_size(size, size);
+    }
+    if let Some((username, password)) = &broker.credentials {
+        mqtt_options.set_credentials(username.as_str(), password.as_str());
+    }
+    mqtt_options
+}
+
+/// Exponential delay between reconnect attempts: `base`, doubling on each
+/// consecutive connection error up to `cap`, and back to `base` once the
+/// broker accepts a connection again.
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    /// `RECONNECT_BACKOFF_BASE_MS` (default 100) and
+    /// `RECONNECT_BACKOFF_CAP_MS` (default 30000).
+    pub fn from_env() -> Self {
+        let base = env_var::<u64>("RECONNECT_BACKOFF_BASE_MS").unwrap_or(100).max(1);
+        let cap = env_var::<u64>("RECONNECT_BACKOFF_CAP_MS").unwrap_or(30_000).max(base);
+        Self { base: Duration::from_millis(base), cap: Duration::from_millis(cap), attempt: 0 }
+    }
+
+    /// The delay before the next attempt, counting this one.
+    pub fn next_delay(&mut self) -> Duration {
+        let factor = 2u32.saturating_pow(self.attempt);
+        self.attempt = self.attempt.saturating_add(1);
+        self.base.saturating_mul(factor).min(self.cap)
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))
+}
+
+/// Loads the client certificate and key, making sure the key actually
+/// belongs to the certificate so a mismatch fails here rather than as an
+/// opaque handshake error.
+fn load_client_auth(cert_path: &str, key_path: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let cert_pem = read_pem(cert_path)?;
+    let key_pem = read_pem(key_path)?;
+
+    let cert = rustls_pemfile::certs(&mut cert_pem.as_slice())
+        .next()
+        .ok_or_else(|| format!("{} contains no certificates", cert_path))?
+        .map_err(|e| format!("failed to parse {}: {}", cert_path, e))?;
+    let key = rustls_pemfile::private_key(&mut key_pem.as_slice())
+        .map_err(|e| format!("failed to parse {}: {}", key_path, e))?
+        .ok_or_else(|| format!("{} contains no private key", key_path))?;
+
+    check_key_matches_cert(&cert, &key)
+        .map_err(|e| format!("{} and {}: {}", cert_path, key_path, e))?;
+
+    Ok((cert_pem, key_pem))
+}
+
+/// Signs a probe message with the key and verifies it against the
+/// certificate's public key.
+fn check_key_matches_cert(cert: &CertificateDer<'_>, key: &PrivateKeyDer<'_>) -> Result<(), String> {
+    const PROBE: &[u8] = b"masterandslave client certificate check";
+
+    let algorithms = rustls::crypto::ring::default_provider().signature_verification_algorithms;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(key)
+        .map_err(|e| format!("unsupported client key: {}", e))?;
+    let schemes: Vec<_> = algorithms.mapping.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = signing_key
+        .choose_scheme(&schemes)
+        .ok_or_else(|| "no signature scheme available for client key".to_string())?;
+    let signature = signer
+        .sign(PROBE)
+        .map_err(|e| format!("failed to sign with client key: {}", e))?;
+
+    let end_entity = webpki::EndEntityCert::try_from(cert)
+        .map_err(|e| format!("invalid client certificate: {}", e))?;
+    let matches = algorithms
+        .all
+        .iter()
+        .any(|alg| end_entity.verify_signature(*alg, PROBE, &signature).is_ok());
+
+    if matches {
+        Ok(())
+    } else {
+        Err("client key does not match client certificate".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One payload of every variant, with values that exercise the encoders:
+    /// an integer and a fractional number, a non-default coordinate system,
+    /// raw bytes and nested JSON.
+    fn every_variant() -> Vec<DataPayload> {
+        vec![
+            DataPayload::Text("hello".to_string()),
+            DataPayload::Number(42.into()),
+            DataPayload::Coordinates { x: 1.0, y: 0.5, z: -2.25, system: CoordinateSystem::Spherical },
+            DataPayload::SensorData { sensor_id: "S1".to_string(), temperature: 21.5, humidity: 40.0, pressure: 1013.25 },
+            DataPayload::ImageData { width: 2, height: 1, format: "GRAY".to_string(), data: vec![0, 255] },
+            DataPayload::LogEntry { level: "WARN".to_string(), message: "disk".to_string(), timestamp: "2024-05-01T12:00:00Z".to_string() },
+            DataPayload::TimeSeries {
+                series_id: "T".to_string(),
+                points: vec![TimeSeriesPoint { timestamp: "2024-05-01T12:00:00Z".to_string(), value: 0.1 }],
+            },
+            DataPayload::Reference {
+                uri: "s3://bucket/key".to_string(),
+                size: 10,
+                content_type: "application/octet-stream".to_string(),
+                checksum: "sha256:00".to_string(),
+            },
+            DataPayload::Json(serde_json::json!({ "nested": [1, "two", null, { "three": 3.5 }] })),
+            DataPayload::Batch(vec![DataPayload::Text("a".to_string()), DataPayload::Number(serde_json::Number::from_f64(2.5).unwrap())]),
+        ]
+    }
+
+    #[test]
+    fn routing_key_follows_the_per_variant_rules() {
+        let sensor = DataPayload::SensorData { sensor_id: "S1".to_string(), temperature: 0.0, humidity: 0.0, pressure: 0.0 };
+        assert_eq!(routing_key(&sensor, "p"), "S1");
+        let log = DataPayload::LogEntry { level: "WARN".to_string(), message: "m".to_string(), timestamp: "t".to_string() };
+        assert_eq!(routing_key(&log, "p"), "WARN");
+        let series = DataPayload::TimeSeries { series_id: "T1".to_string(), points: Vec::new() };
+        assert_eq!(routing_key(&series, "p"), "T1");
+        let reference = DataPayload::Reference {
+            uri: "file:///x".to_string(),
+            size: 0,
+            content_type: "text/plain".to_string(),
+            checksum: String::new(),
+        };
+        assert_eq!(routing_key(&reference, "p"), "file:///x");
+
+        let text = routing_key(&DataPayload::Text("hello".to_string()), "p");
+        assert_eq!(text, format!("text-{:016x}", fnv1a(b"hello")));
+        assert_eq!(text, routing_key(&DataPayload::Text("hello".to_string()), "other"));
+    }
+
+    #[test]
+    fn routing_key_falls_back_to_the_packet_id() {
+        let keyless = [
+            DataPayload::Number(1.into()),
+            DataPayload::Coordinates { x: 0.0, y: 0.0, z: 0.0, system: CoordinateSystem::Cartesian },
+            DataPayload::ImageData { width: 0, height: 0, format: "RGB".to_string(), data: Vec::new() },
+            DataPayload::Json(serde_json::Value::Null),
+            DataPayload::Batch(Vec::new()),
+        ];
+        for payload in keyless {
+            assert_eq!(routing_key(&payload, "packet-7"), "packet-7", "{}", payload.type_name());
+        }
+    }
+
+    #[test]
+    fn format_float_never_uses_scientific_notation() {
+        assert_eq!(format_float(1e20, 6), "100000000000000000000");
+        assert_eq!(format_float(1.234e-10, 3), "0.000000000123");
+        assert_eq!(format_float(-2.5e-7, 2), "-0.00000025");
+        for value in [1e300, -1e-300, 6.02214076e23, f64::MIN_POSITIVE] {
+            let formatted = format_float(value, 6);
+            assert!(!formatted.contains(['e', 'E']), "{} formatted as {}", value, formatted);
+        }
+    }
+
+    #[test]
+    fn format_float_respects_significant_digits() {
+        assert_eq!(format_float(9.87654, 3), "9.88");
+        assert_eq!(format_float(1.23456789, 6), "1.23457");
+        assert_eq!(format_float(1234.5678, 2), "1235");
+        assert_eq!(format_float(0.0001234, 2), "0.00012");
+        assert_eq!(format_float(42.0, 6), "42");
+        assert_eq!(format_float(0.0, 6), "0");
+        assert_eq!(format_float(7.25, 0), "7");
+    }
+
+    #[test]
+    fn from_outcome_builds_every_status_kind() {
+        let at = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        let cases = [
+            (ResponseStatus::Ok("done".to_string()), serde_json::json!("done")),
+            (ResponseStatus::ParseError("bad".to_string()), serde_json::json!({ "ParseError": "bad" })),
+            (ResponseStatus::ConversionError, serde_json::json!({ "ConversionError": null })),
+            (ResponseStatus::ValidationError("range".to_string()), serde_json::json!({ "ValidationError": "range" })),
+            (ResponseStatus::Rejected("busy".to_string()), serde_json::json!({ "Rejected": "busy" })),
+            (ResponseStatus::ChecksumError("crc".to_string()), serde_json::json!({ "ChecksumError": "crc" })),
+        ];
+        for (status, wire) in cases {
+            let response = DataResponse::from_outcome_at("p1".to_string(), status.clone(), 7, at);
+            assert_eq!(response.packet_id, "p1");
+            assert_eq!(response.received_at, "2024-05-01T12:00:00+00:00");
+            assert_eq!(response.processing_time_ms, 7);
+            assert_eq!(response.status, status);
+            assert!(response.result.is_none() && response.routing_key.is_none() && response.receive_index.is_none());
+
+            let encoded = serde_json::to_value(&response).unwrap();
+            assert_eq!(encoded["status"], wire);
+            let decoded: DataResponse = serde_json::from_value(encoded).unwrap();
+            assert_eq!(decoded.status, status);
+        }
+    }
+
+    #[test]
+    fn canonical_bytes_ignore_key_order() {
+        let a: serde_json::Value = serde_json::from_str(
+            r#"{"SensorData":{"sensor_id":"S1","temperature":20.5,"humidity":40,"pressure":1000,"extra":{"b":[1,{"y":2,"x":1}],"a":null}}}"#,
+        )
+        .unwrap();
+        let b: serde_json::Value = serde_json::from_str(
+            r#"{"SensorData":{"extra":{"a":null,"b":[1,{"x":1,"y":2}]},"pressure":1000,"humidity":40,"temperature":20.5,"sensor_id":"S1"}}"#,
+        )
+        .unwrap();
+        assert_eq!(canonical_value_bytes(&a), canonical_value_bytes(&b));
+        assert_eq!(payload_crc32(&a), payload_crc32(&b));
+        assert_eq!(
+            String::from_utf8(canonical_value_bytes(&b)).unwrap(),
+            r#"{"SensorData":{"extra":{"a":null,"b":[1,{"x":1,"y":2}]},"humidity":40,"pressure":1000,"sensor_id":"S1","temperature":20.5}}"#
+        );
+        // Array order is part of the value and must still matter.
+        let reordered: serde_json::Value = serde_json::from_str(r#"[2,1]"#).unwrap();
+        assert_ne!(canonical_value_bytes(&reordered), canonical_value_bytes(&serde_json::json!([1, 2])));
+    }
+
+    #[test]
+    fn response_schemas_shape_the_same_response() {
+        let at = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        let ok = DataResponse::from_outcome_at("p1".to_string(), ResponseStatus::Ok("done".to_string()), 3, at);
+        let invalid = DataResponse::from_outcome_at("p2".to_string(), ResponseStatus::ValidationError("range".to_string()), 3, at);
+
+        assert_eq!(ResponseSchema::Legacy.serialize(&ok).unwrap(), r#"{"id":"p1","result":"done"}"#);
+        assert_eq!(ResponseSchema::Legacy.serialize(&invalid).unwrap(), r#"{"id":"p2","result":"INVALID: range"}"#);
+
+        let v2 = ResponseSchema::V2.serialize(&invalid).unwrap();
+        let decoded: DataResponse = serde_json::from_str(&v2).unwrap();
+        assert_eq!(decoded.packet_id, "p2");
+        assert_eq!(decoded.status, invalid.status);
+        // A legacy consumer can't mistake a v2 response for its own shape.
+        assert!(serde_json::from_str::<LegacyResponse>(&v2).is_err());
+
+        assert_eq!("legacy".parse::<ResponseSchema>(), Ok(ResponseSchema::Legacy));
+        assert_eq!("v2".parse::<ResponseSchema>(), Ok(ResponseSchema::V2));
+        assert!("v3".parse::<ResponseSchema>().is_err());
+    }
+
+    #[test]
+    fn every_variant_round_trips_through_every_wire_format() {
+        for format in [WireFormat::Json, WireFormat::Cbor, WireFormat::MsgPack] {
+            for payload in every_variant() {
+                let packet = DataPacket::builder(payload).metadata("seq", "1").build();
+                let encoded = format.encode(&packet).unwrap();
+                assert_eq!(WireFormat::detect(&encoded), format);
+                let decoded: DataPacket = serde_json::from_slice(&WireFormat::to_json(&encoded).unwrap()).unwrap();
+                assert_eq!(
+                    serde_json::to_value(&decoded).unwrap(),
+                    serde_json::to_value(&packet).unwrap(),
+                    "{:?} lost something through {:?}",
+                    packet.payload.type_name(),
+                    format
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_megabyte_image_survives_compression() {
+        let data: Vec<u8> = (0..1024 * 1024).map(|i: u32| (i % 251) as u8).collect();
+        let image = DataPayload::ImageData { width: 1024, height: 1024, format: "GRAY".to_string(), data };
+        let packet = DataPacket::builder(image).build();
+        let encoded = WireFormat::MsgPack.encode(&packet).unwrap();
+        let compressed = compress(&encoded).unwrap();
+        assert!(compressed.len() < encoded.len() / 10, "{} of {} bytes", compressed.len(), encoded.len());
+
+        let limit = encoded.len() as u64;
+        let restored = decompress(&compressed, limit).unwrap();
+        assert_eq!(restored.as_ref(), encoded.as_slice());
+        let decoded: DataPacket = serde_json::from_slice(&WireFormat::to_json(&restored).unwrap()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&packet).unwrap());
+
+        // A limit one byte under the real size is refused rather than inflated.
+        assert!(decompress(&compressed, limit - 1).unwrap_err().contains("exceeds"));
+        // Uncompressed payloads pass through untouched.
+        assert!(matches!(decompress(&encoded, 0).unwrap(), std::borrow::Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn every_payload_type_but_batch_has_a_count() {
+        let names: Vec<&str> = MetricsSnapshot::default().type_counts().into_iter().map(|(name, _)| name).collect();
+        let expected: Vec<&str> = PAYLOAD_TYPE_NAMES.into_iter().filter(|name| *name != "batch").collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn log_levels_rank_by_severity_and_unknown_ones_rank_highest() {
+        assert_eq!(LogLevel::of("trace"), LogLevel::Trace);
+        assert_eq!(LogLevel::of(" Info "), LogLevel::Info);
+        assert_eq!(LogLevel::of("WARNING"), LogLevel::Warn);
+        assert_eq!(LogLevel::of("FATAL"), LogLevel::Error);
+        assert_eq!(LogLevel::of(""), LogLevel::Error);
+        assert!(LogLevel::Trace < LogLevel::Debug && LogLevel::Debug < LogLevel::Info);
+        assert!(LogLevel::Info < LogLevel::Warn && LogLevel::Warn < LogLevel::Error);
+        assert!("FATAL".parse::<LogLevel>().is_err());
+    }
+
+    #[test]
+    fn builder_names_the_data_type_after_the_payload() {
+        let data_types: Vec<String> =
+            every_variant().into_iter().map(|payload| DataPacket::builder(payload).build().data_type).collect();
+        assert_eq!(data_types, PAYLOAD_TYPE_NAMES);
+    }
+
+    #[test]
+    fn client_id_is_fixed_only_when_given() {
+        let broker = |args: &[&str]| BrokerArgs::extract(args.iter().map(|arg| arg.to_string())).unwrap().0;
+        let fixed = broker(&["--client-id", "slave-7", "--clean-session", "false"]);
+        assert_eq!(fixed.client_id("slave-node-"), "slave-7");
+        assert_eq!(fixed.client_id("slave-node-"), "slave-7");
+
+        let prefixed = broker(&["--client-id-prefix", "edge-"]);
+        let first = prefixed.client_id("slave-node-");
+        assert!(first.starts_with("edge-"), "{}", first);
+        assert_ne!(first, prefixed.client_id("slave-node-"));
+        assert!(broker(&[]).client_id("slave-node-").starts_with("slave-node-"));
+
+        assert!(BrokerArgs::extract(["--client-id".to_string(), String::new()]).is_err());
+    }
 }
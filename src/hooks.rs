@@ -0,0 +1,77 @@
+use crate::common::{DataPayload, ResponseStatus};
+use std::collections::HashMap;
+
+type Hook = Box<dyn Fn(&DataPayload) -> ResponseStatus + Send + Sync>;
+
+/// Per-variant overrides for payload processing. A registered hook replaces
+/// the built-in handling for its variant and returns the response status;
+/// variants without a hook keep the default behaviour.
+#[derive(Default)]
+pub struct ProcessingHooks {
+    hooks: HashMap<&'static str, Hook>,
+}
+
+impl ProcessingHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `hook` for the variant whose `type_name()` is `type_name`.
+    pub fn on(mut self, type_name: &'static str, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.hooks.insert(type_name, Box::new(hook));
+        self
+    }
+
+    pub fn on_text(self, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.on("text", hook)
+    }
+
+    pub fn on_number(self, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.on("number", hook)
+    }
+
+    pub fn on_coordinates(self, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.on("coordinates", hook)
+    }
+
+    pub fn on_sensor_data(self, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.on("sensor_data", hook)
+    }
+
+    pub fn on_image_data(self, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.on("image_data", hook)
+    }
+
+    pub fn on_log_entry(self, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.on("log_entry", hook)
+    }
+
+    pub fn on_time_series(self, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.on("time_series", hook)
+    }
+
+    pub fn on_reference(self, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.on("reference", hook)
+    }
+
+    pub fn on_json(self, hook: impl Fn(&DataPayload) -> ResponseStatus + Send + Sync + 'static) -> Self {
+        self.on("json", hook)
+    }
+
+    /// Runs the hook registered for this payload's variant, if any.
+    pub fn process(&self, payload: &DataPayload) -> Option<ResponseStatus> {
+        self.hooks.get(payload.type_name()).map(|hook| hook(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_registered_variants_are_claimed() {
+        let hooks = ProcessingHooks::new().on_text(|_| ResponseStatus::Ok("custom".to_string()));
+        assert_eq!(hooks.process(&DataPayload::Text("hi".to_string())), Some(ResponseStatus::Ok("custom".to_string())));
+        assert_eq!(hooks.process(&DataPayload::Number(1.into())), None);
+    }
+}
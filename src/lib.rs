@@ -1 +1,5 @@
-pub mod common;
\ No newline at end of file
+pub mod common;
+pub mod hooks;
+pub mod validation;
+pub mod processing;
+pub mod slave;
@@ -1,5 +1,3 @@
-mod common;
-
 fn main() {
     println!("Please run either 'cargo run --bin master' or 'cargo run --bin slave'");
 }
\ No newline at end of file
@@ -0,0 +1,395 @@
+//! Payload conversion and processing, independent of MQTT.
+
+use crate::common::{env_var, format_float, type_list_from_env, CoordinateSystem, DataPayload, ResponseStatus, TimeSeriesPoint};
+use crate::validation::{check_image_buffer, SensorRange};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use tracing::info;
+
+/// Converts a packet's raw `payload` object into a [`DataPayload`], or
+/// `None` when it matches no known variant.
+pub fn convert_payload(value: &Value) -> Option<DataPayload> {
+    // First try simple format
+    if let Value::Object(map) = value {
+        if let Some(text) = map.get("Text") {
+            if let Some(text_str) = text.as_str() {
+                return Some(DataPayload::Text(text_str.to_string()));
+            }
+        }
+
+        if let Some(Value::Number(n)) = map.get("Number") {
+            return Some(DataPayload::Number(n.clone()));
+        }
+        
+        // Try complex formats
+        if let Some(img_data) = map.get("ImageData") {
+            if let Ok(img) = serde_json::from_value::<ImageData>(img_data.clone()) {
+                return Some(DataPayload::ImageData {
+                    width: img.width,
+                    height: img.height,
+                    format: img.format,
+                    data: img.data,
+                });
+            }
+        }
+        
+        if let Some(sensor_data) = map.get("SensorData") {
+            if let Ok(sensor) = serde_json::from_value::<SensorData>(sensor_data.clone()) {
+                return Some(DataPayload::SensorData {
+                    sensor_id: sensor.sensor_id,
+                    temperature: sensor.temperature,
+                    humidity: sensor.humidity,
+                    pressure: sensor.pressure,
+                });
+            }
+        }
+        
+        if let Some(coord_data) = map.get("Coordinates") {
+            if let Ok(coord) = serde_json::from_value::<Coordinates>(coord_data.clone()) {
+                return Some(DataPayload::Coordinates {
+                    x: coord.x,
+                    y: coord.y,
+                    z: coord.z,
+                    system: coord.system,
+                });
+            }
+        }
+        
+        if let Some(series_data) = map.get("TimeSeries") {
+            if let Ok(series) = serde_json::from_value::<TimeSeries>(series_data.clone()) {
+                return Some(DataPayload::TimeSeries {
+                    series_id: series.series_id,
+                    points: series.points,
+                });
+            }
+        }
+
+        if let Some(Value::Array(items)) = map.get("Batch") {
+            return items.iter().map(convert_payload).collect::<Option<Vec<_>>>().map(DataPayload::Batch);
+        }
+
+        if let Some(json) = map.get("Json") {
+            return Some(DataPayload::Json(json.clone()));
+        }
+
+        if let Some(reference_data) = map.get("Reference") {
+            if let Ok(reference) = serde_json::from_value::<Reference>(reference_data.clone()) {
+                return Some(DataPayload::Reference {
+                    uri: reference.uri,
+                    size: reference.size,
+                    content_type: reference.content_type,
+                    checksum: reference.checksum,
+                });
+            }
+        }
+
+        if let Some(log_data) = map.get("LogEntry") {
+            if let Ok(log) = serde_json::from_value::<LogEntry>(log_data.clone()) {
+                return Some(DataPayload::LogEntry {
+                    level: log.level,
+                    message: log.message,
+                    timestamp: log.timestamp.to_rfc3339(),
+                });
+            }
+        }
+    }
+    None
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageData {
+    width: u32,
+    height: u32,
+    format: String,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SensorData {
+    sensor_id: String,
+    temperature: f64,
+    humidity: f64,
+    pressure: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Coordinates {
+    x: f64,
+    y: f64,
+    z: f64,
+    #[serde(default)]
+    system: CoordinateSystem,
+}
+
+#[derive(Debug, Deserialize)]
+struct TimeSeries {
+    series_id: String,
+    points: Vec<TimeSeriesPoint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Reference {
+    uri: String,
+    size: u64,
+    content_type: String,
+    checksum: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LogEntry {
+    level: String,
+    message: String,
+    timestamp: DateTime<Utc>,
+}
+
+/// Runs the built-in handling for a payload and returns the response
+/// status. `verbose` prints what is being processed; `digits` is the number
+/// of significant digits floats are reported with.
+pub fn process_data(payload: &DataPayload, verbose: bool, digits: usize) -> ResponseStatus {
+    let num = |value: f64| format_float(value, digits);
+    let log = |line: String| {
+        if verbose {
+            info!("{}", line);
+        }
+    };
+    let processed = match payload {
+        DataPayload::Text(text) => {
+            log(format!("Processing text data: {}", text));
+            format!("Text processed: {} chars", text.len())
+        }
+        DataPayload::Number(value) => {
+            log(format!("Processing numeric data: {}", value));
+            match value.as_f64() {
+                Some(float) if value.is_f64() => format!("Number processed: {}", num(float)),
+                _ => format!("Number processed: {}", value),
+            }
+        }
+        DataPayload::Coordinates { x, y, z, system } => {
+            log(format!("Processing {:?} coordinates: ({}, {}, {})", system, x, y, z));
+            let (x, y, z) = system.to_cartesian(*x, *y, *z);
+            let distance = num((x * x + y * y + z * z).sqrt());
+            if system.is_cartesian() {
+                format!("Coordinates processed: distance from origin = {}", distance)
+            } else {
+                format!("Coordinates processed: distance from origin = {}, cartesian = ({}, {}, {})",
+                    distance, num(x), num(y), num(z))
+            }
+        }
+        DataPayload::SensorData { sensor_id, temperature, humidity, pressure } => {
+            log(format!("Processing sensor data from {}", sensor_id));
+            if let Some((field, value, _)) = SensorRange::default().out_of_range(*temperature, *humidity, *pressure) {
+                return ResponseStatus::ValidationError(format!("{} out of range ({:?})", field, value));
+            }
+            format!("Sensor data processed: temp={}°C, humidity={}%, pressure={}hPa",
+                num(*temperature), num(*humidity), num(*pressure))
+        }
+        DataPayload::ImageData { width, height, format, data } => {
+            log(format!("Processing {}x{} image in {} format", width, height, format));
+            // Catches truncated or corrupt transfers; an unknown format
+            // gets its own message.
+            if let Err(e) = check_image_buffer(*width, *height, format, data) {
+                return ResponseStatus::ValidationError(e);
+            }
+            format!("Image processed: {} bytes", data.len())
+        }
+        DataPayload::LogEntry { level, message, timestamp } => {
+            log(format!("Processing log entry: [{}] {}", level, message));
+            format!("Log entry processed at {}", timestamp)
+        }
+        DataPayload::TimeSeries { series_id, points } => {
+            log(format!("Processing time series {} with {} points", series_id, points.len()));
+            if points.is_empty() {
+                "Time series processed: 0 points".to_string()
+            } else {
+                let mean = points.iter().map(|p| p.value).sum::<f64>() / points.len() as f64;
+                format!("Time series processed: {} points, mean = {}", points.len(), num(mean))
+            }
+        }
+        DataPayload::Reference { uri, size, content_type, .. } => {
+            log(format!("Recording reference to {}", uri));
+            format!("Reference recorded: {} ({} bytes, {})", uri, size, content_type)
+        }
+        DataPayload::Json(value) => {
+            log(format!("Processing JSON: {}", value));
+            match value {
+                Value::Object(map) => format!("JSON processed: {} keys", map.len()),
+                Value::Array(items) => format!("JSON processed: {} items", items.len()),
+                _ => "JSON processed: scalar".to_string(),
+            }
+        }
+        DataPayload::Batch(items) => {
+            log(format!("Processing batch of {} items", items.len()));
+            let invalid = items
+                .iter()
+                .map(|item| process_data(item, verbose, digits))
+                .filter(|status| matches!(status, ResponseStatus::ValidationError(_)))
+                .count();
+            if invalid > 0 {
+                format!("Batch processed: {} items, {} invalid", items.len(), invalid)
+            } else {
+                format!("Batch processed: {} items", items.len())
+            }
+        }
+    };
+    ResponseStatus::Ok(processed)
+}
+
+/// What the slave does with a payload once it has been converted and has
+/// passed the validators. [`DefaultProcessor`] is the built-in handling;
+/// implement this to do something else with payloads, e.g. persist sensor
+/// readings or forward images, and hand it to the slave instead.
+pub trait PayloadProcessor: Send + Sync {
+    fn process(&self, payload: &DataPayload) -> ResponseStatus;
+}
+
+/// [`process_data`] as a [`PayloadProcessor`].
+pub struct DefaultProcessor {
+    /// Types processed without logging what is being processed.
+    pub quiet_types: HashSet<String>,
+    /// Significant digits floats are reported with.
+    pub digits: usize,
+}
+
+impl DefaultProcessor {
+    /// Reads `QUIET_TYPES` and `FLOAT_SIG_DIGITS`.
+    pub fn from_env() -> Self {
+        Self {
+            quiet_types: type_list_from_env("QUIET_TYPES"),
+            digits: env_var("FLOAT_SIG_DIGITS").unwrap_or(6),
+        }
+    }
+}
+
+impl Default for DefaultProcessor {
+    fn default() -> Self {
+        Self { quiet_types: HashSet::new(), digits: 6 }
+    }
+}
+
+impl PayloadProcessor for DefaultProcessor {
+    fn process(&self, payload: &DataPayload) -> ResponseStatus {
+        let verbose = !self.quiet_types.contains(payload.type_name());
+        process_data(payload, verbose, self.digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(temperature: f64, humidity: f64, pressure: f64) -> ResponseStatus {
+        let payload = DataPayload::SensorData { sensor_id: "S1".to_string(), temperature, humidity, pressure };
+        process_data(&payload, false, 6)
+    }
+
+    #[test]
+    fn sensor_readings_on_the_range_boundaries_are_accepted() {
+        for (temperature, humidity, pressure) in [(-90.0, 0.0, 300.0), (60.0, 100.0, 1100.0)] {
+            assert!(matches!(sensor(temperature, humidity, pressure), ResponseStatus::Ok(_)));
+        }
+    }
+
+    #[test]
+    fn sensor_readings_just_past_a_boundary_are_invalid() {
+        let cases = [
+            ((-90.1, 50.0, 1000.0), "temperature out of range (-90.1)"),
+            ((60.1, 50.0, 1000.0), "temperature out of range (60.1)"),
+            ((20.0, -0.1, 1000.0), "humidity out of range (-0.1)"),
+            ((20.0, 100.1, 1000.0), "humidity out of range (100.1)"),
+            ((20.0, 50.0, 299.9), "pressure out of range (299.9)"),
+            ((20.0, 50.0, 1100.1), "pressure out of range (1100.1)"),
+        ];
+        for ((temperature, humidity, pressure), message) in cases {
+            assert_eq!(sensor(temperature, humidity, pressure), ResponseStatus::ValidationError(message.to_string()));
+        }
+        assert_eq!(sensor(20.0, 500.0, 1000.0).to_string(), "INVALID: humidity out of range (500.0)");
+    }
+
+    fn convert(json: &str) -> Option<Value> {
+        convert_payload(&serde_json::from_str(json).unwrap()).map(|payload| serde_json::to_value(payload).unwrap())
+    }
+
+    #[test]
+    fn convert_payload_accepts_every_variant() {
+        let cases = [
+            r#"{"Text":"hello"}"#,
+            r#"{"Number":42}"#,
+            r#"{"Coordinates":{"x":1.0,"y":2.0,"z":3.0}}"#,
+            r#"{"Coordinates":{"x":1.0,"y":0.0,"z":0.0,"system":"Spherical"}}"#,
+            r#"{"SensorData":{"sensor_id":"S1","temperature":20.5,"humidity":40.0,"pressure":1000.0}}"#,
+            r#"{"ImageData":{"width":1,"height":1,"format":"GRAY","data":[7]}}"#,
+            r#"{"LogEntry":{"level":"INFO","message":"up","timestamp":"2024-05-01T12:00:00+00:00"}}"#,
+            r#"{"TimeSeries":{"series_id":"T","points":[{"timestamp":"t0","value":1.5}]}}"#,
+            r#"{"Reference":{"uri":"s3://b/k","size":3,"content_type":"text/plain","checksum":"sha256:00"}}"#,
+            r#"{"Json":{"any":["shape",1,null]}}"#,
+            r#"{"Batch":[{"Text":"a"},{"Number":1}]}"#,
+        ];
+        for json in cases {
+            assert_eq!(convert(json), Some(serde_json::from_str(json).unwrap()), "{}", json);
+        }
+    }
+
+    #[test]
+    fn log_entry_timestamps_are_normalised() {
+        let converted = convert(r#"{"LogEntry":{"level":"INFO","message":"up","timestamp":"2024-05-01T14:00:00+02:00"}}"#);
+        assert_eq!(converted.unwrap()["LogEntry"]["timestamp"], "2024-05-01T12:00:00+00:00");
+    }
+
+    #[test]
+    fn convert_payload_rejects_malformed_and_partial_objects() {
+        let cases = [
+            r#""Text""#,
+            r#"[{"Text":"a"}]"#,
+            r#"{}"#,
+            r#"{"Unknown":1}"#,
+            r#"{"Text":1}"#,
+            r#"{"Coordinates":{"x":1.0,"y":2.0}}"#,
+            r#"{"Coordinates":{"x":1.0,"y":2.0,"z":3.0,"system":"polar"}}"#,
+            r#"{"SensorData":{"sensor_id":"S1","temperature":20.5,"humidity":40.0}}"#,
+            r#"{"ImageData":{"width":-1,"height":1,"format":"GRAY","data":[7]}}"#,
+            r#"{"ImageData":{"width":1,"height":1,"format":"GRAY","data":[256]}}"#,
+            r#"{"LogEntry":{"level":"INFO","message":"up","timestamp":"yesterday"}}"#,
+            r#"{"TimeSeries":{"series_id":"T","points":[{"timestamp":"t0"}]}}"#,
+            r#"{"Reference":{"uri":"s3://b/k","size":-3,"content_type":"text/plain","checksum":"sha256:00"}}"#,
+            r#"{"Batch":[{"Text":"a"},{"Unknown":1}]}"#,
+        ];
+        for json in cases {
+            assert_eq!(convert(json), None, "{}", json);
+        }
+    }
+
+    #[test]
+    fn number_payloads_must_be_numeric() {
+        assert_eq!(convert(r#"{"Number":3.25}"#), Some(serde_json::json!({ "Number": 3.25 })));
+        assert_eq!(convert(r#"{"Number":"abc"}"#), None);
+        assert_eq!(convert(r#"{"Number":null}"#), None);
+    }
+
+    #[test]
+    fn an_empty_batch_is_processed_as_zero_items() {
+        assert_eq!(process_data(&DataPayload::Batch(Vec::new()), true, 6), ResponseStatus::Ok("Batch processed: 0 items".to_string()));
+        let nested = DataPayload::Batch(vec![DataPayload::Batch(Vec::new())]);
+        assert_eq!(process_data(&nested, true, 6), ResponseStatus::Ok("Batch processed: 1 items".to_string()));
+    }
+
+    #[test]
+    fn integer_numbers_keep_their_integer_form() {
+        let number = |json: &str| process_data(&serde_json::from_str(json).unwrap(), false, 6);
+        assert_eq!(number(r#"{"Number":42}"#), ResponseStatus::Ok("Number processed: 42".to_string()));
+        assert_eq!(number(r#"{"Number":-7}"#), ResponseStatus::Ok("Number processed: -7".to_string()));
+        assert_eq!(number(r#"{"Number":42.5}"#), ResponseStatus::Ok("Number processed: 42.5".to_string()));
+    }
+
+    #[test]
+    fn spherical_coordinates_are_converted_before_measuring() {
+        assert_eq!(CoordinateSystem::Spherical.to_cartesian(1.0, 0.0, 0.0), (0.0, 0.0, 1.0));
+        assert_eq!(CoordinateSystem::Spherical.to_cartesian(2.0, std::f64::consts::FRAC_PI_2, 0.0), (2.0, 0.0, 0.0));
+        let payload = DataPayload::Coordinates { x: 1.0, y: 0.0, z: 0.0, system: CoordinateSystem::Spherical };
+        assert_eq!(
+            process_data(&payload, false, 6),
+            ResponseStatus::Ok("Coordinates processed: distance from origin = 1, cartesian = (0, 0, 1)".to_string())
+        );
+    }
+}
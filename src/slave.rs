@@ -0,0 +1,3668 @@
+//! The slave: takes packets from the request topic, hands them to a pool of
+//! worker threads and publishes a response for each. [`run_slave`] runs it
+//! and [`run_slave_async`] runs the same handling on tokio; `src/bin/slave.rs`
+//! and `src/bin/slave_async.rs` are their command-line front ends, and tests
+//! or embedders can call them directly with their own [`PayloadProcessor`].
+
+mod asynchronous;
+
+pub use asynchronous::{run_slave_async, AsyncSlaveArgs, AsyncSlaveConfig};
+
+use base64::Engine;
+use crate::common::{
+    canonical_value_bytes, payload_crc32, CoordinateSystem, connect, leading_packet_id, LogLevel, parse_qos, Ack, Backoff, decompress, env_var, BrokerArgs, Command, ConnectionMetrics, SelfTestReport, SelfTestResult, fnv1a, format_float, routing_key, type_list_from_env, DataPayload, DataResponse, DeadLetter, MetricsSnapshot, ResponseSchema, ResponseStatus, Heartbeat, SlaveOnline, SlaveStatus, Topics, UNPARSED_PACKET_ID, SkipReason, TenantMetrics, TimeSeriesPoint, PAYLOAD_TYPE_NAMES, PROTOCOL_VERSION,
+    WireFormat,
+};
+use crate::hooks::ProcessingHooks;
+use crate::processing::{convert_payload, PayloadProcessor};
+use crate::validation::{check_image_buffer, ValidatorChain};
+use rumqttc::{AsyncClient, Client, ConnectReturnCode, ConnectionError, QoS, RecvTimeoutError, StateError};
+use std::{time::Duration, sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, error, info, info_span, warn};
+
+
+/// Counters updated from the processing thread and read for reporting.
+///
+/// The counters for a single message (`processed_count`, its per-type count and
+/// the processing time) are updated together under the shared side of
+/// `consistency`, while `snapshot` takes the exclusive side. A snapshot therefore
+/// sees every recorded message either completely or not at all, so the per-type
+/// counts always sum to `processed_count`.
+struct ProcessingMetrics {
+    consistency: RwLock<()>,
+    processed_count: AtomicU64,
+    total_processing_time: AtomicU64,
+    text_count: AtomicU64,
+    number_count: AtomicU64,
+    coordinates_count: AtomicU64,
+    sensor_count: AtomicU64,
+    image_count: AtomicU64,
+    log_count: AtomicU64,
+    time_series_count: AtomicU64,
+    reference_count: AtomicU64,
+    json_count: AtomicU64,
+    exploded_points: AtomicU64,
+    bad_utf8: AtomicU64,
+    rejected_image_dims: AtomicU64,
+    seq_gaps: AtomicU64,
+    seq_reorders: AtomicU64,
+    duplicate_ids: AtomicU64,
+    duplicate_contents: AtomicU64,
+    unsupported_versions: AtomicU64,
+    roundtrip_mismatches: AtomicU64,
+    throttle_sleep_ms: AtomicU64,
+    unknown_fields: AtomicU64,
+    out_of_order_responses: AtomicU64,
+    images_in_progress: AtomicU64,
+    rss_bytes: AtomicU64,
+    busy_workers: AtomicU64,
+    worker_samples: AtomicU64,
+    busy_worker_sum: AtomicU64,
+    saturated_samples: AtomicU64,
+    saturated_ms: AtomicU64,
+    shed_memory: AtomicU64,
+    log_filtered: AtomicU64,
+    rejected_oversize: AtomicU64,
+    protocol_mismatches: AtomicU64,
+    checksum_failures: AtomicU64,
+    data_type_mismatches: AtomicU64,
+    publish_attempts: AtomicU64,
+    publish_confirms: AtomicU64,
+    validation_failures: AtomicU64,
+    disconnect_reasons: Mutex<BTreeMap<String, u64>>,
+    /// Updated from the event-loop thread as connects and drops are observed.
+    connection: Mutex<ConnectionState>,
+    tenants: Mutex<BTreeMap<String, TenantMetrics>>,
+    max_tenants: usize,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    ever_connected: bool,
+    reconnects: u64,
+    last_connected_at: Option<DateTime<Utc>>,
+    connected_since: Option<Instant>,
+    /// Uptime of connections that have since ended.
+    previous_uptime: Duration,
+}
+
+impl ConnectionState {
+    fn on_connect(&mut self) {
+        if self.ever_connected {
+            self.reconnects += 1;
+        }
+        self.ever_connected = true;
+        self.last_connected_at = Some(Utc::now());
+        self.connected_since = Some(Instant::now());
+    }
+
+    fn on_disconnect(&mut self) {
+        if let Some(since) = self.connected_since.take() {
+            self.previous_uptime += since.elapsed();
+        }
+    }
+
+    fn snapshot(&self) -> ConnectionMetrics {
+        let current = self.connected_since.map_or(Duration::ZERO, |since| since.elapsed());
+        ConnectionMetrics {
+            connected: self.connected_since.is_some(),
+            reconnects: self.reconnects,
+            last_connected_at: self.last_connected_at,
+            connected_uptime_secs: (self.previous_uptime + current).as_secs(),
+        }
+    }
+}
+
+impl ProcessingMetrics {
+    fn new() -> Self {
+        Self {
+            consistency: RwLock::new(()),
+            processed_count: AtomicU64::new(0),
+            total_processing_time: AtomicU64::new(0),
+            text_count: AtomicU64::new(0),
+            number_count: AtomicU64::new(0),
+            coordinates_count: AtomicU64::new(0),
+            sensor_count: AtomicU64::new(0),
+            image_count: AtomicU64::new(0),
+            log_count: AtomicU64::new(0),
+            time_series_count: AtomicU64::new(0),
+            reference_count: AtomicU64::new(0),
+            json_count: AtomicU64::new(0),
+            exploded_points: AtomicU64::new(0),
+            bad_utf8: AtomicU64::new(0),
+            rejected_image_dims: AtomicU64::new(0),
+            seq_gaps: AtomicU64::new(0),
+            seq_reorders: AtomicU64::new(0),
+            duplicate_ids: AtomicU64::new(0),
+            duplicate_contents: AtomicU64::new(0),
+            unsupported_versions: AtomicU64::new(0),
+            roundtrip_mismatches: AtomicU64::new(0),
+            throttle_sleep_ms: AtomicU64::new(0),
+            unknown_fields: AtomicU64::new(0),
+            out_of_order_responses: AtomicU64::new(0),
+            images_in_progress: AtomicU64::new(0),
+            rss_bytes: AtomicU64::new(0),
+            busy_workers: AtomicU64::new(0),
+            worker_samples: AtomicU64::new(0),
+            busy_worker_sum: AtomicU64::new(0),
+            saturated_samples: AtomicU64::new(0),
+            saturated_ms: AtomicU64::new(0),
+            shed_memory: AtomicU64::new(0),
+            log_filtered: AtomicU64::new(0),
+            rejected_oversize: AtomicU64::new(0),
+            protocol_mismatches: AtomicU64::new(0),
+            checksum_failures: AtomicU64::new(0),
+            data_type_mismatches: AtomicU64::new(0),
+            publish_attempts: AtomicU64::new(0),
+            publish_confirms: AtomicU64::new(0),
+            validation_failures: AtomicU64::new(0),
+            disconnect_reasons: Mutex::new(BTreeMap::new()),
+            connection: Mutex::new(ConnectionState::default()),
+            tenants: Mutex::new(BTreeMap::new()),
+            max_tenants: env_var("MAX_TRACKED_TENANTS").unwrap_or(100),
+        }
+    }
+
+    fn update_count(&self, payload: &DataPayload) {
+        match payload {
+            DataPayload::Text(_) => self.text_count.fetch_add(1, Ordering::Relaxed),
+            DataPayload::Number(_) => self.number_count.fetch_add(1, Ordering::Relaxed),
+            DataPayload::Coordinates { .. } => self.coordinates_count.fetch_add(1, Ordering::Relaxed),
+            DataPayload::SensorData { .. } => self.sensor_count.fetch_add(1, Ordering::Relaxed),
+            DataPayload::ImageData { .. } => self.image_count.fetch_add(1, Ordering::Relaxed),
+            DataPayload::LogEntry { .. } => self.log_count.fetch_add(1, Ordering::Relaxed),
+            DataPayload::TimeSeries { .. } => self.time_series_count.fetch_add(1, Ordering::Relaxed),
+            DataPayload::Reference { .. } => self.reference_count.fetch_add(1, Ordering::Relaxed),
+            DataPayload::Json(_) => self.json_count.fetch_add(1, Ordering::Relaxed),
+            DataPayload::Batch(items) => {
+                items.iter().for_each(|item| self.update_count(item));
+                0
+            }
+        };
+    }
+
+    /// Records one processed message.
+    fn record(&self, payload: &DataPayload, processing_time_ms: u64, tenant: Option<&str>) {
+        let _guard = self.consistency.read().unwrap();
+        self.processed_count.fetch_add(1, Ordering::Relaxed);
+        self.update_count(payload);
+        self.total_processing_time.fetch_add(processing_time_ms, Ordering::Relaxed);
+        if let Some(tenant) = tenant {
+            self.record_tenant(tenant, payload, processing_time_ms);
+        }
+    }
+
+    /// Tenant names come from producers, so only the first `max_tenants - 1`
+    /// get their own entry and the rest share the last one, keeping the map
+    /// at `max_tenants` entries.
+    fn record_tenant(&self, tenant: &str, payload: &DataPayload, processing_time_ms: u64) {
+        const OTHER_TENANTS: &str = "_other";
+        let mut tenants = self.tenants.lock().unwrap();
+        let named = tenants.len() - usize::from(tenants.contains_key(OTHER_TENANTS));
+        let key = if tenants.contains_key(tenant) || named < self.max_tenants.saturating_sub(1) {
+            tenant
+        } else {
+            OTHER_TENANTS
+        };
+        let entry = tenants.entry(key.to_string()).or_default();
+        entry.processed_count += 1;
+        entry.total_processing_time_ms += processing_time_ms;
+        *entry.type_counts.entry(payload.type_name().to_string()).or_insert(0) += 1;
+    }
+
+    /// Reads all counters as one internally consistent set.
+    fn snapshot(&self) -> MetricsSnapshot {
+        let _guard = self.consistency.write().unwrap();
+        let worker_samples = self.worker_samples.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            processed_count: self.processed_count.load(Ordering::Relaxed),
+            total_processing_time_ms: self.total_processing_time.load(Ordering::Relaxed),
+            text_count: self.text_count.load(Ordering::Relaxed),
+            number_count: self.number_count.load(Ordering::Relaxed),
+            coordinates_count: self.coordinates_count.load(Ordering::Relaxed),
+            sensor_count: self.sensor_count.load(Ordering::Relaxed),
+            image_count: self.image_count.load(Ordering::Relaxed),
+            log_count: self.log_count.load(Ordering::Relaxed),
+            time_series_count: self.time_series_count.load(Ordering::Relaxed),
+            reference_count: self.reference_count.load(Ordering::Relaxed),
+            json_count: self.json_count.load(Ordering::Relaxed),
+            exploded_points: self.exploded_points.load(Ordering::Relaxed),
+            bad_utf8: self.bad_utf8.load(Ordering::Relaxed),
+            rejected_image_dims: self.rejected_image_dims.load(Ordering::Relaxed),
+            seq_gaps: self.seq_gaps.load(Ordering::Relaxed),
+            seq_reorders: self.seq_reorders.load(Ordering::Relaxed),
+            duplicate_ids: self.duplicate_ids.load(Ordering::Relaxed),
+            duplicate_contents: self.duplicate_contents.load(Ordering::Relaxed),
+            unsupported_versions: self.unsupported_versions.load(Ordering::Relaxed),
+            roundtrip_mismatches: self.roundtrip_mismatches.load(Ordering::Relaxed),
+            throttle_sleep_ms: self.throttle_sleep_ms.load(Ordering::Relaxed),
+            unknown_fields: self.unknown_fields.load(Ordering::Relaxed),
+            out_of_order_responses: self.out_of_order_responses.load(Ordering::Relaxed),
+            images_in_progress: self.images_in_progress.load(Ordering::Relaxed),
+            rss_bytes: self.rss_bytes.load(Ordering::Relaxed),
+            avg_busy_workers: self.busy_worker_sum.load(Ordering::Relaxed) as f64 / worker_samples.max(1) as f64,
+            saturated_fraction: self.saturated_samples.load(Ordering::Relaxed) as f64 / worker_samples.max(1) as f64,
+            saturated_ms: self.saturated_ms.load(Ordering::Relaxed),
+            shed_memory: self.shed_memory.load(Ordering::Relaxed),
+            log_filtered: self.log_filtered.load(Ordering::Relaxed),
+            rejected_oversize: self.rejected_oversize.load(Ordering::Relaxed),
+            protocol_mismatches: self.protocol_mismatches.load(Ordering::Relaxed),
+            checksum_failures: self.checksum_failures.load(Ordering::Relaxed),
+            data_type_mismatches: self.data_type_mismatches.load(Ordering::Relaxed),
+            publish_attempts: self.publish_attempts.load(Ordering::Relaxed),
+            publish_confirms: self.publish_confirms.load(Ordering::Relaxed),
+            validation_failures: self.validation_failures.load(Ordering::Relaxed),
+            disconnect_reasons: self.disconnect_reasons.lock().unwrap().clone(),
+            connection: self.connection.lock().unwrap().snapshot(),
+            tenants: self.tenants.lock().unwrap().clone(),
+        }
+    }
+
+    fn record_skip(&self, reason: &SkipReason) {
+        let counter = match reason {
+            SkipReason::UnsupportedVersion(_) => &self.unsupported_versions,
+            SkipReason::DuplicateId => &self.duplicate_ids,
+            SkipReason::DuplicateContent => &self.duplicate_contents,
+            SkipReason::ImageTooLarge(_) => &self.rejected_image_dims,
+            SkipReason::MemoryPressure => &self.shed_memory,
+            SkipReason::LogLevelFiltered(_) => &self.log_filtered,
+            SkipReason::ProtocolMismatch(_) => &self.protocol_mismatches,
+            SkipReason::ChecksumMismatch(_) => &self.checksum_failures,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a publish the client accepted. QoS 0 is left out since the
+    /// broker never acknowledges it.
+    fn record_publish_attempt(&self, qos: QoS) {
+        if qos != QoS::AtMostOnce {
+            self.publish_attempts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn record_disconnect(&self, reason: DisconnectReason) {
+        *self.disconnect_reasons
+            .lock()
+            .unwrap()
+            .entry(reason.as_str().to_string())
+            .or_insert(0) += 1;
+    }
+}
+
+/// Why the broker connection dropped. MQTT 3.1.1 has no DISCONNECT reason
+/// codes, so this is inferred from the CONNACK return code or the error the
+/// event loop surfaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DisconnectReason {
+    /// The broker sent a DISCONNECT itself.
+    ServerDisconnect,
+    /// CONNACK "server unavailable", i.e. the broker is busy or shutting down.
+    ServerUnavailable,
+    /// CONNACK refused the credentials or client.
+    NotAuthorized,
+    ConnectionRefused,
+    /// The broker stopped answering pings.
+    KeepAliveTimeout,
+    /// The socket was reset or closed under us.
+    ConnectionClosed,
+    /// Nothing is listening at the broker address.
+    BrokerUnreachable,
+    NetworkTimeout,
+    ProtocolError,
+    Other,
+}
+
+impl DisconnectReason {
+    fn classify(error: &ConnectionError) -> Self {
+        match error {
+            ConnectionError::ConnectionRefused(ConnectReturnCode::ServiceUnavailable) => {
+                DisconnectReason::ServerUnavailable
+            }
+            ConnectionError::ConnectionRefused(
+                ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized,
+            ) => DisconnectReason::NotAuthorized,
+            ConnectionError::ConnectionRefused(_) => DisconnectReason::ConnectionRefused,
+            ConnectionError::MqttState(StateError::AwaitPingResp) => DisconnectReason::KeepAliveTimeout,
+            ConnectionError::MqttState(StateError::Io(e)) | ConnectionError::Io(e) => match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => DisconnectReason::BrokerUnreachable,
+                std::io::ErrorKind::TimedOut => DisconnectReason::NetworkTimeout,
+                _ => DisconnectReason::ConnectionClosed,
+            },
+            ConnectionError::MqttState(_) | ConnectionError::NotConnAck(_) => DisconnectReason::ProtocolError,
+            ConnectionError::NetworkTimeout | ConnectionError::FlushTimeout => DisconnectReason::NetworkTimeout,
+            _ => DisconnectReason::Other,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::ServerDisconnect => "server_disconnect",
+            DisconnectReason::ServerUnavailable => "server_unavailable",
+            DisconnectReason::NotAuthorized => "not_authorized",
+            DisconnectReason::ConnectionRefused => "connection_refused",
+            DisconnectReason::KeepAliveTimeout => "keep_alive_timeout",
+            DisconnectReason::ConnectionClosed => "connection_closed",
+            DisconnectReason::BrokerUnreachable => "broker_unreachable",
+            DisconnectReason::NetworkTimeout => "network_timeout",
+            DisconnectReason::ProtocolError => "protocol_error",
+            DisconnectReason::Other => "other",
+        }
+    }
+
+    /// The least the event loop waits before reconnecting, whatever the
+    /// backoff says. A busy or refusing broker gets more room than a dropped
+    /// socket.
+    fn reconnect_delay(&self) -> Duration {
+        match self {
+            DisconnectReason::ServerUnavailable => Duration::from_secs(10),
+            DisconnectReason::NotAuthorized | DisconnectReason::ConnectionRefused => Duration::from_secs(30),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// Spreads `delay` by a random factor within ±`fraction` so a fleet of slaves
+/// dropped by the same broker restart doesn't reconnect in lockstep.
+fn with_jitter(delay: Duration, fraction: f64) -> Duration {
+    let factor = 1.0 + (rand::random::<f64>() * 2.0 - 1.0) * fraction;
+    delay.mul_f64(factor.max(0.0))
+}
+
+/// Rounds `value` to `decimals` places, ties to even. Works on the shortest
+/// decimal form of the float, so 2.675 is a tie as the sensor wrote it even
+/// though its binary value is slightly below.
+fn round_half_even(value: f64, decimals: usize) -> f64 {
+    if !value.is_finite() {
+        return value;
+    }
+    let repr = format!("{}", value.abs());
+    let (int_part, frac_part) = repr.split_once('.').unwrap_or((&repr, ""));
+    if frac_part.len() <= decimals {
+        return value;
+    }
+    let mut digits: Vec<u8> = int_part.bytes().chain(frac_part.bytes().take(decimals)).collect();
+    let next = frac_part.as_bytes()[decimals];
+    let rest_nonzero = frac_part.as_bytes()[decimals + 1..].iter().any(|&d| d != b'0');
+    let last_odd = digits.last().is_some_and(|d| (d - b'0') % 2 == 1);
+    if next > b'5' || (next == b'5' && (rest_nonzero || last_odd)) {
+        let mut i = digits.len();
+        loop {
+            if i == 0 {
+                digits.insert(0, b'1');
+                break;
+            }
+            i -= 1;
+            if digits[i] == b'9' {
+                digits[i] = b'0';
+            } else {
+                digits[i] += 1;
+                break;
+            }
+        }
+    }
+    let split = digits.len() - decimals;
+    let rounded = format!(
+        "{}.{}",
+        std::str::from_utf8(&digits[..split]).unwrap_or("0"),
+        std::str::from_utf8(&digits[split..]).unwrap_or("0"),
+    );
+    let rounded: f64 = rounded.trim_end_matches('.').parse().unwrap_or(value.abs());
+    rounded.copysign(value)
+}
+
+/// Rejects absurd declared dimensions before anything is sized from them:
+/// each side must be at most `max_dim` and the pixel count must fit in a `u32`.
+fn check_image_dimensions(width: u32, height: u32, max_dim: u32) -> Result<(), String> {
+    if width > max_dim || height > max_dim || width.checked_mul(height).is_none() {
+        return Err(format!("image dimensions too large ({}x{}, limit {})", width, height, max_dim));
+    }
+    Ok(())
+}
+
+/// Downscales an image with nearest-neighbour sampling so its longest side is
+/// at most `max_side` pixels. Returns `Ok(None)` when the image is already
+/// small enough, and an error when the buffer doesn't match the dimensions.
+fn make_thumbnail(
+    width: u32,
+    height: u32,
+    format: &str,
+    data: &[u8],
+    max_side: u32,
+) -> Result<Option<Value>, String> {
+    let bpp = check_image_buffer(width, height, format, data)?;
+
+    let longest = width.max(height);
+    if longest <= max_side {
+        return Ok(None);
+    }
+
+    let thumb_width = ((width as u64 * max_side as u64) / longest as u64).max(1) as u32;
+    let thumb_height = ((height as u64 * max_side as u64) / longest as u64).max(1) as u32;
+    let mut thumb = Vec::with_capacity(thumb_width as usize * thumb_height as usize * bpp);
+    for ty in 0..thumb_height as u64 {
+        let sy = ty * height as u64 / thumb_height as u64;
+        for tx in 0..thumb_width as u64 {
+            let sx = tx * width as u64 / thumb_width as u64;
+            let offset = (sy as usize * width as usize + sx as usize) * bpp;
+            thumb.extend_from_slice(&data[offset..offset + bpp]);
+        }
+    }
+
+    Ok(Some(serde_json::json!({
+        "thumbnail": {
+            "width": thumb_width,
+            "height": thumb_height,
+            "format": format,
+            "data": base64::engine::general_purpose::STANDARD.encode(&thumb),
+        }
+    })))
+}
+
+/// Buckets per channel in the `IMAGE_STATS` histogram.
+const IMAGE_HISTOGRAM_BINS: usize = 8;
+
+/// Per-channel mean, min, max and a coarse histogram, so consumers can
+/// quality-check an image without its bytes. An empty image has no mean,
+/// min or max and an all-zero histogram.
+fn image_stats(width: u32, height: u32, format: &str, data: &[u8]) -> Result<Value, String> {
+    let bpp = check_image_buffer(width, height, format, data)?;
+    let channels: Vec<Value> = (0..bpp)
+        .map(|channel| {
+            let mut histogram = [0u64; IMAGE_HISTOGRAM_BINS];
+            let mut sum = 0u64;
+            let mut min = None::<u8>;
+            let mut max = None::<u8>;
+            for &value in data.iter().skip(channel).step_by(bpp) {
+                histogram[value as usize * IMAGE_HISTOGRAM_BINS / 256] += 1;
+                sum += value as u64;
+                min = Some(min.map_or(value, |m| m.min(value)));
+                max = Some(max.map_or(value, |m| m.max(value)));
+            }
+            let pixels = data.len() / bpp;
+            let mean = (pixels > 0).then(|| sum as f64 / pixels as f64);
+            serde_json::json!({ "mean": mean, "min": min, "max": max, "histogram": histogram })
+        })
+        .collect();
+    Ok(serde_json::json!({ "channels": channels }))
+}
+
+/// Fetches the data behind `Reference` payloads from `file://` or plain
+/// `http://` URIs, checking size and checksum before handing it on.
+struct ReferenceResolver {
+    allowed_schemes: HashSet<String>,
+    max_bytes: u64,
+}
+
+impl ReferenceResolver {
+    const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn from_env() -> Option<Self> {
+        if env_var::<u8>("RESOLVE_REFERENCES").unwrap_or(0) != 1 {
+            return None;
+        }
+        let allowed_schemes = std::env::var("REFERENCE_SCHEMES")
+            .unwrap_or_else(|_| "file,http".to_string())
+            .split(',')
+            .map(|scheme| scheme.trim().to_ascii_lowercase())
+            .filter(|scheme| !scheme.is_empty())
+            .collect();
+        let max_bytes = env_var("REFERENCE_MAX_BYTES").unwrap_or(64 * 1024 * 1024);
+        Some(Self { allowed_schemes, max_bytes })
+    }
+
+    /// Fetches and verifies the referenced bytes, then decodes them by
+    /// content type: JSON as a payload, text as `Text`.
+    fn resolve(&self, uri: &str, size: u64, content_type: &str, checksum: &str) -> Result<DataPayload, String> {
+        if size > self.max_bytes {
+            return Err(format!("declared size {} exceeds the {} byte limit", size, self.max_bytes));
+        }
+        let (scheme, rest) = uri.split_once("://").ok_or_else(|| format!("not a URI: {}", uri))?;
+        let scheme = scheme.to_ascii_lowercase();
+        if !self.allowed_schemes.contains(&scheme) {
+            return Err(format!("scheme {} is not allowed", scheme));
+        }
+        let bytes = match scheme.as_str() {
+            "file" => read_limited(File::open(rest).map_err(|e| e.to_string())?, size)?,
+            "http" => fetch_http(rest, size)?,
+            other => return Err(format!("scheme {} is not supported", other)),
+        };
+        if bytes.len() as u64 != size {
+            return Err(format!("size mismatch: expected {} bytes, got {}", size, bytes.len()));
+        }
+        verify_checksum(&bytes, checksum)?;
+
+        let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "application/json" => {
+                let value: Value = serde_json::from_slice(&bytes).map_err(|e| format!("invalid JSON: {}", e))?;
+                match convert_payload(&value) {
+                    Some(DataPayload::Reference { .. }) => Err("references may not point at references".to_string()),
+                    Some(payload) => Ok(payload),
+                    None => Err("referenced JSON is not a known payload".to_string()),
+                }
+            }
+            text if text.starts_with("text/") => String::from_utf8(bytes)
+                .map(DataPayload::Text)
+                .map_err(|_| "referenced text is not valid UTF-8".to_string()),
+            other => Err(format!("unsupported content type {}", other)),
+        }
+    }
+}
+
+/// Reads at most one byte more than `expected`, so an oversized source is
+/// caught without reading all of it.
+fn read_limited(reader: impl std::io::Read, expected: u64) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    reader
+        .take(expected + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// A minimal HTTP/1.0 GET, enough for fetching a blob from an internal store.
+fn fetch_http(location: &str, expected: u64) -> Result<Vec<u8>, String> {
+    let (authority, path) = match location.find('/') {
+        Some(index) => location.split_at(index),
+        None => (location, "/"),
+    };
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+    let stream = std::net::ToSocketAddrs::to_socket_addrs(&address)
+        .map_err(|e| format!("cannot resolve {}: {}", authority, e))?
+        .next()
+        .ok_or_else(|| format!("cannot resolve {}", authority))?;
+    let mut stream = std::net::TcpStream::connect_timeout(&stream, ReferenceResolver::HTTP_TIMEOUT)
+        .map_err(|e| format!("cannot connect to {}: {}", authority, e))?;
+    stream.set_read_timeout(Some(ReferenceResolver::HTTP_TIMEOUT)).map_err(|e| e.to_string())?;
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, authority)
+        .map_err(|e| e.to_string())?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).map_err(|e| e.to_string())?;
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("HTTP request failed: {}", status_line.trim()));
+    }
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).map_err(|e| e.to_string())? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    read_limited(reader, expected)
+}
+
+fn verify_checksum(bytes: &[u8], checksum: &str) -> Result<(), String> {
+    use sha2::Digest;
+    let expected = checksum
+        .strip_prefix("sha256:")
+        .ok_or_else(|| format!("unsupported checksum {:?}, expected sha256:<hex>", checksum))?;
+    let actual: String = sha2::Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect();
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(format!("checksum mismatch: expected {}, got sha256:{}", checksum, actual))
+    }
+}
+
+/// CPU time consumed by the calling thread, where the platform exposes it.
+#[cfg(unix)]
+fn thread_cpu_time() -> Option<Duration> {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `ts` is a valid, writable timespec for the duration of the call.
+    let rc = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    (rc == 0).then(|| Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+/// Resident set size of this process, read from `/proc/self/statm`.
+#[cfg(target_os = "linux")]
+fn resident_memory_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no memory-safety preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (page_size > 0).then(|| resident_pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory_bytes() -> Option<u64> {
+    None
+}
+
+/// Samples RSS every `interval` and flips `memory_pressure` while it is over
+/// `cap_bytes`, which makes the handler shed new work.
+fn spawn_memory_monitor(handler: Arc<MessageHandler>, cap_bytes: u64, interval: Duration) {
+    thread::spawn(move || loop {
+        if let Some(rss) = resident_memory_bytes() {
+            handler.metrics.rss_bytes.store(rss, Ordering::Relaxed);
+            let over = rss > cap_bytes;
+            if handler.memory_pressure.swap(over, Ordering::Relaxed) != over {
+                if over {
+                    warn!("RSS {} bytes is over the {} byte cap, shedding new work", rss, cap_bytes);
+                } else {
+                    info!("RSS back under the cap ({} bytes), accepting work again", rss);
+                }
+            }
+        }
+        thread::sleep(interval);
+    });
+}
+
+/// Cooperative CPU cap for the processing thread. After each message it
+/// compares CPU use against wall-clock time over a rolling window and, when
+/// over `CPU_TARGET_PCT`, sleeps just long enough to bring the ratio back down.
+/// Without per-thread CPU time it falls back to the measured busy wall time.
+struct CpuThrottle {
+    target: f64,
+    window_start: Instant,
+    window_start_cpu: Option<Duration>,
+    window_busy: Duration,
+}
+
+impl CpuThrottle {
+    const WINDOW: Duration = Duration::from_secs(1);
+    const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+    fn from_env() -> Option<Self> {
+        let target_pct = env_var::<f64>("CPU_TARGET_PCT").filter(|pct| *pct > 0.0 && *pct < 100.0)?;
+        Some(Self {
+            target: target_pct / 100.0,
+            window_start: Instant::now(),
+            window_start_cpu: thread_cpu_time(),
+            window_busy: Duration::ZERO,
+        })
+    }
+
+    /// Accounts for one message that kept the thread busy for `busy` and
+    /// returns how long it slept to yield the CPU.
+    fn after_message(&mut self, busy: Duration) -> Duration {
+        self.window_busy += busy;
+        let used = match (self.window_start_cpu, thread_cpu_time()) {
+            (Some(start), Some(now)) => now.saturating_sub(start),
+            _ => self.window_busy,
+        };
+        let elapsed = self.window_start.elapsed();
+
+        let ratio = used.as_secs_f64() / elapsed.as_secs_f64().max(f64::EPSILON);
+        let sleep = if ratio > self.target {
+            let needed = Duration::from_secs_f64(used.as_secs_f64() / self.target).saturating_sub(elapsed);
+            needed.min(Self::MAX_SLEEP)
+        } else {
+            Duration::ZERO
+        };
+        if !sleep.is_zero() {
+            thread::sleep(sleep);
+        }
+
+        if self.window_start.elapsed() >= Self::WINDOW {
+            self.window_start = Instant::now();
+            self.window_start_cpu = thread_cpu_time();
+            self.window_busy = Duration::ZERO;
+        }
+        sleep
+    }
+}
+
+/// The packet versions this slave accepts. The major protocol version must be
+/// [`PROTOCOL_VERSION`]; packets without `protocol_version` fall back to the
+/// major part of their metadata `version` (`"1.0"` is version 1), and
+/// packets with neither are accepted. When `SUPPORTED_VERSIONS` is set
+/// (e.g. `1.0,1.1`) the metadata version must also be one of those; packets
+/// without one are then let through unless `MISSING_VERSION_POLICY=deny`.
+struct VersionPolicy {
+    supported: Option<HashSet<String>>,
+    allow_missing: bool,
+}
+
+impl VersionPolicy {
+    fn from_env() -> Self {
+        let supported = std::env::var("SUPPORTED_VERSIONS").ok().map(|raw| {
+            raw.split(',')
+                .map(str::trim)
+                .filter(|version| !version.is_empty())
+                .map(str::to_string)
+                .collect()
+        });
+        let allow_missing = match std::env::var("MISSING_VERSION_POLICY").as_deref() {
+            Ok("deny") => false,
+            Ok("allow") | Err(_) => true,
+            Ok(other) => {
+                warn!("Unknown MISSING_VERSION_POLICY {:?}, allowing packets without a version", other);
+                true
+            }
+        };
+        Self { supported, allow_missing }
+    }
+
+    fn check(&self, protocol_version: u32, version: Option<&str>) -> Result<(), SkipReason> {
+        let major = match protocol_version {
+            0 => version.and_then(|version| version.split('.').next()?.trim().parse::<u32>().ok()),
+            major => Some(major),
+        };
+        if let Some(major) = major.filter(|major| *major != PROTOCOL_VERSION) {
+            return Err(SkipReason::ProtocolMismatch(format!(
+                "protocol version {} is not supported, this slave speaks version {}",
+                major, PROTOCOL_VERSION
+            )));
+        }
+        let Some(supported) = &self.supported else {
+            return Ok(());
+        };
+        match version {
+            Some(version) if supported.contains(version) => Ok(()),
+            Some(version) => Err(SkipReason::UnsupportedVersion(format!("unsupported version {}", version))),
+            None if self.allow_missing => Ok(()),
+            None => Err(SkipReason::UnsupportedVersion("unsupported version (none given)".to_string())),
+        }
+    }
+}
+
+/// QoS used when publishing responses, optionally overridden per payload type.
+struct ResponseQos {
+    default: QoS,
+    by_type: HashMap<String, QoS>,
+}
+
+impl ResponseQos {
+    /// Reads `RESPONSE_QOS` (the global level, `fallback` when unset) and
+    /// `RESPONSE_QOS_BY_TYPE` (e.g. `alert:2,sensor_data:0`).
+    fn from_env(fallback: QoS) -> Result<Self, String> {
+        let default = match std::env::var("RESPONSE_QOS") {
+            Ok(level) => parse_qos(&level)?,
+            Err(_) => fallback,
+        };
+
+        let mut by_type = HashMap::new();
+        let raw = std::env::var("RESPONSE_QOS_BY_TYPE").unwrap_or_default();
+        for entry in raw.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (type_name, level) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("expected type:qos in RESPONSE_QOS_BY_TYPE, got {:?}", entry))?;
+            let type_name = type_name.trim();
+            if !PAYLOAD_TYPE_NAMES.contains(&type_name) {
+                warn!("RESPONSE_QOS_BY_TYPE names unknown payload type {:?}", type_name);
+            }
+            by_type.insert(type_name.to_string(), parse_qos(level)?);
+        }
+
+        Ok(Self { default, by_type })
+    }
+
+    fn for_type(&self, type_name: &str) -> QoS {
+        self.by_type.get(type_name).copied().unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[allow(dead_code)]
+struct FlexiblePacket {
+    id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data_type: Option<String>,
+    payload: Value,
+    #[serde(default)]
+    metadata: Option<Metadata>,
+    /// 0 when the sender predates the field; see `VersionPolicy`.
+    #[serde(default)]
+    protocol_version: u32,
+    /// The text the packet was parsed from, kept for dead-lettering.
+    #[serde(skip)]
+    raw: String,
+    /// Assigned by the connection thread in arrival order, starting at 1.
+    #[serde(skip)]
+    receive_index: u64,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[allow(dead_code)]
+struct Metadata {
+    #[serde(default)]
+    source: String,
+    #[serde(default)]
+    version: String,
+    /// Routes every packet with the same value to the same worker.
+    #[serde(default)]
+    partition: Option<String>,
+    #[serde(default)]
+    master_id: Option<String>,
+    /// Per-master sequence number, starting at 1.
+    #[serde(default)]
+    seq: Option<String>,
+    /// Set to "true" on packets the master resent after a reconnect.
+    #[serde(default)]
+    replay: Option<String>,
+    /// Who the packet belongs to, for per-tenant metrics.
+    #[serde(default)]
+    tenant: Option<String>,
+    /// `payload_crc32` of the payload as the master sent it.
+    #[serde(default)]
+    crc32: Option<String>,
+}
+
+impl FlexiblePacket {
+    /// The metadata `version`, if the producer sent one.
+    fn version(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .map(|m| m.version.as_str())
+            .filter(|version| !version.is_empty())
+    }
+
+    fn seq(&self) -> Option<u64> {
+        self.metadata.as_ref()?.seq.as_deref()?.parse().ok()
+    }
+
+    /// Compares the payload with its `crc32` metadata; packets without one
+    /// pass.
+    fn verify_crc32(&self) -> Result<(), String> {
+        let Some(expected) = self.metadata.as_ref().and_then(|m| m.crc32.as_deref()) else {
+            return Ok(());
+        };
+        let actual = payload_crc32(&self.payload);
+        if actual.eq_ignore_ascii_case(expected) {
+            Ok(())
+        } else {
+            Err(format!("payload crc32 {} does not match {}", actual, expected))
+        }
+    }
+
+    fn tenant(&self) -> Option<&str> {
+        self.metadata.as_ref().and_then(|m| m.tenant.as_deref())
+    }
+
+    fn is_replay(&self) -> bool {
+        self.metadata.as_ref().and_then(|m| m.replay.as_deref()) == Some("true")
+    }
+
+    /// The key that pins a packet to one worker: an explicit `partition` in
+    /// the metadata, otherwise the sensor id of `SensorData` payloads.
+    fn partition_key(&self) -> Option<&str> {
+        if let Some(partition) = self.metadata.as_ref().and_then(|m| m.partition.as_deref()) {
+            return Some(partition);
+        }
+        self.payload.get("SensorData")?.get("sensor_id")?.as_str()
+    }
+}
+
+/// Mirrors of the packet schema that deny unknown fields, used only for the
+/// extra `STRICT_FIELDS` check. Leaf values are ignored so that only the
+/// shape is checked; type errors are left to the normal lenient path.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictPacket {
+    id: IgnoredAny,
+    #[serde(default)]
+    timestamp: IgnoredAny,
+    #[serde(default)]
+    data_type: IgnoredAny,
+    #[serde(default)]
+    protocol_version: IgnoredAny,
+    payload: StrictPayload,
+    #[serde(default)]
+    metadata: Option<StrictMetadata>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictMetadata {
+    source: IgnoredAny,
+    version: IgnoredAny,
+    partition: IgnoredAny,
+    master_id: IgnoredAny,
+    seq: IgnoredAny,
+    replay: IgnoredAny,
+    critical: IgnoredAny,
+    tenant: IgnoredAny,
+    crc32: IgnoredAny,
+    requeued: IgnoredAny,
+    requeue_attempts: IgnoredAny,
+    original_id: IgnoredAny,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+enum StrictPayload {
+    Text(IgnoredAny),
+    Number(IgnoredAny),
+    Coordinates(StrictCoordinates),
+    SensorData(StrictSensorData),
+    ImageData(StrictImageData),
+    LogEntry(StrictLogEntry),
+    TimeSeries(StrictTimeSeries),
+    Reference(StrictReference),
+    Json(IgnoredAny),
+    Batch(Vec<StrictPayload>),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictCoordinates {
+    x: IgnoredAny,
+    y: IgnoredAny,
+    z: IgnoredAny,
+    system: IgnoredAny,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictSensorData {
+    sensor_id: IgnoredAny,
+    temperature: IgnoredAny,
+    humidity: IgnoredAny,
+    pressure: IgnoredAny,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictImageData {
+    width: IgnoredAny,
+    height: IgnoredAny,
+    format: IgnoredAny,
+    data: IgnoredAny,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictLogEntry {
+    level: IgnoredAny,
+    message: IgnoredAny,
+    timestamp: IgnoredAny,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictTimeSeries {
+    series_id: IgnoredAny,
+    points: Vec<StrictPoint>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictReference {
+    uri: IgnoredAny,
+    size: IgnoredAny,
+    content_type: IgnoredAny,
+    checksum: IgnoredAny,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictPoint {
+    timestamp: IgnoredAny,
+    value: IgnoredAny,
+}
+
+/// Writes every response the slave emits to a SQLite database (`--db`) for
+/// auditing. Rows are handed to a writer thread so disk I/O never holds up
+/// processing; if the writer falls `QUEUE` rows behind, new rows are dropped.
+struct ResponseStore {
+    rows: Mutex<Option<SyncSender<StoredResponse>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct StoredResponse {
+    packet_id: String,
+    received_at: String,
+    status: String,
+    processing_time_ms: u64,
+    payload_type: Option<String>,
+}
+
+impl ResponseStore {
+    const QUEUE: usize = 10_000;
+
+    /// Opens the database, creating the table if needed, and starts the
+    /// writer thread.
+    fn open(path: &str) -> Result<Self, String> {
+        let db = rusqlite::Connection::open(path).map_err(|e| format!("failed to open {}: {}", path, e))?;
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS responses (
+                id INTEGER PRIMARY KEY,
+                packet_id TEXT NOT NULL,
+                received_at TEXT NOT NULL,
+                status TEXT NOT NULL,
+                processing_time_ms INTEGER NOT NULL,
+                payload_type TEXT
+            )",
+            (),
+        )
+        .map_err(|e| format!("failed to create the responses table in {}: {}", path, e))?;
+        let (rows, received) = mpsc::sync_channel::<StoredResponse>(Self::QUEUE);
+        let writer = thread::spawn(move || {
+            let mut db = db;
+            // Whatever has queued up meanwhile goes in the same transaction.
+            while let Ok(first) = received.recv() {
+                let batch: Vec<StoredResponse> = std::iter::once(first).chain(received.try_iter()).collect();
+                if let Err(e) = Self::insert(&mut db, &batch) {
+                    error!("Failed to store {} responses: {}", batch.len(), e);
+                }
+            }
+        });
+        Ok(Self { rows: Mutex::new(Some(rows)), writer: Mutex::new(Some(writer)) })
+    }
+
+    fn insert(db: &mut rusqlite::Connection, batch: &[StoredResponse]) -> rusqlite::Result<()> {
+        let tx = db.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO responses (packet_id, received_at, status, processing_time_ms, payload_type)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for row in batch {
+                insert.execute((
+                    &row.packet_id,
+                    &row.received_at,
+                    &row.status,
+                    row.processing_time_ms as i64,
+                    &row.payload_type,
+                ))?;
+            }
+        }
+        tx.commit()
+    }
+
+    fn record(&self, response: &DataResponse, payload_type: Option<&str>) {
+        let Some(rows) = &*self.rows.lock().unwrap() else {
+            return;
+        };
+        let row = StoredResponse {
+            packet_id: response.packet_id.clone(),
+            received_at: response.received_at.clone(),
+            status: response.status.to_string(),
+            processing_time_ms: response.processing_time_ms,
+            payload_type: payload_type.map(str::to_string),
+        };
+        if let Err(TrySendError::Full(_)) = rows.try_send(row) {
+            warn!(packet_id = %response.packet_id, "Response store is {} rows behind, dropping", Self::QUEUE);
+        }
+    }
+
+    /// Stops taking rows and waits for the writer to store the rest.
+    fn close(&self) {
+        self.rows.lock().unwrap().take();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Where the handler sends what it produces: the broker, or, when running
+/// offline, a JSONL file that receives whatever goes to the response topic.
+enum Outlet {
+    Broker(Client),
+    /// The async slave's client. Publishing blocks on `Handle`, so the
+    /// handler must run on a blocking thread (`spawn_blocking`), never on a
+    /// runtime worker.
+    Async(AsyncClient, tokio::runtime::Handle),
+    File(Mutex<BufWriter<File>>, Topics),
+}
+
+impl Outlet {
+    fn publish(&self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<(), String> {
+        match self {
+            Outlet::Broker(client) => client.publish(topic, qos, retain, payload).map_err(|e| format!("{:?}", e)),
+            Outlet::Async(client, runtime) => runtime
+                .block_on(client.publish(topic, qos, retain, payload))
+                .map_err(|e| format!("{:?}", e)),
+            Outlet::File(file, topics) if topics.is_response(topic) => {
+                let mut file = file.lock().unwrap();
+                file.write_all(&payload).and_then(|_| file.write_all(b"\n")).map_err(|e| e.to_string())
+            }
+            // Only responses belong in the output file; anything else
+            // (dead letters, reports) is still worth seeing.
+            Outlet::File(..) => {
+                info!("{}: {}", topic, String::from_utf8_lossy(&payload));
+                Ok(())
+            }
+        }
+    }
+
+    /// Publishes without waiting for room in the request queue, failing if
+    /// there is none. Safe to call from the thread driving the connection.
+    fn try_publish(&self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<(), String> {
+        match self {
+            Outlet::Broker(client) => client.try_publish(topic, qos, retain, payload).map_err(|e| format!("{:?}", e)),
+            Outlet::Async(client, _) => client.try_publish(topic, qos, retain, payload).map_err(|e| format!("{:?}", e)),
+            Outlet::File(..) => self.publish(topic, qos, retain, payload),
+        }
+    }
+}
+
+/// Settings and shared state used to handle each incoming message.
+struct MessageHandler {
+    outlet: Outlet,
+    metrics: Arc<ProcessingMetrics>,
+    thumbnail_max: Option<u32>,
+    quiet_types: HashSet<String>,
+    response_qos: ResponseQos,
+    /// Answer a `TimeSeries` with one response per point instead of one aggregate.
+    explode_time_series: bool,
+    /// Per-variant replacements for the built-in processing.
+    hooks: ProcessingHooks,
+    /// Handles every payload no hook claims.
+    processor: Box<dyn PayloadProcessor>,
+    /// Packets dispatched to a worker and not yet finished.
+    in_flight: AtomicUsize,
+    /// When a message last arrived or finished processing, for `IDLE_SHUTDOWN_SECS`.
+    last_activity: Mutex<Instant>,
+    /// Request and response topics; `--request-topic` and `--response-topic`.
+    topics: Topics,
+    /// `--db`: where emitted responses are also stored.
+    response_store: Option<ResponseStore>,
+    /// `--dead-letter`: where messages that can't be handled are republished
+    /// with the reason; `None` when turned off.
+    dead_letter_topic: Option<String>,
+    /// `--min-log-level`: log entries below it are answered as filtered
+    /// instead of being processed.
+    min_log_level: Option<LogLevel>,
+    /// Publish responses to `<response topic>/<routing key>` rather than the
+    /// response topic itself.
+    routing_key_in_topic: bool,
+    max_image_dim: u32,
+    /// Decimal places sensor readings are rounded to after conversion.
+    sensor_round_decimals: Option<usize>,
+    /// Significant digits for floats in status strings.
+    float_digits: usize,
+    /// Skips packet ids already processed within the window (redelivery).
+    dedup_by_id: Option<Mutex<DedupCache>>,
+    /// Skips payloads identical to one already processed within the window.
+    dedup_by_content: Option<Mutex<DedupCache>>,
+    /// Artificial network delay before each response is published, plus up
+    /// to `response_delay_jitter` extra. Separate from processing time.
+    response_delay: Duration,
+    response_delay_jitter: Duration,
+    /// Rejects packets from another protocol version, or whose metadata
+    /// version isn't supported when that is configured.
+    version_policy: VersionPolicy,
+    /// Run on every converted payload before processing (`VALIDATORS`).
+    validators: ValidatorChain,
+    /// Only answer a validation failure, without also dead-lettering it
+    /// (`VALIDATION_FAILURE=reject`).
+    reject_invalid: bool,
+    /// Acknowledge each packet on `data/ack` before processing it (`SEND_ACKS`).
+    send_acks: bool,
+    /// Shape of published responses (`RESPONSE_SCHEMA`).
+    response_schema: ResponseSchema,
+    /// Encoding of published responses (`--format`). Requests are decoded
+    /// by their tag byte whatever this is set to.
+    wire_format: WireFormat,
+    /// Gzipped requests may not inflate past this (`MAX_DECOMPRESSED_BYTES`).
+    max_decompressed_bytes: u64,
+    /// `--max-payload-bytes`: larger messages are refused before any
+    /// decoding.
+    max_payload_bytes: usize,
+    /// Serialized responses larger than this lose their optional fields.
+    max_response_bytes: Option<usize>,
+    /// Re-serialize each converted payload and compare it with what arrived.
+    verify_roundtrip: bool,
+    /// Check that responses go out in receive order. Only meaningful with a
+    /// single worker; parallel workers legitimately finish out of order.
+    verify_receive_order: bool,
+    last_emitted_index: AtomicU64,
+    /// Fetches `Reference` payloads and processes what they point at
+    /// (`RESOLVE_REFERENCES`); without it references are only recorded.
+    reference_resolver: Option<ReferenceResolver>,
+    /// Add per-channel pixel statistics to image responses (`IMAGE_STATS`).
+    image_stats: bool,
+    /// Limits how many images are processed at once (`MAX_CONCURRENT_IMAGES`).
+    /// The worker pool turns this into its image lane; only callers without
+    /// a pool, such as the async slave, wait on it here.
+    image_permits: Option<Semaphore>,
+    /// Set by the memory monitor while RSS is over `MEMORY_CAP_MB`.
+    memory_pressure: AtomicBool,
+    /// Dead-letter packets carrying fields the schema doesn't know about.
+    strict_fields: bool,
+    /// Fraction of received packets mirrored to `data/tap` for live debugging.
+    tap_sample: f64,
+    /// Only packets of these types are tapped; empty means all types.
+    tap_types: HashSet<String>,
+}
+
+impl MessageHandler {
+    fn mark_active(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// True once nothing has arrived or been processed for `idle_for`.
+    /// Activity is read before the in-flight count, and arrivals mark
+    /// activity before dispatching, so a message that is just arriving or
+    /// still being processed always counts as activity.
+    fn is_idle(&self, idle_for: Duration) -> bool {
+        let quiet = self.last_activity.lock().unwrap().elapsed() >= idle_for;
+        quiet && self.in_flight.load(Ordering::SeqCst) == 0
+    }
+
+    /// Builds a handler configured from the environment around the given
+    /// processing.
+    fn from_env(
+        outlet: Outlet,
+        metrics: Arc<ProcessingMetrics>,
+        response_qos: ResponseQos,
+        workers: usize,
+        processor: Box<dyn PayloadProcessor>,
+        hooks: ProcessingHooks,
+        validators: ValidatorChain,
+    ) -> Self {
+        let dedup_window = Duration::from_secs(env_var("DEDUP_WINDOW_SECS").unwrap_or(60));
+        let dedup_cache = |var: &str| {
+            (env_var::<u8>(var).unwrap_or(0) == 1).then(|| Mutex::new(DedupCache::new(dedup_window)))
+        };
+        // Types whose per-message processing logs are suppressed. They are
+        // still processed and counted as usual.
+        let quiet_types = type_list_from_env("QUIET_TYPES");
+        MessageHandler {
+            outlet,
+            metrics,
+            thumbnail_max: env_var::<u32>("THUMBNAIL_MAX").filter(|max| *max > 0),
+            processor,
+            quiet_types,
+            response_qos,
+            explode_time_series: env_var::<u8>("EXPLODE_TIMESERIES").unwrap_or(0) == 1,
+            hooks,
+            in_flight: AtomicUsize::new(0),
+            last_activity: Mutex::new(Instant::now()),
+            topics: Topics::default(),
+            response_store: None,
+            dead_letter_topic: Some(DeadLetter::TOPIC.to_string()),
+            min_log_level: None,
+            routing_key_in_topic: env_var::<u8>("ROUTING_KEY_IN_TOPIC").unwrap_or(0) == 1,
+            max_image_dim: env_var("MAX_IMAGE_DIM").unwrap_or(16384),
+            sensor_round_decimals: env_var("SENSOR_ROUND_DECIMALS"),
+            float_digits: env_var("FLOAT_SIG_DIGITS").unwrap_or(6),
+            dedup_by_id: dedup_cache("DEDUP_BY_ID"),
+            dedup_by_content: dedup_cache("DEDUP_BY_CONTENT"),
+            response_delay: Duration::from_millis(env_var("RESPONSE_DELAY_MS").unwrap_or(0)),
+            response_delay_jitter: Duration::from_millis(env_var("RESPONSE_DELAY_JITTER_MS").unwrap_or(0)),
+            version_policy: VersionPolicy::from_env(),
+            validators,
+            reject_invalid: std::env::var("VALIDATION_FAILURE").is_ok_and(|policy| policy == "reject"),
+            send_acks: env_var::<u8>("SEND_ACKS").unwrap_or(1) == 1,
+            response_schema: env_var("RESPONSE_SCHEMA").unwrap_or_default(),
+            wire_format: WireFormat::Json,
+            max_decompressed_bytes: env_var("MAX_DECOMPRESSED_BYTES").unwrap_or(64 * 1024 * 1024),
+            max_payload_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+            max_response_bytes: env_var("MAX_RESPONSE_BYTES"),
+            verify_roundtrip: env_var::<u8>("VERIFY_ROUNDTRIP").unwrap_or(0) == 1,
+            verify_receive_order: workers == 1,
+            last_emitted_index: AtomicU64::new(0),
+            reference_resolver: ReferenceResolver::from_env(),
+            image_stats: env_var::<u8>("IMAGE_STATS").unwrap_or(0) == 1,
+            image_permits: env_var::<usize>("MAX_CONCURRENT_IMAGES").filter(|max| *max > 0).map(Semaphore::new),
+            memory_pressure: AtomicBool::new(false),
+            strict_fields: env_var::<u8>("STRICT_FIELDS").unwrap_or(0) == 1,
+            tap_sample: env_var::<f64>("TAP_SAMPLE").unwrap_or(0.0).clamp(0.0, 1.0),
+            tap_types: type_list_from_env("TAP_TYPES"),
+        }
+    }
+
+    /// Checks and parses a raw message into the packets it carries: one for a
+    /// plain packet, several for an NDJSON stream, none if it's unusable.
+    fn parse_message(&self, payload: &[u8]) -> Vec<FlexiblePacket> {
+        if payload.len() > self.max_payload_bytes {
+            self.reject_oversize(payload);
+            return Vec::new();
+        }
+        let payload = match decompress(payload, self.max_decompressed_bytes) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Rejecting message that failed to decompress: {}", e);
+                self.dead_letter_bytes(None, &e, payload);
+                self.reply_unparsed(payload, &e);
+                return Vec::new();
+            }
+        };
+        // CBOR and MessagePack become JSON here, so everything after
+        // (dead letters included) sees the same text form.
+        let payload = match WireFormat::to_json(&payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Rejecting message that failed to decode: {}", e);
+                self.dead_letter_bytes(None, &e, &payload);
+                self.reply_unparsed(&payload, &e);
+                return Vec::new();
+            }
+        };
+        let payload = payload.as_ref();
+        // A lossy conversion would turn corrupt bytes into confusing parse
+        // errors, so reject anything that isn't valid UTF-8 up front.
+        let payload_str = match std::str::from_utf8(payload) {
+            Ok(payload_str) => payload_str,
+            Err(e) => {
+                warn!("Rejecting message that is not valid UTF-8: {}", e);
+                self.metrics.bad_utf8.fetch_add(1, Ordering::Relaxed);
+                self.dead_letter_bytes(None, "invalid UTF-8", payload);
+                self.reply_unparsed(payload, &format!("invalid UTF-8: {}", e));
+                return Vec::new();
+            }
+        };
+
+        debug!("Attempting to parse message: {}", payload_str);
+
+        let packets = match serde_json::from_str::<FlexiblePacket>(payload_str) {
+            Ok(mut packet) => {
+                packet.raw = payload_str.to_string();
+                vec![packet]
+            }
+            Err(e) if is_ndjson(payload_str) => {
+                debug!("Message is not a single packet ({}), processing as NDJSON", e);
+                self.parse_ndjson(payload_str)
+            }
+            Err(e) => {
+                error!("Failed to parse message: {:?}", e);
+                debug!("Raw payload: {}", payload_str);
+                self.reply_unparsed(payload, &e.to_string());
+                Vec::new()
+            }
+        };
+        if !self.strict_fields {
+            return packets;
+        }
+        packets.into_iter().filter(|packet| self.check_strict(packet)).collect()
+    }
+
+    /// Parses and handles a raw message on the calling thread, for callers
+    /// without a worker pool.
+    fn handle_message(&self, payload: &[u8]) {
+        self.mark_active();
+        for packet in self.parse_message(payload) {
+            self.ack(&packet);
+            self.handle_packet(packet);
+        }
+    }
+
+    /// Re-parses a packet with unknown fields denied, dead-lettering it with
+    /// the offending field named if the producer sent anything unexpected.
+    fn check_strict(&self, packet: &FlexiblePacket) -> bool {
+        match serde_json::from_str::<StrictPacket>(&packet.raw) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!(packet_id = %packet.id, "Dead-lettering packet: {}", e);
+                self.metrics.unknown_fields.fetch_add(1, Ordering::Relaxed);
+                self.dead_letter(Some(packet.id.clone()), &format!("strict mode: {}", e), &packet.raw);
+                false
+            }
+        }
+    }
+
+    /// Parses each line of an NDJSON stream as its own packet. Lines that
+    /// don't parse are dead-lettered individually.
+    fn parse_ndjson(&self, payload: &str) -> Vec<FlexiblePacket> {
+        let mut packets = Vec::new();
+        for line in payload.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match serde_json::from_str::<FlexiblePacket>(line) {
+                Ok(mut packet) => {
+                    packet.raw = line.to_string();
+                    packets.push(packet);
+                }
+                Err(e) => {
+                    error!("Failed to parse NDJSON line: {:?}", e);
+                    self.dead_letter(None, &format!("malformed NDJSON line: {}", e), line);
+                    self.reply_unparsed(line.as_bytes(), &e.to_string());
+                }
+            }
+        }
+        packets
+    }
+
+    /// Refuses a message over `--max-payload-bytes` without decoding it.
+    /// Only a leading `"id"` is looked for, so the rejection can still be
+    /// matched to its request.
+    fn reject_oversize(&self, payload: &[u8]) {
+        let detail = format!("payload of {} bytes exceeds the {} byte limit", payload.len(), self.max_payload_bytes);
+        let packet_id = leading_packet_id(payload).unwrap_or_else(|| UNPARSED_PACKET_ID.to_string());
+        warn!(packet_id = %packet_id, "Rejecting message: {}", detail);
+        self.metrics.rejected_oversize.fetch_add(1, Ordering::Relaxed);
+        let response = DataResponse::from_outcome(packet_id, ResponseStatus::Rejected(detail), 0);
+        self.publish_response(&response, None, self.response_qos.default, true);
+    }
+
+    /// Answers a message that never became a packet, so the master isn't
+    /// left waiting on it. The id is taken from the raw JSON when it has one,
+    /// and is [`UNPARSED_PACKET_ID`] otherwise.
+    fn reply_unparsed(&self, payload: &[u8], error: &str) {
+        let packet_id = serde_json::from_slice::<Value>(payload)
+            .ok()
+            .and_then(|value| value.get("id")?.as_str().map(str::to_string))
+            .unwrap_or_else(|| UNPARSED_PACKET_ID.to_string());
+        let response = DataResponse::from_outcome(packet_id, ResponseStatus::ParseError(error.to_string()), 0);
+        self.publish_response(&response, None, self.response_qos.default, true);
+    }
+
+    fn handle_packet(&self, packet: FlexiblePacket) {
+        let start_time = Instant::now();
+        // Everything logged while handling the packet carries its id, and
+        // the span's closing line shows how long that took.
+        let _span = info_span!("packet", id = %packet.id).entered();
+        info!("Successfully parsed message");
+        self.tap(&packet);
+
+        let Some(data_payload) = convert_payload(&packet.payload) else {
+            // A newer protocol may have payloads this slave can't even
+            // parse, and the sender should hear why.
+            if let Err(reason) = self.should_process(&packet, None) {
+                self.skip(packet, reason);
+                return;
+            }
+            error!("Failed to convert payload to DataPayload");
+            debug!("Raw payload structure: {:?}", packet.payload);
+            self.dead_letter(Some(packet.id.clone()), "unknown payload", &packet.raw);
+            let response = DataResponse::from_outcome(packet.id.clone(), ResponseStatus::ConversionError, 0)
+                .with_receive_index(packet.receive_index);
+            self.publish_response(&response, packet.data_type.as_deref(), self.response_qos.default, true);
+            return;
+        };
+
+        // The payload wins, but a producer labelling packets wrongly is
+        // worth hearing about.
+        if let Some(declared) = packet.data_type.as_deref().filter(|declared| *declared != data_payload.type_name()) {
+            warn!("Declared data_type {:?} does not match the {} payload", declared, data_payload.type_name());
+            self.metrics.data_type_mismatches.fetch_add(1, Ordering::Relaxed);
+        }
+        if self.verify_roundtrip {
+            self.verify_roundtrip(&packet, &data_payload);
+        }
+
+        let data_payload = match (data_payload, &self.reference_resolver) {
+            (DataPayload::Reference { uri, size, content_type, checksum }, Some(resolver)) => {
+                match resolver.resolve(&uri, size, &content_type, &checksum) {
+                    Ok(resolved) => resolved,
+                    Err(e) => {
+                        error!(packet_id = %packet.id, "Failed to resolve reference {}: {}", uri, e);
+                        let detail = format!("unresolvable reference: {}", e);
+                        self.dead_letter(Some(packet.id.clone()), &detail, &packet.raw);
+                        self.reject(&packet, ResponseStatus::Rejected(detail));
+                        return;
+                    }
+                }
+            }
+            (data_payload, _) => data_payload,
+        };
+        let data_payload = match (data_payload, self.sensor_round_decimals) {
+            (DataPayload::SensorData { sensor_id, temperature, humidity, pressure }, Some(decimals)) => {
+                DataPayload::SensorData {
+                    sensor_id,
+                    temperature: round_half_even(temperature, decimals),
+                    humidity: round_half_even(humidity, decimals),
+                    pressure: round_half_even(pressure, decimals),
+                }
+            }
+            (data_payload, _) => data_payload,
+        };
+
+        if let Err(reason) = self.should_process(&packet, Some(&data_payload)) {
+            self.skip(packet, reason);
+            return;
+        }
+        if let Err(e) = self.validators.validate(&data_payload) {
+            error!(packet_id = %packet.id, "Packet failed validation: {}", e);
+            self.metrics.validation_failures.fetch_add(1, Ordering::Relaxed);
+            if !self.reject_invalid {
+                self.dead_letter(Some(packet.id.clone()), &format!("validation failed: {}", e), &packet.raw);
+            }
+            self.reject(&packet, ResponseStatus::ValidationError(e));
+            return;
+        }
+
+        let verbose = !self.quiet_types.contains(data_payload.type_name());
+        // Held until the image work below is done, so a burst of images
+        // can't tie up every worker at once.
+        let image_permit = match (&data_payload, &self.image_permits) {
+            (DataPayload::ImageData { .. }, Some(permits)) => Some(permits.acquire(&self.metrics.images_in_progress)),
+            _ => None,
+        };
+        let result = self.hooks.process(&data_payload).unwrap_or_else(|| self.processor.process(&data_payload));
+        if let ResponseStatus::ValidationError(_) = result {
+            self.metrics.validation_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut derived = serde_json::Map::new();
+        if let DataPayload::ImageData { width, height, format, data } = &data_payload {
+            if let Some(max_side) = self.thumbnail_max {
+                match make_thumbnail(*width, *height, format, data, max_side) {
+                    Ok(Some(Value::Object(thumbnail))) => derived.extend(thumbnail),
+                    Ok(_) => {}
+                    Err(e) => warn!(packet_id = %packet.id, "Skipping thumbnail: {}", e),
+                }
+            }
+            if self.image_stats {
+                match image_stats(*width, *height, format, data) {
+                    Ok(stats) => {
+                        derived.insert("stats".to_string(), stats);
+                    }
+                    Err(e) => warn!(packet_id = %packet.id, "Skipping image stats: {}", e),
+                }
+            }
+        }
+        let derived = (!derived.is_empty()).then_some(Value::Object(derived));
+        drop(image_permit);
+        let processing_time = start_time.elapsed().as_millis() as u64;
+        self.metrics.record(&data_payload, processing_time, packet.tenant());
+        let qos = self.response_qos.for_type(data_payload.type_name());
+        let key = routing_key(&data_payload, &packet.id);
+
+        if let (DataPayload::TimeSeries { points, .. }, true) = (&data_payload, self.explode_time_series) {
+            self.publish_points(&packet, &key, points, processing_time, qos, verbose);
+            return;
+        }
+
+        let response = DataResponse::from_outcome(packet.id, result, processing_time)
+            .with_result(derived)
+            .with_routing_key(key)
+            .with_receive_index(packet.receive_index);
+        self.publish_response(&response, Some(data_payload.type_name()), qos, verbose);
+    }
+
+    /// Mirrors a sample of received packets to `data/tap`. Uses
+    /// `try_publish` so a full request queue drops the copy instead of
+    /// holding up processing.
+    fn tap(&self, packet: &FlexiblePacket) {
+        if self.tap_sample <= 0.0 || rand::random::<f64>() >= self.tap_sample {
+            return;
+        }
+        let wanted = self.tap_types.is_empty()
+            || packet.data_type.as_deref().is_some_and(|t| self.tap_types.contains(t));
+        if !wanted {
+            return;
+        }
+        let _ = self.outlet.try_publish("data/tap", QoS::AtMostOnce, false, packet.raw.clone().into_bytes());
+    }
+
+    /// The single gate every skip policy goes through, checked in order; the
+    /// first policy that applies wins. Without a payload, for a packet whose
+    /// payload couldn't be converted, only the policies on the packet itself
+    /// apply.
+    fn should_process(&self, packet: &FlexiblePacket, data_payload: Option<&DataPayload>) -> Result<(), SkipReason> {
+        if self.memory_pressure.load(Ordering::Relaxed) {
+            return Err(SkipReason::MemoryPressure);
+        }
+        self.version_policy.check(packet.protocol_version, packet.version())?;
+        packet.verify_crc32().map_err(SkipReason::ChecksumMismatch)?;
+        if let Some(dedup) = &self.dedup_by_id {
+            if dedup.lock().unwrap().check_and_insert(packet.id.clone()) {
+                return Err(SkipReason::DuplicateId);
+            }
+        }
+        let Some(data_payload) = data_payload else {
+            return Ok(());
+        };
+        if let Some(dedup) = &self.dedup_by_content {
+            if dedup.lock().unwrap().check_and_insert(content_key(data_payload)) {
+                return Err(SkipReason::DuplicateContent);
+            }
+        }
+        if let DataPayload::ImageData { width, height, .. } = data_payload {
+            check_image_dimensions(*width, *height, self.max_image_dim).map_err(SkipReason::ImageTooLarge)?;
+        }
+        if let (DataPayload::LogEntry { level, .. }, Some(min)) = (data_payload, self.min_log_level) {
+            if LogLevel::of(level) < min {
+                return Err(SkipReason::LogLevelFiltered(format!("level {} is below {}", level, min)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a skipped packet and sends whatever its reason calls for:
+    /// duplicates are dropped silently, bad versions are dead-lettered,
+    /// oversized images, memory pressure or a protocol mismatch get a
+    /// rejection, a bad checksum gets a checksum error and filtered log
+    /// entries are answered as such.
+    fn skip(&self, packet: FlexiblePacket, reason: SkipReason) {
+        info!(packet_id = %packet.id, "Skipping packet ({}): {}", reason.as_str(), reason);
+        self.metrics.record_skip(&reason);
+        match reason {
+            SkipReason::UnsupportedVersion(detail) => {
+                self.dead_letter(Some(packet.id), &detail, &packet.raw)
+            }
+            SkipReason::DuplicateId | SkipReason::DuplicateContent => {}
+            SkipReason::ImageTooLarge(detail) => self.reject(&packet, ResponseStatus::Rejected(detail)),
+            SkipReason::MemoryPressure => self.reject(&packet, ResponseStatus::Rejected("memory pressure".to_string())),
+            SkipReason::ProtocolMismatch(detail) => self.reject(&packet, ResponseStatus::Rejected(detail)),
+            SkipReason::ChecksumMismatch(detail) => self.reject(&packet, ResponseStatus::ChecksumError(detail)),
+            // Filtering is what was asked for, so it isn't a failure.
+            SkipReason::LogLevelFiltered(detail) => {
+                self.reject(&packet, ResponseStatus::Ok(format!("Log entry filtered: {}", detail)))
+            }
+        }
+    }
+
+    /// Sends one response per time-series point, each carrying the point and
+    /// the id of the packet it came from.
+    fn publish_points(
+        &self,
+        packet: &FlexiblePacket,
+        key: &str,
+        points: &[TimeSeriesPoint],
+        processing_time: u64,
+        qos: QoS,
+        verbose: bool,
+    ) {
+        if points.is_empty() {
+            info!(packet_id = %packet.id, "Time series has no points, nothing to emit");
+            return;
+        }
+        for (index, point) in points.iter().enumerate() {
+            let status = format!("Time series point processed: value = {}", format_float(point.value, self.float_digits));
+            let response = DataResponse::from_outcome(packet.id.clone(), status, processing_time)
+                .with_result(Some(serde_json::json!({
+                    "parent_packet_id": packet.id,
+                    "index": index,
+                    "timestamp": point.timestamp,
+                    "value": point.value,
+                })))
+                .with_routing_key(key)
+                .with_receive_index(packet.receive_index);
+            self.publish_response(&response, Some("time_series"), qos, verbose);
+        }
+        self.metrics.exploded_points.fetch_add(points.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Flags conversions that lost or altered data, which would point at a
+    /// serde bug or a lossy helper struct in `convert_payload`.
+    fn verify_roundtrip(&self, packet: &FlexiblePacket, data_payload: &DataPayload) {
+        match serde_json::to_value(data_payload) {
+            Ok(reencoded) if json_equivalent(&reencoded, &packet.payload) => {}
+            Ok(reencoded) => {
+                warn!(
+                    packet_id = %packet.id,
+                    "Round-trip mismatch: received {} but re-encoded {}",
+                    packet.payload, reencoded
+                );
+                self.metrics.roundtrip_mismatches.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                error!(packet_id = %packet.id, "Round-trip re-encoding failed: {:?}", e);
+                self.metrics.roundtrip_mismatches.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn inject_response_delay(&self) {
+        let jitter_ms = self.response_delay_jitter.as_millis() as u64;
+        let jitter = match jitter_ms {
+            0 => Duration::ZERO,
+            max => Duration::from_millis(rand::random::<u64>() % (max + 1)),
+        };
+        let delay = self.response_delay + jitter;
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Serializes a response, dropping its optional fields if the result
+    /// would exceed `max_response_bytes`.
+    fn serialize_response(&self, response: &DataResponse) -> serde_json::Result<String> {
+        let payload = self.response_schema.serialize(response)?;
+        match self.max_response_bytes {
+            Some(max) if payload.len() > max => {
+                let mut truncated = response.clone();
+                truncated.truncate();
+                let payload = self.response_schema.serialize(&truncated)?;
+                warn!(
+                    packet_id = %response.packet_id,
+                    "Response exceeded {} bytes, dropped optional fields ({} bytes now)",
+                    max, payload.len()
+                );
+                Ok(payload)
+            }
+            _ => Ok(payload),
+        }
+    }
+
+    /// Tells the master a packet arrived, as soon as it has parsed and
+    /// before it waits for a worker. Never blocks, so it can run on the
+    /// connection thread; an ack that doesn't fit in the request queue is
+    /// dropped rather than holding up the connection.
+    fn ack(&self, packet: &FlexiblePacket) {
+        if !self.send_acks {
+            return;
+        }
+        let ack = Ack { packet_id: packet.id.clone(), received_at: Utc::now() };
+        match serde_json::to_vec(&ack) {
+            Ok(payload) => match self.outlet.try_publish("data/ack", QoS::AtLeastOnce, false, payload) {
+                Ok(()) => self.metrics.record_publish_attempt(QoS::AtLeastOnce),
+                Err(e) => warn!(packet_id = %packet.id, "Dropping ack: {}", e),
+            },
+            Err(e) => error!("Failed to serialize ack: {:?}", e),
+        }
+    }
+
+    /// Announces this slave on its [`SlaveOnline::topic`]. Sent on every
+    /// connect, since the broker may have lost its retained messages while
+    /// the slave was away. Never blocks, so it can run on the thread driving
+    /// the connection.
+    fn announce(&self, announcement: &SlaveOnline) {
+        let topic = SlaveOnline::topic(&announcement.slave_id);
+        match serde_json::to_vec(announcement) {
+            Ok(payload) => match self.outlet.try_publish(&topic, QoS::AtLeastOnce, true, payload) {
+                Ok(()) => self.metrics.record_publish_attempt(QoS::AtLeastOnce),
+                Err(e) => error!("Failed to announce on {}: {}", topic, e),
+            },
+            Err(e) => error!("Failed to serialize announcement: {:?}", e),
+        }
+    }
+
+    /// Clears the retained announcement on a graceful shutdown.
+    fn withdraw(&self, slave_id: &str) {
+        let topic = SlaveOnline::topic(slave_id);
+        if let Err(e) = self.publish(&topic, QoS::AtLeastOnce, true, Vec::new()) {
+            error!("Failed to clear {}: {}", topic, e);
+        }
+    }
+
+    /// Answers a packet that was refused or filtered without being processed.
+    fn reject(&self, packet: &FlexiblePacket, status: ResponseStatus) {
+        let response = DataResponse::from_outcome(packet.id.clone(), status, 0)
+            .with_receive_index(packet.receive_index);
+        self.publish_response(&response, packet.data_type.as_deref(), self.response_qos.default, true);
+    }
+
+    /// Flags a response emitted after one for a later-received packet.
+    fn check_receive_order(&self, response: &DataResponse) {
+        let Some(index) = response.receive_index else {
+            return;
+        };
+        let latest = self.last_emitted_index.fetch_max(index, Ordering::Relaxed);
+        if index < latest {
+            warn!(
+                packet_id = %response.packet_id,
+                "Response (receive index {}) emitted after receive index {}",
+                index, latest
+            );
+            self.metrics.out_of_order_responses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Publishes through the outlet, counting it towards `publish_attempts`
+    /// when it went to a broker.
+    fn publish(&self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<(), String> {
+        self.outlet.publish(topic, qos, retain, payload)?;
+        if !matches!(self.outlet, Outlet::File(..)) {
+            self.metrics.record_publish_attempt(qos);
+        }
+        Ok(())
+    }
+
+    /// Sends a response, and stores it when `--db` is set. `payload_type`
+    /// is `None` for messages that never became a packet.
+    fn publish_response(&self, response: &DataResponse, payload_type: Option<&str>, qos: QoS, verbose: bool) {
+        if self.verify_receive_order {
+            self.check_receive_order(response);
+        }
+        if let Some(store) = &self.response_store {
+            store.record(response, payload_type);
+        }
+        let topic = match (&response.routing_key, self.routing_key_in_topic) {
+            (Some(key), true) => format!("{}/{}", self.topics.response, topic_segment(key)),
+            _ => self.topics.response.clone(),
+        };
+        if let Ok(response_payload) = self.serialize_response(response) {
+            self.inject_response_delay();
+            if verbose {
+                debug!("Sending response: {}", response_payload);
+            }
+            let response_payload = match self.wire_format.encode_json(response_payload) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!("Failed to encode response: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = self.publish(&topic, qos, false, response_payload) {
+                error!("Failed to send response: {}", e);
+            } else if verbose {
+                // Only queued locally; delivery shows up in publish_confirms.
+                debug!("Response queued for sending");
+            }
+        }
+    }
+
+    fn dead_letter(&self, packet_id: Option<String>, reason: &str, raw: &str) {
+        self.publish_dead_letter(DeadLetter {
+            packet_id,
+            reason: reason.to_string(),
+            raw: raw.to_string(),
+            encoding: None,
+        });
+    }
+
+    /// Dead-letters a payload that isn't text, base64-encoding the raw bytes.
+    fn dead_letter_bytes(&self, packet_id: Option<String>, reason: &str, raw: &[u8]) {
+        self.publish_dead_letter(DeadLetter {
+            packet_id,
+            reason: reason.to_string(),
+            raw: base64::engine::general_purpose::STANDARD.encode(raw),
+            encoding: Some("base64".to_string()),
+        });
+    }
+
+    fn publish_dead_letter(&self, dead_letter: DeadLetter) {
+        let Some(topic) = &self.dead_letter_topic else {
+            return;
+        };
+        match serde_json::to_string(&dead_letter) {
+            Ok(payload) => {
+                if let Err(e) = self.publish(topic, QoS::AtLeastOnce, false, payload.into_bytes()) {
+                    error!("Failed to publish dead letter: {}", e);
+                }
+            }
+            Err(e) => error!("Failed to serialize dead letter: {:?}", e),
+        }
+    }
+}
+
+/// Compares two JSON values semantically: object key order is ignored and
+/// numbers are compared by value, so `42` and `42.0` match.
+fn json_equivalent(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64() == y.as_f64(),
+        (Value::Array(xs), Value::Array(ys)) => {
+            xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| json_equivalent(x, y))
+        }
+        (Value::Object(xs), Value::Object(ys)) => {
+            xs.len() == ys.len()
+                && xs.iter().all(|(key, x)| ys.get(key).is_some_and(|y| json_equivalent(x, y)))
+        }
+        _ => a == b,
+    }
+}
+
+/// A counting semaphore; each permit is returned when its guard drops.
+struct Semaphore {
+    capacity: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    in_use: &'a AtomicU64,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self { capacity: permits, available: Mutex::new(permits), released: Condvar::new() }
+    }
+
+    /// Blocks until a permit is free. `in_use` tracks how many are held.
+    fn acquire<'a>(&'a self, in_use: &'a AtomicU64) -> SemaphorePermit<'a> {
+        let available = self.available.lock().unwrap();
+        let mut available = self.released.wait_while(available, |n| *n == 0).unwrap();
+        *available -= 1;
+        in_use.fetch_add(1, Ordering::Relaxed);
+        SemaphorePermit { semaphore: self, in_use }
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+/// Keys seen within a time window, used to skip duplicate work. Bounded so a
+/// flood of unique keys can't grow it without limit.
+struct DedupCache {
+    window: Duration,
+    seen: HashMap<String, Instant>,
+}
+
+impl DedupCache {
+    const MAX_ENTRIES: usize = 10_000;
+
+    fn new(window: Duration) -> Self {
+        Self { window, seen: HashMap::new() }
+    }
+
+    /// Records `key` and returns whether it was already seen within the window.
+    fn check_and_insert(&mut self, key: String) -> bool {
+        let now = Instant::now();
+        if self.seen.get(&key).is_some_and(|seen_at| now.duration_since(*seen_at) < self.window) {
+            return true;
+        }
+        if self.seen.len() >= Self::MAX_ENTRIES {
+            let window = self.window;
+            self.seen.retain(|_, seen_at| now.duration_since(*seen_at) < window);
+        }
+        if self.seen.len() >= Self::MAX_ENTRIES {
+            let oldest = self.seen
+                .iter()
+                .min_by_key(|(_, seen_at)| **seen_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key, now);
+        false
+    }
+}
+
+/// Hashes a payload by content rather than identity: its canonical bytes,
+/// with every float rounded to 9 significant digits so representation noise
+/// doesn't defeat the match.
+fn content_key(payload: &DataPayload) -> String {
+    fn round_floats(value: &mut Value) {
+        match value {
+            Value::Number(n) if n.is_f64() => {
+                let rounded = n.as_f64().map(|f| format!("{:.8e}", f).parse::<f64>().unwrap_or(f));
+                if let Some(number) = rounded.and_then(serde_json::Number::from_f64) {
+                    *n = number;
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(round_floats),
+            Value::Object(map) => map.values_mut().for_each(round_floats),
+            _ => {}
+        }
+    }
+
+    let mut value = serde_json::to_value(payload).unwrap_or(Value::Null);
+    round_floats(&mut value);
+    format!("{:016x}", fnv1a(&canonical_value_bytes(&value)))
+}
+
+/// Holds packets back for a short window after a reconnect so that replayed
+/// packets, which carry older per-master sequence numbers, are processed
+/// ahead of new ones that overtook them. The trade-off is up to `window` of
+/// extra latency for everything that arrives right after a reconnect. This
+/// is best effort: anything arriving after the window is processed as it
+/// comes. A zero window disables it.
+struct ReorderBuffer {
+    window: Duration,
+    max_held: usize,
+    deadline: Option<Instant>,
+    held: Vec<FlexiblePacket>,
+}
+
+impl ReorderBuffer {
+    fn new(window: Duration, max_held: usize) -> Self {
+        Self { window, max_held: max_held.max(1), deadline: None, held: Vec::new() }
+    }
+
+    fn start(&mut self) {
+        if !self.window.is_zero() {
+            info!("Holding new packets for {:?} to restore order after reconnect", self.window);
+            self.deadline = Some(Instant::now() + self.window);
+        }
+    }
+
+    /// Time left in the current window, or `None` when not buffering.
+    fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Keeps the packet while a window is open; otherwise hands it back.
+    fn hold(&mut self, packet: FlexiblePacket) -> Option<FlexiblePacket> {
+        if self.deadline.is_none() {
+            return Some(packet);
+        }
+        self.held.push(packet);
+        None
+    }
+
+    fn is_full(&self) -> bool {
+        self.held.len() >= self.max_held
+    }
+
+    /// Closes the window and returns what was held, lowest sequence number
+    /// first. The sort is stable, so packets without a sequence number keep
+    /// their arrival order at the end.
+    fn release(&mut self) -> Vec<FlexiblePacket> {
+        self.deadline = None;
+        let mut held = std::mem::take(&mut self.held);
+        held.sort_by_key(|packet| packet.seq().unwrap_or(u64::MAX));
+        held
+    }
+}
+
+/// Warm-standby coordination (`LEADER_ELECTION=1`). The leader republishes
+/// a retained claim with its id on `slaves/leader` every heartbeat; the
+/// others stay connected but drop requests. A standby that sees no claim
+/// for `timeout` claims leadership itself. If two slaves both think they
+/// lead, the one with the lower id keeps it and the other steps down.
+struct LeaderElection {
+    slave_id: String,
+    heartbeat: Duration,
+    timeout: Duration,
+    state: Mutex<ElectionState>,
+}
+
+struct ElectionState {
+    leader: bool,
+    /// When another slave's claim was last seen.
+    last_claim: Instant,
+    last_heartbeat: Option<Instant>,
+}
+
+impl LeaderElection {
+    const TOPIC: &'static str = "slaves/leader";
+
+    fn from_env(slave_id: &str) -> Option<Self> {
+        if env_var::<u8>("LEADER_ELECTION").unwrap_or(0) != 1 {
+            return None;
+        }
+        Some(Self {
+            slave_id: slave_id.to_string(),
+            heartbeat: Duration::from_secs(env_var("LEADER_HEARTBEAT_SECS").unwrap_or(2).max(1)),
+            timeout: Duration::from_secs(env_var("LEADER_TIMEOUT_SECS").unwrap_or(10).max(1)),
+            // Starting as a standby gives a running leader's retained claim
+            // and heartbeats a full timeout to show up.
+            state: Mutex::new(ElectionState { leader: false, last_claim: Instant::now(), last_heartbeat: None }),
+        })
+    }
+
+    fn is_leader(&self) -> bool {
+        self.state.lock().unwrap().leader
+    }
+
+    /// Handles a claim seen on the leader topic. An empty claim means the
+    /// leader resigned, so there's no need to wait out the timeout.
+    fn observe(&self, claimant: &str) {
+        if claimant == self.slave_id {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if claimant.is_empty() {
+            if !state.leader {
+                state.last_claim = Instant::now().checked_sub(self.timeout).unwrap_or_else(Instant::now);
+            }
+            return;
+        }
+        state.last_claim = Instant::now();
+        if state.leader {
+            if claimant < self.slave_id.as_str() {
+                info!("{} also claims leadership and has the lower id; standing by", claimant);
+                state.leader = false;
+            } else {
+                // Reassert on the next tick so the other side steps down.
+                state.last_heartbeat = None;
+            }
+        }
+    }
+
+    /// Called periodically from the main thread: sends the leader's
+    /// heartbeat, or takes over once the leader has gone quiet.
+    fn tick(&self, handler: &MessageHandler) {
+        let mut state = self.state.lock().unwrap();
+        if !state.leader {
+            if state.last_claim.elapsed() < self.timeout {
+                return;
+            }
+            info!("No leader heartbeat for {:?}; taking over", self.timeout);
+            state.leader = true;
+            state.last_heartbeat = None;
+        }
+        if state.last_heartbeat.is_some_and(|sent| sent.elapsed() < self.heartbeat) {
+            return;
+        }
+        state.last_heartbeat = Some(Instant::now());
+        drop(state);
+        if let Err(e) = handler.publish(Self::TOPIC, QoS::AtLeastOnce, true, self.slave_id.clone().into_bytes()) {
+            error!("Failed to publish leader heartbeat: {}", e);
+        }
+    }
+
+    /// Clears the retained claim on shutdown so a standby takes over at once.
+    fn resign(&self, handler: &MessageHandler) {
+        if !self.is_leader() {
+            return;
+        }
+        if let Err(e) = handler.publish(Self::TOPIC, QoS::AtLeastOnce, true, Vec::new()) {
+            error!("Failed to resign leadership: {}", e);
+        }
+    }
+}
+
+/// Tracks the last sequence number seen from each master, in arrival order,
+/// to count gaps (skipped numbers) and reorderings (a number lower than one
+/// already seen). A master that restarts gets a new id and starts again at 1,
+/// and a `seq` of 1 is always taken as a restart rather than a reordering.
+struct SequenceTracker {
+    last_seen: HashMap<String, (u64, Instant)>,
+}
+
+impl SequenceTracker {
+    const MAX_MASTERS: usize = 1024;
+
+    fn new() -> Self {
+        Self { last_seen: HashMap::new() }
+    }
+
+    fn observe(&mut self, packet: &FlexiblePacket, metrics: &ProcessingMetrics) {
+        // Replayed packets legitimately repeat old sequence numbers.
+        if packet.is_replay() {
+            return;
+        }
+        let Some(metadata) = &packet.metadata else { return };
+        let (Some(master_id), Some(seq)) = (&metadata.master_id, &metadata.seq) else { return };
+        let Ok(seq) = seq.parse::<u64>() else { return };
+
+        if !self.last_seen.contains_key(master_id) && self.last_seen.len() >= Self::MAX_MASTERS {
+            let stalest = self.last_seen
+                .iter()
+                .min_by_key(|(_, (_, seen_at))| *seen_at)
+                .map(|(id, _)| id.clone());
+            if let Some(id) = stalest {
+                self.last_seen.remove(&id);
+            }
+        }
+
+        match self.last_seen.get(master_id).map(|(last, _)| *last) {
+            Some(last) if seq == 1 && last > 1 => {
+                info!("Master {} restarted its sequence (last seq {})", master_id, last);
+            }
+            Some(last) if seq > last + 1 => {
+                let missing = seq - last - 1;
+                warn!("Sequence gap from {}: {} missing between {} and {}", master_id, missing, last, seq);
+                metrics.seq_gaps.fetch_add(missing, Ordering::Relaxed);
+            }
+            Some(last) if seq <= last => {
+                warn!("Out-of-order packet from {}: seq {} after {}", master_id, seq, last);
+                metrics.seq_reorders.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            _ => {}
+        }
+        self.last_seen.insert(master_id.clone(), (seq, Instant::now()));
+    }
+}
+
+/// Worker threads that each drain their own bounded queue. Packets with a
+/// partition key always go to the same worker, so per-key state can live on
+/// that worker's thread without a shared lock; packets without one are spread
+/// round-robin. A full queue blocks the connection thread (backpressure).
+struct WorkerPool {
+    queues: Vec<SyncSender<FlexiblePacket>>,
+    /// Shared queue of the image lane, when `MAX_CONCURRENT_IMAGES` is set.
+    images: Option<SyncSender<FlexiblePacket>>,
+    next: usize,
+    handler: Arc<MessageHandler>,
+}
+
+impl WorkerPool {
+    /// Starts `workers` general workers and, with `image_lanes`, that many
+    /// extra threads that take only image packets. Images then queue for the
+    /// lane instead of holding a general worker while they wait their turn.
+    fn start(handler: Arc<MessageHandler>, workers: usize, queue_capacity: usize, image_lanes: Option<usize>) -> Self {
+        let queues = (0..workers)
+            .map(|index| {
+                let (sender, receiver) = mpsc::sync_channel::<FlexiblePacket>(queue_capacity);
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    // Created here so it samples this worker's CPU time.
+                    let mut throttle = CpuThrottle::from_env();
+                    for packet in receiver {
+                        work(&handler, packet, &mut throttle);
+                    }
+                    info!("Worker {} stopped", index);
+                });
+                sender
+            })
+            .collect();
+        let images = image_lanes.map(|lanes| {
+            let (sender, receiver) = mpsc::sync_channel::<FlexiblePacket>(queue_capacity);
+            let receiver = Arc::new(Mutex::new(receiver));
+            for index in 0..lanes {
+                let handler = handler.clone();
+                let receiver = receiver.clone();
+                thread::spawn(move || {
+                    let mut throttle = CpuThrottle::from_env();
+                    loop {
+                        let next = receiver.lock().unwrap().recv();
+                        let Ok(packet) = next else { break };
+                        handler.metrics.images_in_progress.fetch_add(1, Ordering::Relaxed);
+                        work(&handler, packet, &mut throttle);
+                        handler.metrics.images_in_progress.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    info!("Image lane {} stopped", index);
+                });
+            }
+            sender
+        });
+        Self { queues, images, next: 0, handler }
+    }
+
+    fn dispatch(&mut self, packet: FlexiblePacket) {
+        // Images skip the partition key: the lane has no per-key ordering.
+        if let (Some(images), true) = (&self.images, packet.payload.get("ImageData").is_some()) {
+            self.handler.in_flight.fetch_add(1, Ordering::SeqCst);
+            if images.send(packet).is_err() {
+                self.handler.in_flight.fetch_sub(1, Ordering::SeqCst);
+                warn!("Image lane has stopped, dropping packet");
+            }
+            return;
+        }
+        let index = match packet.partition_key() {
+            Some(key) => worker_for_key(key, self.queues.len()),
+            None => {
+                let index = self.next;
+                self.next = (self.next + 1) % self.queues.len();
+                index
+            }
+        };
+        self.handler.in_flight.fetch_add(1, Ordering::SeqCst);
+        let sent = match self.queues[index].try_send(packet) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(packet)) => {
+                warn!("Worker {} queue is full, waiting for room", index);
+                self.queues[index].send(packet).map_err(|_| ())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(()),
+        };
+        if sent.is_err() {
+            self.handler.in_flight.fetch_sub(1, Ordering::SeqCst);
+            warn!("Worker {} has stopped, dropping packet", index);
+        }
+    }
+}
+
+/// Handles one packet on a pool thread, keeping the busy and in-flight
+/// counts and the CPU throttle up to date.
+fn work(handler: &MessageHandler, packet: FlexiblePacket, throttle: &mut Option<CpuThrottle>) {
+    let busy_start = Instant::now();
+    handler.metrics.busy_workers.fetch_add(1, Ordering::Relaxed);
+    handler.handle_packet(packet);
+    handler.metrics.busy_workers.fetch_sub(1, Ordering::Relaxed);
+    handler.mark_active();
+    handler.in_flight.fetch_sub(1, Ordering::SeqCst);
+    if let Some(throttle) = throttle.as_mut() {
+        let slept = throttle.after_message(busy_start.elapsed());
+        handler.metrics.throttle_sleep_ms.fetch_add(slept.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Periodically samples how many workers are busy, to help tune
+/// `SLAVE_WORKERS`. The pool counts as saturated when every worker is busy
+/// and packets are still waiting in the queues.
+fn spawn_saturation_sampler(handler: Arc<MessageHandler>, workers: usize, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let busy = handler.metrics.busy_workers.load(Ordering::Relaxed);
+        let in_flight = handler.in_flight.load(Ordering::SeqCst) as u64;
+        let metrics = &handler.metrics;
+        metrics.worker_samples.fetch_add(1, Ordering::Relaxed);
+        metrics.busy_worker_sum.fetch_add(busy, Ordering::Relaxed);
+        if busy >= workers as u64 && in_flight > busy {
+            metrics.saturated_ms.fetch_add(interval.as_millis() as u64, Ordering::Relaxed);
+            metrics.saturated_samples.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Maps a partition key onto one of `workers` buckets with jump consistent
+/// hashing (Lamping & Veach), so a key always lands on the same worker and
+/// changing the worker count moves as few keys as possible.
+fn worker_for_key(key: &str, workers: usize) -> usize {
+    let mut hash = fnv1a(key.as_bytes());
+    let (mut bucket, mut next) = (-1i64, 0i64);
+    while next < workers as i64 {
+        bucket = next;
+        hash = hash.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((hash >> 33) + 1) as f64)) as i64;
+    }
+    bucket.max(0) as usize
+}
+
+/// One representative payload per variant, for the self-test.
+fn self_test_samples() -> Vec<DataPayload> {
+    vec![
+        DataPayload::Text("self-test".to_string()),
+        DataPayload::Number(42.into()),
+        DataPayload::Coordinates { x: 1.0, y: 2.0, z: 3.0, system: CoordinateSystem::Cartesian },
+        DataPayload::SensorData {
+            sensor_id: "SELF_TEST".to_string(),
+            temperature: 21.5,
+            humidity: 40.0,
+            pressure: 1013.0,
+        },
+        DataPayload::ImageData {
+            width: 2,
+            height: 2,
+            format: "RGB".to_string(),
+            data: vec![0; 12],
+        },
+        DataPayload::LogEntry {
+            level: "INFO".to_string(),
+            message: "self-test".to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+        },
+        DataPayload::TimeSeries {
+            series_id: "SELF_TEST".to_string(),
+            points: vec![TimeSeriesPoint { timestamp: Utc::now().to_rfc3339(), value: 1.0 }],
+        },
+        DataPayload::Reference {
+            uri: "file:///dev/null".to_string(),
+            size: 0,
+            content_type: "text/plain".to_string(),
+            checksum: "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+        },
+        DataPayload::Json(serde_json::json!({ "self_test": true })),
+    ]
+}
+
+/// Runs each sample payload through conversion and processing, the same
+/// path a real packet takes. Nothing is recorded in the live metrics.
+fn self_test(handler: &MessageHandler) -> Vec<SelfTestResult> {
+    self_test_samples()
+        .into_iter()
+        .map(|sample| {
+            let start = Instant::now();
+            let outcome = serde_json::to_value(&sample)
+                .map_err(|e| format!("failed to encode sample: {}", e))
+                .and_then(|value| convert_payload(&value).ok_or_else(|| "conversion failed".to_string()))
+                .and_then(|converted| {
+                    if converted.type_name() != sample.type_name() {
+                        return Err(format!("converted to {} instead", converted.type_name()));
+                    }
+                    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handler.processor.process(&converted).to_string()))
+                        .map_err(|_| "processing panicked".to_string())
+                });
+            let duration_us = start.elapsed().as_micros() as u64;
+            let (passed, detail) = match outcome {
+                Ok(status) => (true, status),
+                Err(e) => (false, e),
+            };
+            SelfTestResult { payload_type: sample.type_name().to_string(), passed, detail, duration_us }
+        })
+        .collect()
+}
+
+fn handle_command(handler: &MessageHandler, slave_id: &str, payload: &[u8]) {
+    let command = match serde_json::from_slice::<Command>(payload) {
+        Ok(command) => command,
+        Err(e) => {
+            warn!("Ignoring malformed command: {}", e);
+            return;
+        }
+    };
+    match command.action.as_str() {
+        "self_test" => {
+            let results = self_test(handler);
+            let report = SelfTestReport {
+                slave_id: slave_id.to_string(),
+                ran_at: Utc::now(),
+                passed: results.iter().all(|r| r.passed),
+                results,
+            };
+            info!("Self-test {}", if report.passed { "passed" } else { "FAILED" });
+            match serde_json::to_string(&report) {
+                Ok(report) => {
+                    if let Err(e) = handler.publish("slave/selftest", QoS::AtLeastOnce, false, report.into_bytes()) {
+                        error!("Failed to publish self-test report: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to serialize self-test report: {:?}", e),
+            }
+        }
+        other => warn!("Ignoring unknown command action: {}", other),
+    }
+}
+
+/// Prints totals, the average processing time and the per-type counts.
+fn print_metrics_summary(metrics: &ProcessingMetrics) {
+    let snapshot = metrics.snapshot();
+    let average_ms = snapshot.total_processing_time_ms.checked_div(snapshot.processed_count).unwrap_or(0);
+    let breakdown: Vec<String> = snapshot
+        .type_counts()
+        .iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(name, count)| format!("{}={}", name, count))
+        .collect();
+    info!(
+        "Metrics: {} processed, avg {} ms; by type: {}",
+        snapshot.processed_count,
+        average_ms,
+        if breakdown.is_empty() { "none".to_string() } else { breakdown.join(", ") }
+    );
+}
+
+/// Renders the counters in the Prometheus text exposition format. The
+/// per-tenant series cover every tracked tenant, or only `tenant` if given.
+fn prometheus_text(snapshot: &MetricsSnapshot, tenant: Option<&str>) -> String {
+    let mut out = String::new();
+    let mut counter = |name: &str, help: &str, value: u64| {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, value));
+    };
+    counter("slave_processed_total", "Packets processed.", snapshot.processed_count);
+    counter("slave_processing_time_ms_total", "Time spent processing packets, in milliseconds.", snapshot.total_processing_time_ms);
+    counter("slave_validation_failures_total", "Packets that failed validation.", snapshot.validation_failures);
+    counter("slave_publish_attempts_total", "QoS 1/2 publishes handed to the client.", snapshot.publish_attempts);
+    counter("slave_publish_confirms_total", "Publishes acknowledged by the broker.", snapshot.publish_confirms);
+    counter("slave_reconnects_total", "Successful broker connects after the first one.", snapshot.connection.reconnects);
+    let mut gauge = |name: &str, help: &str, value: u64| {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, value));
+    };
+    gauge("slave_connected", "1 while connected to the broker, 0 otherwise.", u64::from(snapshot.connection.connected));
+    gauge(
+        "slave_connected_uptime_seconds",
+        "Total time spent connected to the broker, including the current connection.",
+        snapshot.connection.connected_uptime_secs,
+    );
+    out.push_str("# HELP slave_payload_total Packets processed by payload type.\n# TYPE slave_payload_total counter\n");
+    for (name, count) in snapshot.type_counts() {
+        out.push_str(&format!("slave_payload_total{{type=\"{}\"}} {}\n", name, count));
+    }
+    let tenants: Vec<(&String, &TenantMetrics)> =
+        snapshot.tenants.iter().filter(|(name, _)| tenant.is_none_or(|tenant| tenant == name.as_str())).collect();
+    out.push_str("# HELP slave_tenant_processed_total Packets processed per tenant.\n# TYPE slave_tenant_processed_total counter\n");
+    for (name, metrics) in &tenants {
+        out.push_str(&format!("slave_tenant_processed_total{{tenant=\"{}\"}} {}\n", label_value(name), metrics.processed_count));
+    }
+    out.push_str(concat!(
+        "# HELP slave_tenant_processing_time_ms_total Time spent processing each tenant's packets, in milliseconds.\n",
+        "# TYPE slave_tenant_processing_time_ms_total counter\n",
+    ));
+    for (name, metrics) in &tenants {
+        out.push_str(&format!(
+            "slave_tenant_processing_time_ms_total{{tenant=\"{}\"}} {}\n",
+            label_value(name),
+            metrics.total_processing_time_ms
+        ));
+    }
+    out
+}
+
+/// Escapes a Prometheus label value: backslashes, quotes and newlines.
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` on `port` from its own thread. Each scrape takes a
+/// fresh snapshot; `?tenant=<name>` limits the per-tenant series to that
+/// tenant. Anything else gets a 404.
+fn serve_metrics(port: u16, metrics: Arc<ProcessingMetrics>) -> std::io::Result<()> {
+    let listener = std::net::TcpListener::bind(("0.0.0.0", port))?;
+    info!("Serving Prometheus metrics on port {}", port);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let mut request_line = String::new();
+            if BufReader::new(&stream).read_line(&mut request_line).is_err() {
+                continue;
+            }
+            let mut parts = request_line.split_whitespace();
+            let (method, target) = (parts.next(), parts.next().unwrap_or_default());
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            let tenant = query.split('&').find_map(|pair| pair.strip_prefix("tenant="));
+            let (status, body) = match (method, path) {
+                (Some("GET"), "/metrics") => ("200 OK", prometheus_text(&metrics.snapshot(), tenant)),
+                _ => ("404 Not Found", "not found\n".to_string()),
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+        }
+    });
+    Ok(())
+}
+
+fn publish_metrics(handler: &MessageHandler) {
+    match serde_json::to_string(&handler.metrics.snapshot()) {
+        Ok(snapshot) => {
+            if let Err(e) = handler.publish("data/metrics", QoS::AtLeastOnce, false, snapshot.into_bytes()) {
+                error!("Failed to publish metrics: {}", e);
+            }
+        }
+        Err(e) => error!("Failed to serialize metrics: {:?}", e),
+    }
+}
+
+/// Answers a status request with the current counters.
+fn publish_status(handler: &MessageHandler, slave_id: &str, started: Instant) {
+    let status = SlaveStatus {
+        slave_id: slave_id.to_string(),
+        uptime_secs: started.elapsed().as_secs(),
+        metrics: handler.metrics.snapshot(),
+    };
+    match serde_json::to_string(&status) {
+        Ok(status) => {
+            if let Err(e) = handler.publish(SlaveStatus::RESPONSE_TOPIC, QoS::AtLeastOnce, false, status.into_bytes()) {
+                error!("Failed to publish status: {}", e);
+            }
+        }
+        Err(e) => error!("Failed to serialize status: {:?}", e),
+    }
+}
+
+/// Publishes a [`Heartbeat`] on [`Heartbeat::TOPIC`], telling masters and
+/// balancers this slave is alive and when to expect the next one.
+fn publish_heartbeat(handler: &MessageHandler, slave_id: &str, started: Instant, interval: Duration) {
+    let heartbeat = Heartbeat {
+        slave_id: slave_id.to_string(),
+        uptime_secs: started.elapsed().as_secs(),
+        processed_count: handler.metrics.processed_count.load(Ordering::Relaxed),
+        interval_secs: interval.as_secs(),
+    };
+    match serde_json::to_vec(&heartbeat) {
+        Ok(payload) => {
+            if let Err(e) = handler.publish(Heartbeat::TOPIC, QoS::AtMostOnce, false, payload) {
+                error!("Failed to publish heartbeat: {}", e);
+            }
+        }
+        Err(e) => error!("Failed to serialize heartbeat: {:?}", e),
+    }
+}
+
+/// Publishes the lifetime processed count as a bare integer, retained so a
+/// late subscriber to `masterslave/slaves/+/processed` sees it immediately.
+fn publish_processed_count(handler: &MessageHandler, slave_id: &str) {
+    let topic = format!("masterslave/slaves/{}/processed", slave_id);
+    let count = handler.metrics.processed_count.load(Ordering::Relaxed).to_string();
+    if let Err(e) = handler.publish(&topic, QoS::AtLeastOnce, true, count.into_bytes()) {
+        error!("Failed to publish processed count: {}", e);
+    }
+}
+
+/// Graceful shutdown, shared by SIGINT and SIGTERM: stop taking new work, let
+/// the workers finish what they already have, announce that this slave is
+/// going offline, then disconnect once the queued responses have been flushed.
+/// Gives up on whatever is left once `grace` has elapsed.
+fn drain(
+    client: &Client,
+    handler: &MessageHandler,
+    connection_thread: JoinHandle<()>,
+    slave_id: &str,
+    grace: Duration,
+) {
+    let deadline = Instant::now() + grace;
+    info!("Shutdown requested, draining within {:?}", grace);
+
+    for topic in [handler.topics.request.clone(), handler.topics.direct_request(slave_id)] {
+        if let Err(e) = client.unsubscribe(topic.as_str()) {
+            error!("Failed to unsubscribe from {}: {:?}", topic, e);
+        }
+    }
+    info!(
+        "Stopped accepting requests, waiting for {} in-flight packet(s)",
+        handler.in_flight.load(Ordering::SeqCst)
+    );
+    while handler.in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    match handler.in_flight.load(Ordering::SeqCst) {
+        0 => info!("All in-flight packets processed"),
+        left => warn!("Grace period expired with {} packet(s) still in flight", left),
+    }
+
+    publish_metrics(handler);
+    handler.withdraw(slave_id);
+    if let Err(e) = handler.publish(Heartbeat::OFFLINE_TOPIC, QoS::AtLeastOnce, false, slave_id.as_bytes().to_vec()) {
+        error!("Failed to publish offline status: {}", e);
+    }
+    if let Some(store) = &handler.response_store {
+        store.close();
+    }
+    // The disconnect is queued behind any pending responses, so they go out first.
+    info!("Flushing responses and disconnecting");
+    if let Err(e) = client.disconnect() {
+        error!("Failed to disconnect: {:?}", e);
+    }
+    while !connection_thread.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    if connection_thread.is_finished() {
+        info!("Shutdown complete");
+    } else {
+        warn!("Grace period expired before the connection closed");
+    }
+}
+
+/// Makes a value safe to use as a single topic level by replacing the
+/// separator and wildcard characters.
+fn topic_segment(value: &str) -> String {
+    value.replace(['/', '+', '#'], "_")
+}
+
+/// Whether a payload looks like newline-delimited JSON: more than one
+/// non-empty line, the first of which is a complete JSON value on its own.
+/// A pretty-printed single object fails the second check.
+fn is_ndjson(payload: &str) -> bool {
+    let mut lines = payload.lines().map(str::trim).filter(|line| !line.is_empty());
+    match (lines.next(), lines.next()) {
+        (Some(first), Some(_)) => serde_json::from_str::<Value>(first).is_ok(),
+        _ => false,
+    }
+}
+
+/// Options taken from the command line after the broker flags.
+pub struct SlaveArgs {
+    /// `--input` and `--output` files for an offline run.
+    offline: Option<(String, String)>,
+    /// How often to print a metrics summary; `None` when disabled with 0.
+    metrics_print_interval: Option<Duration>,
+    format: WireFormat,
+    /// Overrides `SLAVE_WORKERS`.
+    workers: Option<usize>,
+    /// `--metrics-port`: serve Prometheus metrics on this port.
+    metrics_port: Option<u16>,
+    /// `--heartbeat-secs`; `None` when disabled with 0.
+    heartbeat_interval: Option<Duration>,
+    /// `--db`: SQLite file every response is stored in.
+    db: Option<String>,
+    /// `--dead-letter <topic|off>`; `data/deadletter` by default.
+    dead_letter_topic: Option<String>,
+    /// `--min-log-level`.
+    min_log_level: Option<LogLevel>,
+    /// `--max-payload-bytes`; [`DEFAULT_MAX_PAYLOAD_BYTES`] unless given.
+    max_payload_bytes: usize,
+    /// `--mqtt-cap`; [`DEFAULT_MQTT_CAP`] unless given.
+    mqtt_cap: usize,
+}
+
+const DEFAULT_MAX_PAYLOAD_BYTES: usize = 16 * 1024 * 1024;
+
+/// Requests (responses, acks, dead letters...) the MQTT client queues before
+/// a publish blocks. A bigger queue absorbs bursts of responses at the cost
+/// of holding more of them in memory.
+const DEFAULT_MQTT_CAP: usize = 20;
+
+/// The largest packet MQTT can encode.
+const MAX_MQTT_PACKET_SIZE: usize = 268_435_455;
+
+/// Parses the slave's own flags. `--input <file> --output <file>` run it
+/// offline and must be given together.
+fn slave_args(args: Vec<String>) -> Result<SlaveArgs, String> {
+    let mut input = None;
+    let mut output = None;
+    let mut metrics_print_interval = Some(Duration::from_secs(10));
+    let mut format = WireFormat::Json;
+    let mut workers = None;
+    let mut metrics_port = None;
+    let mut heartbeat_interval = Some(Duration::from_secs(5));
+    let mut db = None;
+    let mut dead_letter_topic = Some(DeadLetter::TOPIC.to_string());
+    let mut min_log_level = None;
+    let mut max_payload_bytes = DEFAULT_MAX_PAYLOAD_BYTES;
+    let mut mqtt_cap = DEFAULT_MQTT_CAP;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let slot = match arg.as_str() {
+            "--input" => &mut input,
+            "--output" => &mut output,
+            "--db" => &mut db,
+            "--metrics-interval-secs" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                let secs = value.parse::<u64>().map_err(|_| format!("invalid {} {:?}", arg, value))?;
+                metrics_print_interval = (secs > 0).then(|| Duration::from_secs(secs));
+                continue;
+            }
+            "--format" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                format = value.parse()?;
+                continue;
+            }
+            "--workers" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                let count = value.parse::<usize>().ok().filter(|count| *count > 0);
+                workers = Some(count.ok_or_else(|| format!("--workers must be a positive number, got {:?}", value))?);
+                continue;
+            }
+            "--heartbeat-secs" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                let secs = value.parse::<u64>().map_err(|_| format!("invalid {} {:?}", arg, value))?;
+                heartbeat_interval = (secs > 0).then(|| Duration::from_secs(secs));
+                continue;
+            }
+            "--metrics-port" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                let port = value.parse::<u16>().ok().filter(|port| *port != 0);
+                metrics_port = Some(port.ok_or_else(|| format!("--metrics-port must be a port number, got {:?}", value))?);
+                continue;
+            }
+            "--max-payload-bytes" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                let bytes = value.parse::<usize>().ok().filter(|bytes| *bytes > 0);
+                max_payload_bytes = bytes.ok_or_else(|| format!("--max-payload-bytes must be a positive number, got {:?}", value))?;
+                continue;
+            }
+            "--mqtt-cap" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                let cap = value.parse::<usize>().ok().filter(|cap| *cap > 0);
+                mqtt_cap = cap.ok_or_else(|| format!("--mqtt-cap must be a positive number, got {:?}", value))?;
+                continue;
+            }
+            "--min-log-level" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                min_log_level = Some(value.parse()?);
+                continue;
+            }
+            "--dead-letter" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a topic or off", arg))?;
+                dead_letter_topic = match value.as_str() {
+                    "off" => None,
+                    topic => {
+                        DeadLetter::check_topic(topic)?;
+                        Some(value)
+                    }
+                };
+                continue;
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        };
+        *slot = Some(args.next().ok_or_else(|| format!("{} needs a file path", arg))?);
+    }
+    let offline = match (input, output) {
+        (Some(input), Some(output)) => Some((input, output)),
+        (None, None) => None,
+        (Some(_), None) => return Err("--input needs --output".to_string()),
+        (None, Some(_)) => return Err("--output needs --input".to_string()),
+    };
+    Ok(SlaveArgs { offline, metrics_print_interval, format, workers, metrics_port, heartbeat_interval, db, dead_letter_topic, min_log_level, max_payload_bytes, mqtt_cap })
+}
+
+/// Runs the handler over a capture instead of a broker: each line of
+/// `input` is one raw message and every response is written as a line of
+/// `output`. Prints the aggregate metrics when done.
+fn run_offline(handler: &MessageHandler, input: &str, output: &str) -> Result<(), String> {
+    let reader = BufReader::new(File::open(input).map_err(|e| format!("failed to open {}: {}", input, e))?);
+    let mut sequences = SequenceTracker::new();
+    let mut receive_index: u64 = 0;
+    // Split on raw bytes so a corrupt line is dead-lettered like it would be
+    // off the wire instead of aborting the run.
+    for line in reader.split(b'\n') {
+        let line = line.map_err(|e| format!("failed to read {}: {}", input, e))?;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        for mut packet in handler.parse_message(&line) {
+            receive_index += 1;
+            packet.receive_index = receive_index;
+            sequences.observe(&packet, &handler.metrics);
+            handler.handle_packet(packet);
+        }
+    }
+    if let Outlet::File(file, _) = &handler.outlet {
+        file.lock().unwrap().flush().map_err(|e| format!("failed to write {}: {}", output, e))?;
+    }
+    if let Some(store) = &handler.response_store {
+        store.close();
+    }
+
+    let snapshot = serde_json::to_string_pretty(&handler.metrics.snapshot()).map_err(|e| e.to_string())?;
+    info!("Processed {} packets from {} into {}:\n{}", receive_index, input, output, snapshot);
+    Ok(())
+}
+
+/// What a slave runs with, apart from the environment: the broker flags,
+/// the slave's own flags and the flag that stops it.
+pub struct SlaveConfig {
+    pub broker: BrokerArgs,
+    pub args: SlaveArgs,
+    /// Starts the graceful drain once set. SIGINT and SIGTERM set it too.
+    pub shutdown: Arc<AtomicBool>,
+}
+
+impl SlaveConfig {
+    /// Parses a command line, without the program name.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let (broker, args) = BrokerArgs::extract(args)?;
+        Ok(Self { broker, args: slave_args(args)?, shutdown: Arc::new(AtomicBool::new(false)) })
+    }
+}
+
+/// Runs a slave until `config.shutdown` is set, or until the offline input
+/// is used up when `--input`/`--output` were given. Hooks get the first
+/// chance at each payload, then `processor` handles it; `validators` run on
+/// every converted payload before either. Returns an error for
+/// configuration that keeps the slave from starting.
+pub fn run_slave(
+    config: SlaveConfig,
+    processor: Box<dyn PayloadProcessor>,
+    hooks: ProcessingHooks,
+    validators: ValidatorChain,
+) -> Result<(), String> {
+    let started = Instant::now();
+    let SlaveConfig { broker, args, shutdown } = config;
+    let response_qos = ResponseQos::from_env(broker.qos)
+        .map_err(|e| format!("Invalid response QoS configuration: {}", e))?;
+    let response_store = args.db
+        .as_deref()
+        .map(ResponseStore::open)
+        .transpose()
+        .map_err(|e| format!("Failed to open the response database: {}", e))?;
+    if let Some((input, output)) = &args.offline {
+        let writer = File::create(output).map_err(|e| format!("failed to create {}: {}", output, e))?;
+        let outlet = Outlet::File(Mutex::new(BufWriter::new(writer)), broker.topics.clone());
+        let metrics = Arc::new(ProcessingMetrics::new());
+        let mut handler = MessageHandler::from_env(outlet, metrics, response_qos, 1, processor, hooks, validators);
+        handler.topics = broker.topics.clone();
+        handler.response_store = response_store;
+        handler.dead_letter_topic = args.dead_letter_topic.clone();
+        handler.min_log_level = args.min_log_level;
+        handler.max_payload_bytes = args.max_payload_bytes;
+        // There's no master listening for acks in an offline run.
+        handler.send_acks = false;
+        return run_offline(&handler, input, output).map_err(|e| format!("Offline run failed: {}", e));
+    }
+
+    let transport = broker.transport().map_err(|e| format!("Invalid TLS configuration: {}", e))?;
+
+    let slave_id = broker.client_id("slave-node-");
+    info!("Connecting to MQTT broker...");
+    // The client lets through packets up to twice the payload limit so an
+    // oversized request gets a rejection rather than a dropped connection;
+    // beyond that the connection is dropped before anything is buffered.
+    let max_packet_size = args.max_payload_bytes.saturating_mul(2).min(MAX_MQTT_PACKET_SIZE);
+    info!("MQTT request queue capacity {}", args.mqtt_cap);
+    let (client, mut connection) = connect(
+        &slave_id,
+        &broker,
+        transport,
+        args.mqtt_cap,
+        Some(Heartbeat::last_will(&slave_id)),
+        Some(max_packet_size),
+    );
+
+    info!("Taking requests from {} and responding on {}", broker.topics.request, broker.topics.response);
+    client
+        .subscribe(broker.topics.request.as_str(), broker.qos)
+        .map_err(|e| format!("Failed to subscribe: {:?}", e))?;
+    info!("Successfully subscribed to {}", broker.topics.request);
+    // Lets a master address this slave alone; see Topics::direct_request.
+    let direct_topic = broker.topics.direct_request(&slave_id);
+    if let Err(e) = client.subscribe(direct_topic.as_str(), broker.qos) {
+        error!("Failed to subscribe to {}: {:?}", direct_topic, e);
+    }
+    if let Err(e) = client.subscribe("slave/command", QoS::AtLeastOnce) {
+        error!("Failed to subscribe to slave/command: {:?}", e);
+    }
+    if let Err(e) = client.subscribe(SlaveStatus::REQUEST_TOPIC, QoS::AtLeastOnce) {
+        error!("Failed to subscribe to {}: {:?}", SlaveStatus::REQUEST_TOPIC, e);
+    }
+    let election = LeaderElection::from_env(&slave_id).map(Arc::new);
+    if election.is_some() {
+        if let Err(e) = client.subscribe(LeaderElection::TOPIC, QoS::AtLeastOnce) {
+            error!("Failed to subscribe to {}: {:?}", LeaderElection::TOPIC, e);
+        }
+    }
+
+    let metrics = Arc::new(ProcessingMetrics::new());
+    let metrics_interval = Duration::from_secs(env_var("METRICS_INTERVAL_SECS").unwrap_or(10).max(1));
+    // Opt-in: only published when an interval is configured.
+    let processed_count_interval = env_var::<u64>("PROCESSED_COUNT_INTERVAL_SECS")
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    if let Some(interval) = args.metrics_print_interval {
+        let metrics = metrics.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            print_metrics_summary(&metrics);
+        });
+    }
+    if let Some(port) = args.metrics_port {
+        serve_metrics(port, metrics.clone()).map_err(|e| format!("Failed to serve metrics on port {}: {}", port, e))?;
+    }
+    // One worker per CPU unless told otherwise.
+    let workers = args.workers
+        .or_else(|| env_var::<usize>("SLAVE_WORKERS"))
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |cpus| cpus.get()))
+        .max(1);
+    let outlet = Outlet::Broker(client.clone());
+    let mut handler = MessageHandler::from_env(outlet, metrics.clone(), response_qos, workers, processor, hooks, validators);
+    handler.wire_format = args.format;
+    handler.topics = broker.topics.clone();
+    handler.dead_letter_topic = args.dead_letter_topic.clone();
+    handler.min_log_level = args.min_log_level;
+    handler.max_payload_bytes = args.max_payload_bytes;
+    handler.response_store = response_store;
+    // The pool gives images their own lane, so nothing waits on the permits.
+    let image_lanes = handler.image_permits.take().map(|permits| permits.capacity);
+    let handler = Arc::new(handler);
+    let announcement = SlaveOnline { slave_id: slave_id.clone(), request_topic: direct_topic };
+    if let Some(cap_mb) = env_var::<u64>("MEMORY_CAP_MB").filter(|mb| *mb > 0) {
+        let interval = Duration::from_secs(env_var("MEMORY_CHECK_SECS").unwrap_or(5).max(1));
+        spawn_memory_monitor(handler.clone(), cap_mb * 1024 * 1024, interval);
+    }
+    let queue_capacity = env_var::<usize>("WORKER_QUEUE_CAPACITY").unwrap_or(100).max(1);
+    info!("Starting {} worker(s) with queue capacity {}", workers, queue_capacity);
+    let mut pool = WorkerPool::start(handler.clone(), workers, queue_capacity, image_lanes);
+    let saturation_sample_ms = env_var::<u64>("SATURATION_SAMPLE_MS").unwrap_or(100);
+    if saturation_sample_ms > 0 {
+        spawn_saturation_sampler(handler.clone(), workers, Duration::from_millis(saturation_sample_ms));
+    }
+
+    // SIGINT (Ctrl-C) and SIGTERM (sent by orchestrators such as Kubernetes)
+    // both trigger the same graceful drain.
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        if let Err(e) = signal_hook::flag::register(signal, shutdown.clone()) {
+            error!("Failed to install handler for signal {}: {:?}", signal, e);
+        }
+    }
+    let shutdown_grace = Duration::from_secs(env_var("SHUTDOWN_GRACE_SECS").unwrap_or(25));
+    // Lets an autoscaler take an unused slave down to zero.
+    let idle_shutdown = env_var::<u64>("IDLE_SHUTDOWN_SECS")
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    let shutting_down = shutdown.clone();
+    let reconnect_jitter = env_var::<f64>("RECONNECT_JITTER").unwrap_or(0.2).clamp(0.0, 1.0);
+
+    // Main processing thread
+    let connection_handler = handler.clone();
+    let command_slave_id = slave_id.clone();
+    let connection_election = election.clone();
+    let mut reorder = ReorderBuffer::new(
+        Duration::from_millis(env_var("REORDER_WINDOW_MS").unwrap_or(0)),
+        env_var("REORDER_MAX_HELD").unwrap_or(1000),
+    );
+    let connection_thread = thread::spawn(move || {
+        info!("Starting message processing...");
+        let mut server_disconnected = false;
+        let mut sequences = SequenceTracker::new();
+        let mut receive_index: u64 = 0;
+        let mut connected_before = false;
+        let mut backoff = Backoff::from_env();
+        let mut dispatch = |mut packet: FlexiblePacket| {
+            receive_index += 1;
+            packet.receive_index = receive_index;
+            pool.dispatch(packet);
+        };
+
+        loop {
+            // Only wake up on a timer while holding packets back; otherwise
+            // block until the event loop has something.
+            let notification = match reorder.remaining() {
+                Some(remaining) => match connection.recv_timeout(remaining) {
+                    Ok(notification) => notification,
+                    Err(RecvTimeoutError::Timeout) => {
+                        reorder.release().into_iter().for_each(&mut dispatch);
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match connection.recv() {
+                    Ok(notification) => notification,
+                    Err(_) => break,
+                },
+            };
+            match notification {
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)))
+                    if publish.topic == "slave/command" =>
+                {
+                    // Commands publish their results, which can block on a
+                    // full request queue that only this thread drains.
+                    let handler = connection_handler.clone();
+                    let slave_id = command_slave_id.clone();
+                    thread::spawn(move || handle_command(&handler, &slave_id, &publish.payload));
+                }
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)))
+                    if publish.topic == SlaveStatus::REQUEST_TOPIC =>
+                {
+                    // Same as commands: the answer is published off this thread.
+                    let handler = connection_handler.clone();
+                    let slave_id = command_slave_id.clone();
+                    thread::spawn(move || publish_status(&handler, &slave_id, started));
+                }
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)))
+                    if publish.topic == LeaderElection::TOPIC =>
+                {
+                    if let Some(election) = &connection_election {
+                        election.observe(&String::from_utf8_lossy(&publish.payload));
+                    }
+                }
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(_)))
+                    if connection_election.as_ref().is_some_and(|election| !election.is_leader()) =>
+                {
+                    // Standby: the leader handles requests.
+                }
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish))) => {
+                    info!("Received message on topic: {}", publish.topic);
+                    connection_handler.mark_active();
+                    for packet in connection_handler.parse_message(&publish.payload) {
+                        connection_handler.ack(&packet);
+                        sequences.observe(&packet, &connection_handler.metrics);
+                        if let Some(packet) = reorder.hold(packet) {
+                            dispatch(packet);
+                        } else if reorder.is_full() {
+                            reorder.release().into_iter().for_each(&mut dispatch);
+                        }
+                    }
+                }
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(ack)))
+                    if ack.code == ConnectReturnCode::Success =>
+                {
+                    info!("Connected to broker");
+                    backoff.reset();
+                    connection_handler.metrics.connection.lock().unwrap().on_connect();
+                    connection_handler.announce(&announcement);
+                    if connected_before {
+                        reorder.start();
+                    }
+                    connected_before = true;
+                }
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Disconnect)) => {
+                    connection_handler.metrics.connection.lock().unwrap().on_disconnect();
+                    warn!("Broker closed the connection: {}", DisconnectReason::ServerDisconnect.as_str());
+                    connection_handler.metrics.record_disconnect(DisconnectReason::ServerDisconnect);
+                    server_disconnected = true;
+                }
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::PubAck(_) | rumqttc::Packet::PubComp(_))) => {
+                    connection_handler.metrics.publish_confirms.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => {
+                    connection_handler.metrics.connection.lock().unwrap().on_disconnect();
+                    info!("Disconnected from broker");
+                    break;
+                }
+                Ok(other) => debug!("Received other MQTT event: {:?}", other),
+                Err(e) if shutting_down.load(Ordering::Relaxed) => {
+                    connection_handler.metrics.connection.lock().unwrap().on_disconnect();
+                    warn!("Connection error during shutdown, not reconnecting: {}", e);
+                    break;
+                }
+                Err(e) => {
+                    connection_handler.metrics.connection.lock().unwrap().on_disconnect();
+                    let reason = DisconnectReason::classify(&e);
+                    // The error that follows a broker DISCONNECT is the same
+                    // disconnect, so don't count it twice.
+                    if !std::mem::take(&mut server_disconnected) {
+                        connection_handler.metrics.record_disconnect(reason);
+                    }
+                    let delay = with_jitter(backoff.next_delay().max(reason.reconnect_delay()), reconnect_jitter);
+                    warn!(
+                        "Connection error ({}): {}; reconnect attempt {} in {:?}",
+                        reason.as_str(),
+                        e,
+                        backoff.attempt(),
+                        delay
+                    );
+                    thread::sleep(delay);
+                }
+            }
+        }
+        // However the loop ended, packets still held for reordering go to
+        // the workers so the drain answers them.
+        reorder.release().into_iter().for_each(&mut dispatch);
+    });
+
+    // Keep the main thread alive until shutdown, publishing a metrics snapshot each interval
+    let mut last_metrics = Instant::now();
+    let mut last_processed_count = Instant::now();
+    let mut last_heartbeat: Option<Instant> = None;
+    while !shutdown.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(100));
+        if idle_shutdown.is_some_and(|idle_for| handler.is_idle(idle_for)) {
+            info!("No messages for {:?}, shutting down", idle_shutdown.unwrap_or_default());
+            // Same path as a signal, so the connection thread stops reconnecting too.
+            shutdown.store(true, Ordering::Relaxed);
+            continue;
+        }
+        if let Some(election) = &election {
+            election.tick(&handler);
+        }
+        if last_metrics.elapsed() >= metrics_interval {
+            publish_metrics(&handler);
+            last_metrics = Instant::now();
+        }
+        if processed_count_interval.is_some_and(|interval| last_processed_count.elapsed() >= interval) {
+            publish_processed_count(&handler, &slave_id);
+            last_processed_count = Instant::now();
+        }
+        if let Some(interval) = args.heartbeat_interval {
+            if last_heartbeat.is_none_or(|sent| sent.elapsed() >= interval) {
+                publish_heartbeat(&handler, &slave_id, started, interval);
+                last_heartbeat = Some(Instant::now());
+            }
+        }
+    }
+
+    if let Some(election) = &election {
+        election.resign(&handler);
+    }
+    drain(&client, &handler, connection_thread, &slave_id, shutdown_grace);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::DataPacket;
+    use crate::processing::DefaultProcessor;
+
+    /// Runs `lines` through an offline slave with `args` added to the
+    /// command line and returns the responses it wrote.
+    fn run_offline_with(
+        lines: &[String],
+        args: &[&str],
+        processor: Box<dyn PayloadProcessor>,
+        hooks: ProcessingHooks,
+        validators: ValidatorChain,
+    ) -> Vec<DataResponse> {
+        let dir = std::env::temp_dir().join(format!("slave-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.jsonl");
+        let output = dir.join("output.jsonl");
+        std::fs::write(&input, lines.join("\n")).unwrap();
+        let command_line = [
+            "--input",
+            input.to_str().unwrap(),
+            "--output",
+            output.to_str().unwrap(),
+            "--dead-letter",
+            "off",
+        ];
+        let config = SlaveConfig::from_args(command_line.iter().chain(args).map(|arg| arg.to_string())).unwrap();
+        run_slave(config, processor, hooks, validators).unwrap();
+        let responses = std::fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        responses
+    }
+
+    fn packet_line(payload: DataPayload) -> String {
+        serde_json::to_string(&DataPacket::builder(payload).build()).unwrap()
+    }
+
+    /// Counts the payloads it is given and answers each with a fixed status.
+    struct CountingProcessor(Arc<AtomicUsize>);
+
+    impl PayloadProcessor for CountingProcessor {
+        fn process(&self, _payload: &DataPayload) -> ResponseStatus {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            ResponseStatus::Ok("counted".to_string())
+        }
+    }
+
+    #[test]
+    fn custom_processor_handles_every_payload() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let lines: Vec<String> = (0..3).map(|n| packet_line(DataPayload::Number(n.into()))).collect();
+        let processor = Box::new(CountingProcessor(calls.clone()));
+        let responses = run_offline_with(&lines, &[], processor, ProcessingHooks::new(), ValidatorChain::new());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        assert_eq!(responses.len(), 3);
+        assert!(responses.iter().all(|response| response.status == ResponseStatus::Ok("counted".to_string())));
+    }
+
+    #[test]
+    fn snapshots_never_see_a_half_recorded_message() {
+        let metrics = Arc::new(ProcessingMetrics::new());
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let metrics = metrics.clone();
+                thread::spawn(move || {
+                    for n in 0..5_000u64 {
+                        let payload = match (writer + n) % 3 {
+                            0 => DataPayload::Text("t".to_string()),
+                            1 => DataPayload::Number(n.into()),
+                            _ => DataPayload::Json(Value::Null),
+                        };
+                        metrics.record(&payload, 1, None);
+                    }
+                })
+            })
+            .collect();
+        while !writers.iter().all(|writer| writer.is_finished()) {
+            let snapshot = metrics.snapshot();
+            let typed: u64 = snapshot.type_counts().iter().map(|(_, count)| count).sum();
+            assert_eq!(typed, snapshot.processed_count);
+        }
+        writers.into_iter().for_each(|writer| writer.join().unwrap());
+        assert_eq!(metrics.snapshot().processed_count, 20_000);
+    }
+
+    #[test]
+    fn a_key_always_lands_on_the_same_worker() {
+        for key in ["sensor-1", "sensor-2", "partition-a", ""] {
+            let worker = worker_for_key(key, 8);
+            assert!(worker < 8);
+            assert!((0..100).all(|_| worker_for_key(key, 8) == worker));
+        }
+        // Adding a worker only moves keys onto the new one.
+        for n in 0..1_000 {
+            let key = format!("sensor-{}", n);
+            let before = worker_for_key(&key, 8);
+            let after = worker_for_key(&key, 9);
+            assert!(after == before || after == 8, "{} moved from {} to {}", key, before, after);
+        }
+        assert_eq!(worker_for_key("anything", 1), 0);
+    }
+
+    #[test]
+    fn sensor_packets_are_keyed_by_sensor_unless_partitioned() {
+        let packet = |raw: &str| serde_json::from_str::<FlexiblePacket>(raw).unwrap();
+        let sensor = packet(r#"{"id": "1", "payload": {"SensorData": {"sensor_id": "S9"}}}"#);
+        assert_eq!(sensor.partition_key(), Some("S9"));
+        let partitioned = packet(r#"{"id": "2", "payload": {"SensorData": {"sensor_id": "S9"}}, "metadata": {"partition": "p1"}}"#);
+        assert_eq!(partitioned.partition_key(), Some("p1"));
+        assert_eq!(packet(r#"{"id": "3", "payload": {"Text": "x"}}"#).partition_key(), None);
+    }
+
+    #[test]
+    fn text_hook_replaces_the_processor_for_text_only() {
+        let hooks = ProcessingHooks::new().on_text(|payload| match payload {
+            DataPayload::Text(text) => ResponseStatus::Ok(format!("shouted: {}", text.to_uppercase())),
+            _ => unreachable!("only text reaches the text hook"),
+        });
+        let lines = [packet_line(DataPayload::Text("hello".to_string())), packet_line(DataPayload::Number(7.into()))];
+        let processor = Box::new(DefaultProcessor::default());
+        let responses = run_offline_with(&lines, &[], processor, hooks, ValidatorChain::new());
+        assert_eq!(responses[0].status, ResponseStatus::Ok("shouted: HELLO".to_string()));
+        assert_eq!(responses[1].status, ResponseStatus::Ok("Number processed: 7".to_string()));
+    }
+
+    #[test]
+    fn images_queue_for_their_lane_without_holding_a_worker() {
+        let path = std::env::temp_dir().join(format!("slave-test-{}.jsonl", uuid::Uuid::new_v4()));
+        let outlet = Outlet::File(Mutex::new(BufWriter::new(File::create(&path).unwrap())), Topics::default());
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let hooks = ProcessingHooks::new().on_image_data(move |_| {
+            released.lock().unwrap().recv().unwrap();
+            ResponseStatus::Ok("image".to_string())
+        });
+        let calls = Arc::new(AtomicUsize::new(0));
+        let response_qos = ResponseQos { default: QoS::AtMostOnce, by_type: HashMap::new() };
+        let processor = Box::new(CountingProcessor(calls.clone()));
+        let mut handler = MessageHandler::from_env(outlet, Arc::new(ProcessingMetrics::new()), response_qos, 1, processor, hooks, ValidatorChain::new());
+        handler.send_acks = false;
+        let handler = Arc::new(handler);
+        let mut pool = WorkerPool::start(handler.clone(), 1, 10, Some(1));
+
+        let image = DataPayload::ImageData { width: 1, height: 1, format: "GRAY".to_string(), data: vec![0] };
+        for payload in [image.clone(), image, DataPayload::Number(1.into())] {
+            pool.dispatch(handler.parse_message(packet_line(payload).as_bytes()).remove(0));
+        }
+        // Both images are stuck in the one-thread lane; the number still
+        // gets through on the general worker.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while calls.load(Ordering::Relaxed) == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(handler.metrics.images_in_progress.load(Ordering::Relaxed), 1);
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        while handler.in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(handler.in_flight.load(Ordering::SeqCst), 0);
+        assert_eq!(handler.metrics.images_in_progress.load(Ordering::Relaxed), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tenant_overflow_shares_a_slot_within_the_limit() {
+        let mut metrics = ProcessingMetrics::new();
+        metrics.max_tenants = 3;
+        let payload = DataPayload::Number(1.into());
+        for tenant in ["a", "b", "c", "d", "a", "e"] {
+            metrics.record(&payload, 1, Some(tenant));
+        }
+        let tenants = metrics.tenants.lock().unwrap();
+        let mut names: Vec<&str> = tenants.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["_other", "a", "b"]);
+        assert_eq!(tenants["a"].processed_count, 2);
+        assert_eq!(tenants["_other"].processed_count, 3);
+    }
+
+    #[test]
+    fn reorder_buffer_releases_everything_it_held_in_sequence_order() {
+        let packet = |seq: Option<u64>| {
+            let mut builder = DataPacket::builder(DataPayload::Number(1.into()));
+            if let Some(seq) = seq {
+                builder = builder.metadata("seq", seq.to_string());
+            }
+            serde_json::from_str::<FlexiblePacket>(&serde_json::to_string(&builder.build()).unwrap()).unwrap()
+        };
+        let mut reorder = ReorderBuffer::new(Duration::from_secs(60), 10);
+        assert!(reorder.hold(packet(Some(1))).is_some());
+        reorder.start();
+        for seq in [Some(3), None, Some(1), Some(2)] {
+            assert!(reorder.hold(packet(seq)).is_none());
+        }
+        let released: Vec<Option<u64>> = reorder.release().iter().map(FlexiblePacket::seq).collect();
+        assert_eq!(released, [Some(1), Some(2), Some(3), None]);
+        assert_eq!(reorder.remaining(), None);
+        assert!(reorder.release().is_empty());
+    }
+
+    #[test]
+    fn round_half_even_breaks_ties_towards_even() {
+        assert_eq!(round_half_even(0.5, 0), 0.0);
+        assert_eq!(round_half_even(1.5, 0), 2.0);
+        assert_eq!(round_half_even(2.5, 0), 2.0);
+        assert_eq!(round_half_even(-0.5, 0), 0.0);
+        assert!(round_half_even(-0.5, 0).is_sign_negative());
+        assert_eq!(round_half_even(-1.5, 0), -2.0);
+        assert_eq!(round_half_even(-2.5, 0), -2.0);
+        assert_eq!(round_half_even(2.675, 2), 2.68);
+        assert_eq!(round_half_even(2.665, 2), 2.66);
+        assert_eq!(round_half_even(9.95, 1), 10.0);
+        // Not a tie: anything past the 5 rounds away from zero.
+        assert_eq!(round_half_even(2.5001, 0), 3.0);
+        assert_eq!(round_half_even(1.25, 5), 1.25);
+        assert!(round_half_even(f64::NAN, 1).is_nan());
+    }
+
+    #[test]
+    fn an_empty_batch_is_answered() {
+        let lines = [packet_line(DataPayload::Batch(Vec::new()))];
+        let responses = run_offline_with(&lines, &[], Box::new(DefaultProcessor::default()), ProcessingHooks::new(), ValidatorChain::new());
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, ResponseStatus::Ok("Batch processed: 0 items".to_string()));
+    }
+
+    #[test]
+    fn malformed_json_is_answered_with_a_parse_error() {
+        let lines = [
+            "{not json".to_string(),
+            r#"{"id":"no-payload"}"#.to_string(),
+            packet_line(DataPayload::Text("fine".to_string())),
+        ];
+        let responses = run_offline_with(&lines, &[], Box::new(DefaultProcessor::default()), ProcessingHooks::new(), ValidatorChain::new());
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0].packet_id, UNPARSED_PACKET_ID);
+        assert!(matches!(responses[0].status, ResponseStatus::ParseError(_)));
+        // A packet that is valid JSON keeps its id, so the master can match it.
+        assert_eq!(responses[1].packet_id, "no-payload");
+        assert!(matches!(responses[1].status, ResponseStatus::ParseError(_)));
+        assert!(responses[2].status.is_ok());
+    }
+
+    #[test]
+    fn metrics_endpoint_serves_the_counters() {
+        let metrics = Arc::new(ProcessingMetrics::new());
+        metrics.record(&DataPayload::Batch(vec![DataPayload::Text("a".to_string()), DataPayload::Text("b".to_string())]), 4, None);
+        metrics.record(&DataPayload::Number(1.into()), 3, Some("acme"));
+        metrics.record(&DataPayload::Number(2.into()), 5, Some("acme"));
+        metrics.record(&DataPayload::Number(3.into()), 7, Some("globex"));
+        {
+            let mut connection = metrics.connection.lock().unwrap();
+            connection.on_connect();
+            connection.on_disconnect();
+            connection.on_connect();
+        }
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        serve_metrics(port, metrics).unwrap();
+
+        let scrape = |path: &str| {
+            let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(format!("GET {} HTTP/1.0\r\n\r\n", path).as_bytes()).unwrap();
+            let mut reply = String::new();
+            std::io::Read::read_to_string(&mut stream, &mut reply).unwrap();
+            reply
+        };
+        let counter = |reply: &str, name: &str| -> Option<u64> {
+            let line = reply.lines().find(|line| line.starts_with(&format!("{} ", name)))?;
+            line.rsplit(' ').next()?.parse().ok()
+        };
+
+        let reply = scrape("/metrics");
+        assert!(reply.starts_with("HTTP/1.0 200 OK"), "{}", reply);
+        assert_eq!(counter(&reply, "slave_processed_total"), Some(4));
+        assert_eq!(counter(&reply, "slave_processing_time_ms_total"), Some(19));
+        assert_eq!(counter(&reply, r#"slave_payload_total{type="text"}"#), Some(2));
+        assert_eq!(counter(&reply, r#"slave_payload_total{type="json"}"#), Some(0));
+        assert_eq!(counter(&reply, "slave_reconnects_total"), Some(1));
+        assert_eq!(counter(&reply, "slave_connected"), Some(1));
+        assert_eq!(counter(&reply, "slave_connected_uptime_seconds"), Some(0));
+        assert_eq!(counter(&reply, r#"slave_tenant_processed_total{tenant="acme"}"#), Some(2));
+        assert_eq!(counter(&reply, r#"slave_tenant_processing_time_ms_total{tenant="acme"}"#), Some(8));
+        assert_eq!(counter(&reply, r#"slave_tenant_processed_total{tenant="globex"}"#), Some(1));
+
+        let reply = scrape("/metrics?tenant=acme");
+        assert!(reply.starts_with("HTTP/1.0 200 OK"), "{}", reply);
+        assert_eq!(counter(&reply, "slave_processed_total"), Some(4));
+        assert_eq!(counter(&reply, r#"slave_tenant_processed_total{tenant="acme"}"#), Some(2));
+        assert_eq!(counter(&reply, r#"slave_tenant_processed_total{tenant="globex"}"#), None);
+
+        assert!(scrape("/status").starts_with("HTTP/1.0 404"));
+    }
+
+    #[test]
+    fn responses_are_stored_with_db() {
+        let db = std::env::temp_dir().join(format!("slave-test-{}.db", uuid::Uuid::new_v4()));
+        let lines = [
+            packet_line(DataPayload::Text("one".to_string())),
+            packet_line(DataPayload::Number(2.into())),
+            "{not json".to_string(),
+        ];
+        let processor = Box::new(DefaultProcessor::default());
+        let responses = run_offline_with(&lines, &["--db", db.to_str().unwrap()], processor, ProcessingHooks::new(), ValidatorChain::new());
+        assert_eq!(responses.len(), 3);
+
+        let connection = rusqlite::Connection::open(&db).unwrap();
+        let rows: i64 = connection.query_row("SELECT COUNT(*) FROM responses", (), |row| row.get(0)).unwrap();
+        assert_eq!(rows, 3);
+        let typed: Vec<(String, Option<String>)> = connection
+            .prepare("SELECT packet_id, payload_type FROM responses ORDER BY id")
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(typed[0], (responses[0].packet_id.clone(), Some("text".to_string())));
+        assert_eq!(typed[1].1.as_deref(), Some("number"));
+        assert_eq!(typed[2], (UNPARSED_PACKET_ID.to_string(), None));
+        drop(connection);
+        std::fs::remove_file(&db).unwrap();
+    }
+
+    #[test]
+    fn log_entries_below_the_minimum_level_are_filtered() {
+        let levels = ["debug", "INFO", "warn", "WARNING", "ERROR", "FATAL"];
+        let lines: Vec<String> = levels
+            .iter()
+            .map(|level| {
+                packet_line(DataPayload::LogEntry {
+                    level: level.to_string(),
+                    message: "disk".to_string(),
+                    timestamp: "2024-05-01T12:00:00Z".to_string(),
+                })
+            })
+            .collect();
+        let processor = Box::new(DefaultProcessor::default());
+        let responses =
+            run_offline_with(&lines, &["--min-log-level", "WARN"], processor, ProcessingHooks::new(), ValidatorChain::new());
+        let filtered: Vec<bool> = responses
+            .iter()
+            .map(|response| matches!(&response.status, ResponseStatus::Ok(message) if message.starts_with("Log entry filtered")))
+            .collect();
+        // The threshold itself passes, and a level nobody knows ranks highest.
+        assert_eq!(filtered, [true, true, false, false, false, false]);
+    }
+
+    #[test]
+    fn messages_over_the_payload_limit_are_rejected_by_id() {
+        let small = DataPacket::builder(DataPayload::Text("ok".to_string())).id("small").build();
+        let large = DataPacket::builder(DataPayload::Text("x".repeat(600))).id("large").build();
+        let lines = [serde_json::to_string(&small).unwrap(), serde_json::to_string(&large).unwrap()];
+        let processor = Box::new(DefaultProcessor::default());
+        let responses =
+            run_offline_with(&lines, &["--max-payload-bytes", "512"], processor, ProcessingHooks::new(), ValidatorChain::new());
+        assert_eq!(responses.len(), 2);
+        let large = responses.iter().find(|response| response.packet_id == "large").unwrap();
+        assert!(
+            matches!(&large.status, ResponseStatus::Rejected(detail) if detail.ends_with("exceeds the 512 byte limit")),
+            "{:?}",
+            large.status
+        );
+        let small = responses.iter().find(|response| response.packet_id == "small").unwrap();
+        assert!(matches!(small.status, ResponseStatus::Ok(_)));
+    }
+
+    #[test]
+    fn version_policy_checks_the_protocol_before_the_metadata_version() {
+        let any = VersionPolicy { supported: None, allow_missing: true };
+        assert_eq!(any.check(1, None), Ok(()));
+        assert_eq!(any.check(0, None), Ok(()));
+        assert_eq!(any.check(0, Some("1.3")), Ok(()));
+        assert!(matches!(any.check(2, Some("1.0")), Err(SkipReason::ProtocolMismatch(_))));
+        assert!(matches!(any.check(0, Some("2.0")), Err(SkipReason::ProtocolMismatch(_))));
+
+        let listed = VersionPolicy { supported: Some(HashSet::from(["1.0".to_string()])), allow_missing: false };
+        assert_eq!(listed.check(1, Some("1.0")), Ok(()));
+        assert!(matches!(listed.check(1, Some("1.1")), Err(SkipReason::UnsupportedVersion(_))));
+        assert!(matches!(listed.check(1, None), Err(SkipReason::UnsupportedVersion(_))));
+        assert!(matches!(listed.check(2, Some("1.1")), Err(SkipReason::ProtocolMismatch(_))));
+    }
+
+    #[test]
+    fn version_2_packets_are_rejected() {
+        let mut packet = DataPacket::builder(DataPayload::Text("from the future".to_string())).id("v2").build();
+        packet.protocol_version = 2;
+        let lines = [
+            serde_json::to_string(&packet).unwrap(),
+            // Payloads a newer protocol added are rejected too, not reported
+            // as unconvertible.
+            r#"{"id": "v2-hologram", "protocol_version": 2, "payload": {"Hologram": {}}}"#.to_string(),
+            packet_line(DataPayload::Text("current".to_string())),
+        ];
+        let processor = Box::new(DefaultProcessor::default());
+        let responses = run_offline_with(&lines, &[], processor, ProcessingHooks::new(), ValidatorChain::new());
+        assert_eq!(responses.len(), 3);
+        for response in &responses[..2] {
+            assert!(
+                matches!(&response.status, ResponseStatus::Rejected(detail) if detail.starts_with("protocol version 2 is not supported")),
+                "{}: {:?}",
+                response.packet_id,
+                response.status
+            );
+        }
+        assert!(matches!(responses[2].status, ResponseStatus::Ok(_)));
+    }
+
+    #[test]
+    fn a_flipped_payload_byte_fails_the_checksum() {
+        let payload = DataPayload::Text("hello".to_string());
+        let crc32 = payload_crc32(&serde_json::to_value(&payload).unwrap());
+        let intact = DataPacket::builder(payload.clone()).id("intact").metadata("crc32", crc32.clone()).build();
+        let corrupt = DataPacket::builder(payload).id("corrupt").metadata("crc32", crc32).build();
+        let corrupt = serde_json::to_string(&corrupt).unwrap().replace("hello", "hellp");
+        let lines = [serde_json::to_string(&intact).unwrap(), corrupt];
+        let processor = Box::new(DefaultProcessor::default());
+        let responses = run_offline_with(&lines, &[], processor, ProcessingHooks::new(), ValidatorChain::new());
+        assert_eq!(responses[0].status, ResponseStatus::Ok("Text processed: 5 chars".to_string()));
+        assert_eq!(responses[1].packet_id, "corrupt");
+        assert!(matches!(&responses[1].status, ResponseStatus::ChecksumError(_)), "{:?}", responses[1].status);
+    }
+
+    /// A handler with the default processor that writes its responses to
+    /// `path`, for settings the command line can't reach.
+    fn file_handler(path: &std::path::Path) -> MessageHandler {
+        let outlet = Outlet::File(Mutex::new(BufWriter::new(File::create(path).unwrap())), Topics::default());
+        let response_qos = ResponseQos { default: QoS::AtMostOnce, by_type: HashMap::new() };
+        let processor = Box::new(DefaultProcessor::default());
+        let metrics = Arc::new(ProcessingMetrics::new());
+        let mut handler =
+            MessageHandler::from_env(outlet, metrics, response_qos, 1, processor, ProcessingHooks::new(), ValidatorChain::new());
+        handler.send_acks = false;
+        handler
+    }
+
+    /// The responses a [`file_handler`] wrote, once it is dropped.
+    fn read_responses(path: &std::path::Path) -> Vec<DataResponse> {
+        let responses = std::fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        std::fs::remove_file(path).unwrap();
+        responses
+    }
+
+    #[test]
+    fn a_declared_data_type_that_disagrees_with_the_payload_is_counted() {
+        let path = std::env::temp_dir().join(format!("slave-test-{}.jsonl", uuid::Uuid::new_v4()));
+        let handler = file_handler(&path);
+        for line in [
+            r#"{"id": "mislabelled", "data_type": "text", "payload": {"Number": 1}}"#,
+            r#"{"id": "labelled", "data_type": "number", "payload": {"Number": 2}}"#,
+            r#"{"id": "unlabelled", "payload": {"Number": 3}}"#,
+        ] {
+            handler.handle_message(line.as_bytes());
+        }
+        assert_eq!(handler.metrics.data_type_mismatches.load(Ordering::Relaxed), 1);
+        assert_eq!(handler.metrics.processed_count.load(Ordering::Relaxed), 3);
+        drop(handler);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn an_unresolvable_reference_is_answered() {
+        let path = std::env::temp_dir().join(format!("slave-test-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut handler = file_handler(&path);
+        handler.reference_resolver =
+            Some(ReferenceResolver { allowed_schemes: HashSet::from(["file".to_string()]), max_bytes: 1024 });
+        let missing = std::env::temp_dir().join(format!("slave-test-missing-{}", uuid::Uuid::new_v4()));
+        let payload = DataPayload::Reference {
+            uri: format!("file://{}", missing.display()),
+            size: 4,
+            content_type: "text/plain".to_string(),
+            checksum: "sha256:00".to_string(),
+        };
+        let packet = DataPacket::builder(payload).id("missing").build();
+        handler.handle_message(serde_json::to_string(&packet).unwrap().as_bytes());
+        drop(handler);
+
+        let responses = read_responses(&path);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].packet_id, "missing");
+        assert!(
+            matches!(&responses[0].status, ResponseStatus::Rejected(detail) if detail.starts_with("unresolvable reference")),
+            "{:?}",
+            responses[0].status
+        );
+    }
+
+    #[test]
+    fn strict_mode_accepts_the_metadata_a_looping_replay_adds() {
+        let path = std::env::temp_dir().join(format!("slave-test-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut handler = file_handler(&path);
+        handler.strict_fields = true;
+        let replayed = DataPacket::builder(DataPayload::Text("again".to_string()))
+            .id("second-pass")
+            .metadata("original_id", "sample-1")
+            .build();
+        let unknown = DataPacket::builder(DataPayload::Text("odd".to_string())).id("unknown").metadata("colour", "blue").build();
+        for packet in [replayed, unknown] {
+            handler.handle_message(serde_json::to_string(&packet).unwrap().as_bytes());
+        }
+        assert_eq!(handler.metrics.unknown_fields.load(Ordering::Relaxed), 1);
+        drop(handler);
+
+        let responses = read_responses(&path);
+        let replayed = responses.iter().find(|response| response.packet_id == "second-pass").unwrap();
+        assert!(matches!(replayed.status, ResponseStatus::Ok(_)), "{:?}", replayed.status);
+    }
+}
@@ -0,0 +1,235 @@
+//! The async slave: the same [`MessageHandler`] as the blocking slave, driven
+//! by `rumqttc::AsyncClient` on tokio instead of a worker pool.
+//!
+//! The event loop is awaited on the runtime and every request becomes its
+//! own task, with `--concurrency` (default: one per CPU) bounding how many
+//! process at once; the rest wait for a permit. The handler publishes
+//! synchronously, so each task runs it on a blocking thread. It doesn't
+//! cover the blocking slave's connection-level features (worker pinning,
+//! reordering, commands, leader election, heartbeats and so on).
+
+use super::{
+    publish_metrics, publish_status, DisconnectReason, MessageHandler, Outlet, ProcessingMetrics, ResponseQos,
+    DEFAULT_MAX_PAYLOAD_BYTES, MAX_MQTT_PACKET_SIZE,
+};
+use crate::common::{env_var, mqtt_options, Backoff, BrokerArgs, DeadLetter, Heartbeat, SlaveOnline, SlaveStatus, WireFormat};
+use crate::hooks::ProcessingHooks;
+use crate::processing::PayloadProcessor;
+use crate::validation::ValidatorChain;
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+
+/// Options taken from the command line after the broker flags.
+pub struct AsyncSlaveArgs {
+    concurrency: Option<usize>,
+    format: WireFormat,
+    max_payload_bytes: usize,
+    dead_letter_topic: Option<String>,
+}
+
+fn async_slave_args(args: Vec<String>) -> Result<AsyncSlaveArgs, String> {
+    let mut parsed = AsyncSlaveArgs {
+        concurrency: None,
+        format: WireFormat::Json,
+        max_payload_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+        dead_letter_topic: Some(DeadLetter::TOPIC.to_string()),
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--concurrency" => {
+                let count = value.parse::<usize>().ok().filter(|count| *count > 0);
+                parsed.concurrency = Some(count.ok_or_else(|| format!("--concurrency must be a positive number, got {:?}", value))?);
+            }
+            "--format" => parsed.format = value.parse()?,
+            "--max-payload-bytes" => {
+                let bytes = value.parse::<usize>().ok().filter(|bytes| *bytes > 0);
+                parsed.max_payload_bytes = bytes.ok_or_else(|| format!("--max-payload-bytes must be a positive number, got {:?}", value))?;
+            }
+            "--dead-letter" => {
+                parsed.dead_letter_topic = match value.as_str() {
+                    "off" => None,
+                    topic => {
+                        DeadLetter::check_topic(topic)?;
+                        Some(value)
+                    }
+                };
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    Ok(parsed)
+}
+
+/// What an async slave runs with, apart from the environment.
+pub struct AsyncSlaveConfig {
+    pub broker: BrokerArgs,
+    pub args: AsyncSlaveArgs,
+    /// Starts the shutdown once set. SIGINT and SIGTERM set it too.
+    pub shutdown: Arc<AtomicBool>,
+}
+
+impl AsyncSlaveConfig {
+    /// Parses a command line, without the program name.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let (broker, args) = BrokerArgs::extract(args)?;
+        Ok(Self { broker, args: async_slave_args(args)?, shutdown: Arc::new(AtomicBool::new(false)) })
+    }
+}
+
+/// Runs an async slave on the current tokio runtime until
+/// `config.shutdown` is set. Payloads go through `hooks`, `processor` and
+/// `validators` exactly as in [`run_slave`](super::run_slave).
+pub async fn run_slave_async(
+    config: AsyncSlaveConfig,
+    processor: Box<dyn PayloadProcessor>,
+    hooks: ProcessingHooks,
+    validators: ValidatorChain,
+) -> Result<(), String> {
+    let started = Instant::now();
+    let AsyncSlaveConfig { broker, args, shutdown } = config;
+    let transport = broker.transport().map_err(|e| format!("Invalid TLS configuration: {}", e))?;
+    let response_qos = ResponseQos::from_env(broker.qos)
+        .map_err(|e| format!("Invalid response QoS configuration: {}", e))?;
+
+    let slave_id = broker.client_id("slave-node-");
+    let max_packet_size = args.max_payload_bytes.saturating_mul(2).min(MAX_MQTT_PACKET_SIZE);
+    let options = mqtt_options(&slave_id, &broker, transport, Some(Heartbeat::last_will(&slave_id)), Some(max_packet_size));
+    let concurrency = args.concurrency
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |cpus| cpus.get()));
+    // Room for a response from every in-flight task plus the subscriptions.
+    let (client, mut eventloop) = AsyncClient::new(options, concurrency.max(10) * 2);
+    info!("Connecting to MQTT broker...");
+    info!("Taking requests from {} and responding on {} with up to {} tasks", broker.topics.request, broker.topics.response, concurrency);
+
+    let outlet = Outlet::Async(client.clone(), tokio::runtime::Handle::current());
+    let metrics = Arc::new(ProcessingMetrics::new());
+    let mut handler = MessageHandler::from_env(outlet, metrics, response_qos, concurrency, processor, hooks, validators);
+    handler.wire_format = args.format;
+    handler.topics = broker.topics.clone();
+    handler.dead_letter_topic = args.dead_letter_topic;
+    handler.max_payload_bytes = args.max_payload_bytes;
+    let handler = Arc::new(handler);
+    let permits = Arc::new(Semaphore::new(concurrency));
+    let direct_topic = broker.topics.direct_request(&slave_id);
+    let announcement = SlaveOnline { slave_id: slave_id.clone(), request_topic: direct_topic.clone() };
+
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        if let Err(e) = signal_hook::flag::register(signal, shutdown.clone()) {
+            error!("Failed to install handler for signal {}: {:?}", signal, e);
+        }
+    }
+    let mut shutdown_check = tokio::time::interval(Duration::from_millis(100));
+    let metrics_interval = Duration::from_secs(env_var("METRICS_INTERVAL_SECS").unwrap_or(10).max(1));
+    let mut metrics_tick = tokio::time::interval(metrics_interval);
+    metrics_tick.tick().await;
+    let mut backoff = Backoff::from_env();
+    loop {
+        let event = tokio::select! {
+            _ = shutdown_check.tick() => {
+                if shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+                continue;
+            }
+            _ = metrics_tick.tick() => {
+                let handler = handler.clone();
+                tokio::task::spawn_blocking(move || publish_metrics(&handler));
+                continue;
+            }
+            event = eventloop.poll() => event,
+        };
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                backoff.reset();
+                info!("Connected to the broker");
+                handler.metrics.connection.lock().unwrap().on_connect();
+                // A clean session forgets subscriptions, so they're made again
+                // on every connect. `try_` because awaiting the request queue
+                // here would wait on the loop that drains it.
+                for topic in [&broker.topics.request, &direct_topic] {
+                    if let Err(e) = client.try_subscribe(topic.as_str(), broker.qos) {
+                        error!("Failed to subscribe to {}: {}", topic, e);
+                    }
+                }
+                if let Err(e) = client.try_subscribe(SlaveStatus::REQUEST_TOPIC, QoS::AtLeastOnce) {
+                    error!("Failed to subscribe to {}: {}", SlaveStatus::REQUEST_TOPIC, e);
+                }
+                handler.announce(&announcement);
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == SlaveStatus::REQUEST_TOPIC => {
+                let handler = handler.clone();
+                let slave_id = slave_id.clone();
+                tokio::task::spawn_blocking(move || publish_status(&handler, &slave_id, started));
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                // The permit is taken inside the task: responses only leave
+                // while this loop keeps polling, so blocking it on a permit
+                // would deadlock against tasks waiting to publish.
+                let handler = handler.clone();
+                let permits = permits.clone();
+                tokio::spawn(async move {
+                    let Ok(_permit) = permits.acquire().await else {
+                        return;
+                    };
+                    let handled = tokio::task::spawn_blocking(move || handler.handle_message(&publish.payload)).await;
+                    if let Err(e) = handled {
+                        error!("Processing task failed: {}", e);
+                    }
+                });
+            }
+            Ok(Event::Incoming(Packet::PubAck(_) | Packet::PubComp(_))) => {
+                handler.metrics.publish_confirms.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                handler.metrics.connection.lock().unwrap().on_disconnect();
+                let reason = DisconnectReason::classify(&e);
+                handler.metrics.record_disconnect(reason);
+                let delay = backoff.next_delay().max(reason.reconnect_delay());
+                warn!("Connection error ({}): {}; reconnect attempt {} in {:?}", reason.as_str(), e, backoff.attempt(), delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+
+    info!("Shutting down, waiting for in-flight messages");
+    let drain = async {
+        // Every permit back means every task has finished; the event loop
+        // keeps running meanwhile so their responses go out.
+        let all = permits.acquire_many(concurrency as u32);
+        tokio::pin!(all);
+        loop {
+            tokio::select! {
+                _ = &mut all => break,
+                event = eventloop.poll() => if event.is_err() { break },
+            }
+        }
+    };
+    if tokio::time::timeout(Duration::from_secs(5), drain).await.is_err() {
+        warn!("Gave up waiting for in-flight messages");
+    }
+    let final_handler = handler.clone();
+    let _ = tokio::task::spawn_blocking(move || {
+        publish_metrics(&final_handler);
+        final_handler.withdraw(&slave_id);
+    })
+    .await;
+    let _ = client.disconnect().await;
+    // Flush the metrics and the disconnect.
+    while let Ok(Ok(event)) = tokio::time::timeout(Duration::from_secs(1), eventloop.poll()).await {
+        if let Event::Outgoing(rumqttc::Outgoing::Disconnect) = event {
+            break;
+        }
+    }
+    match serde_json::to_string_pretty(&handler.metrics.snapshot()) {
+        Ok(snapshot) => info!("Final metrics:\n{}", snapshot),
+        Err(e) => error!("Failed to serialize metrics: {}", e),
+    }
+    Ok(())
+}
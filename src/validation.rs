@@ -0,0 +1,291 @@
+use crate::common::DataPayload;
+use std::ops::RangeInclusive;
+
+/// A check run on every converted payload before it is processed.
+pub trait Validator: Send + Sync {
+    fn validate(&self, payload: &DataPayload) -> Result<(), String>;
+}
+
+/// Rejects NaN and infinite values in any numeric field.
+pub struct Finite;
+
+impl Validator for Finite {
+    fn validate(&self, payload: &DataPayload) -> Result<(), String> {
+        let values: Vec<(&str, f64)> = match payload {
+            DataPayload::Coordinates { x, y, z, .. } => vec![("x", *x), ("y", *y), ("z", *z)],
+            DataPayload::SensorData { temperature, humidity, pressure, .. } => {
+                vec![("temperature", *temperature), ("humidity", *humidity), ("pressure", *pressure)]
+            }
+            DataPayload::TimeSeries { points, .. } => points.iter().map(|p| ("point value", p.value)).collect(),
+            _ => Vec::new(),
+        };
+        match values.into_iter().find(|(_, value)| !value.is_finite()) {
+            Some((field, value)) => Err(format!("{} is not finite ({})", field, value)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Rejects sensor readings outside physically plausible ranges.
+pub struct SensorRange {
+    pub temperature: RangeInclusive<f64>,
+    pub humidity: RangeInclusive<f64>,
+    pub pressure: RangeInclusive<f64>,
+}
+
+impl Default for SensorRange {
+    /// °C, % relative humidity and hPa.
+    fn default() -> Self {
+        Self {
+            temperature: -90.0..=60.0,
+            humidity: 0.0..=100.0,
+            pressure: 300.0..=1100.0,
+        }
+    }
+}
+
+impl SensorRange {
+    /// The first reading outside its range, with the range it missed.
+    pub fn out_of_range(
+        &self,
+        temperature: f64,
+        humidity: f64,
+        pressure: f64,
+    ) -> Option<(&'static str, f64, &RangeInclusive<f64>)> {
+        [
+            ("temperature", temperature, &self.temperature),
+            ("humidity", humidity, &self.humidity),
+            ("pressure", pressure, &self.pressure),
+        ]
+        .into_iter()
+        .find(|(_, value, range)| !range.contains(value))
+    }
+}
+
+impl Validator for SensorRange {
+    fn validate(&self, payload: &DataPayload) -> Result<(), String> {
+        let DataPayload::SensorData { temperature, humidity, pressure, .. } = payload else {
+            return Ok(());
+        };
+        match self.out_of_range(*temperature, *humidity, *pressure) {
+            Some((field, value, range)) => Err(format!(
+                "{} {} outside {}..={}",
+                field, value, range.start(), range.end()
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Rejects empty text, log messages, image buffers and series ids.
+pub struct NonEmpty;
+
+impl Validator for NonEmpty {
+    fn validate(&self, payload: &DataPayload) -> Result<(), String> {
+        let empty = match payload {
+            DataPayload::Text(text) => text.trim().is_empty().then_some("text"),
+            DataPayload::LogEntry { message, .. } => message.trim().is_empty().then_some("log message"),
+            DataPayload::ImageData { data, .. } => data.is_empty().then_some("image data"),
+            DataPayload::SensorData { sensor_id, .. } => sensor_id.trim().is_empty().then_some("sensor id"),
+            DataPayload::TimeSeries { series_id, .. } => series_id.trim().is_empty().then_some("series id"),
+            DataPayload::Reference { uri, .. } => uri.trim().is_empty().then_some("reference uri"),
+            _ => None,
+        };
+        match empty {
+            Some(field) => Err(format!("{} is empty", field)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Rejects images whose buffer doesn't hold exactly `width * height` pixels
+/// of their format.
+pub struct ImageSize;
+
+impl Validator for ImageSize {
+    fn validate(&self, payload: &DataPayload) -> Result<(), String> {
+        match payload {
+            DataPayload::ImageData { width, height, format, data } => {
+                check_image_buffer(*width, *height, format, data).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn bytes_per_pixel(format: &str) -> Option<usize> {
+    match format.to_ascii_uppercase().as_str() {
+        "RGB" => Some(3),
+        "RGBA" => Some(4),
+        "GRAY" | "GREY" => Some(1),
+        _ => None,
+    }
+}
+
+/// Checks that the buffer holds exactly `width * height` pixels of `format`,
+/// returning the bytes per pixel.
+pub fn check_image_buffer(width: u32, height: u32, format: &str, data: &[u8]) -> Result<usize, String> {
+    let bpp = bytes_per_pixel(format)
+        .ok_or_else(|| format!("unknown image format {}", format))?;
+    let expected = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(bpp))
+        .ok_or_else(|| "image dimensions overflow".to_string())?;
+    if data.len() != expected {
+        return Err(format!(
+            "buffer size mismatch: expected {} bytes for {}x{} {}, got {}",
+            expected, width, height, format, data.len()
+        ));
+    }
+    Ok(bpp)
+}
+
+/// Looks up a built-in validator by the name used in `VALIDATORS`.
+pub fn builtin(name: &str) -> Option<Box<dyn Validator>> {
+    match name {
+        "finite" => Some(Box::new(Finite)),
+        "sensor_range" => Some(Box::new(SensorRange::default())),
+        "non_empty_text" | "non_empty" => Some(Box::new(NonEmpty)),
+        "image_size" => Some(Box::new(ImageSize)),
+        _ => None,
+    }
+}
+
+/// Validators run in order; the first failure wins.
+#[derive(Default)]
+pub struct ValidatorChain {
+    validators: Vec<(String, Box<dyn Validator>)>,
+}
+
+impl ValidatorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a chain from a comma-separated list of built-in names, e.g.
+    /// `sensor_range,finite,non_empty_text`.
+    pub fn from_names(list: &str) -> Result<Self, String> {
+        let mut chain = Self::new();
+        for name in list.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let validator = builtin(name).ok_or_else(|| format!("unknown validator {}", name))?;
+            chain.validators.push((name.to_string(), validator));
+        }
+        Ok(chain)
+    }
+
+    /// The chain named by `VALIDATORS`; empty when it isn't set. An unknown
+    /// name is an error so a typo can't silently turn validation off.
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("VALIDATORS") {
+            Ok(list) => Self::from_names(&list).map_err(|e| format!("invalid VALIDATORS: {}", e)),
+            Err(_) => Ok(Self::new()),
+        }
+    }
+
+    /// Appends a custom validator, reported under `name` when it fails.
+    pub fn with(mut self, name: &str, validator: impl Validator + 'static) -> Self {
+        self.validators.push((name.to_string(), Box::new(validator)));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    /// Batches are checked item by item.
+    pub fn validate(&self, payload: &DataPayload) -> Result<(), String> {
+        if let DataPayload::Batch(items) = payload {
+            return items.iter().enumerate().try_for_each(|(i, item)| {
+                self.validate(item).map_err(|e| format!("batch item {}: {}", i, e))
+            });
+        }
+        for (name, validator) in &self.validators {
+            validator.validate(payload).map_err(|e| format!("{}: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TimeSeriesPoint;
+
+    fn sensor(temperature: f64, humidity: f64, pressure: f64) -> DataPayload {
+        DataPayload::SensorData { sensor_id: "S1".to_string(), temperature, humidity, pressure }
+    }
+
+    fn image(format: &str, data: Vec<u8>) -> DataPayload {
+        DataPayload::ImageData { width: 2, height: 2, format: format.to_string(), data }
+    }
+
+    #[test]
+    fn finite_rejects_nan_and_infinity() {
+        assert!(Finite.validate(&sensor(20.0, 50.0, 1000.0)).is_ok());
+        assert!(Finite.validate(&sensor(f64::NAN, 50.0, 1000.0)).is_err());
+        let series = DataPayload::TimeSeries {
+            series_id: "T".to_string(),
+            points: vec![TimeSeriesPoint { timestamp: "t".to_string(), value: f64::INFINITY }],
+        };
+        assert_eq!(Finite.validate(&series).unwrap_err(), "point value is not finite (inf)");
+    }
+
+    #[test]
+    fn sensor_range_rejects_implausible_readings() {
+        assert!(SensorRange::default().validate(&sensor(20.0, 50.0, 1000.0)).is_ok());
+        assert_eq!(
+            SensorRange::default().validate(&sensor(20.0, 101.0, 1000.0)).unwrap_err(),
+            "humidity 101 outside 0..=100"
+        );
+        assert!(SensorRange::default().validate(&DataPayload::Text("not a sensor".to_string())).is_ok());
+    }
+
+    #[test]
+    fn non_empty_rejects_blank_fields() {
+        assert!(NonEmpty.validate(&DataPayload::Text("hello".to_string())).is_ok());
+        assert_eq!(NonEmpty.validate(&DataPayload::Text("  ".to_string())).unwrap_err(), "text is empty");
+        assert_eq!(NonEmpty.validate(&image("RGB", Vec::new())).unwrap_err(), "image data is empty");
+    }
+
+    #[test]
+    fn image_size_rejects_mismatched_buffers() {
+        assert!(ImageSize.validate(&image("RGB", vec![0; 12])).is_ok());
+        assert!(ImageSize.validate(&image("RGB", vec![0; 11])).is_err());
+    }
+
+    #[test]
+    fn chain_names_the_failing_validator() {
+        let chain = ValidatorChain::from_names("finite, sensor_range").unwrap();
+        assert_eq!(
+            chain.validate(&sensor(-100.0, 50.0, 1000.0)).unwrap_err(),
+            "sensor_range: temperature -100 outside -90..=60"
+        );
+        let batch = DataPayload::Batch(vec![DataPayload::Number(1.into()), sensor(f64::NAN, 50.0, 1000.0)]);
+        assert!(chain.validate(&batch).unwrap_err().starts_with("batch item 1: finite:"));
+    }
+
+    #[test]
+    fn unknown_validator_name_is_an_error() {
+        assert_eq!(ValidatorChain::from_names("finite,sensor_rnage").err().unwrap(), "unknown validator sensor_rnage");
+    }
+
+    #[test]
+    fn check_image_buffer_knows_each_format() {
+        assert_eq!(check_image_buffer(2, 3, "RGB", &[0; 18]), Ok(3));
+        assert_eq!(check_image_buffer(2, 3, "RGBA", &[0; 24]), Ok(4));
+        assert_eq!(check_image_buffer(2, 3, "GRAY", &[0; 6]), Ok(1));
+        assert_eq!(check_image_buffer(2, 3, "grey", &[0; 6]), Ok(1));
+        assert_eq!(check_image_buffer(0, 3, "RGB", &[]), Ok(3));
+    }
+
+    #[test]
+    fn check_image_buffer_rejects_bad_buffers() {
+        assert_eq!(
+            check_image_buffer(2, 3, "RGB", &[0; 17]).unwrap_err(),
+            "buffer size mismatch: expected 18 bytes for 2x3 RGB, got 17"
+        );
+        assert!(check_image_buffer(2, 3, "RGBA", &[0; 18]).is_err());
+        assert_eq!(check_image_buffer(2, 3, "CMYK", &[0; 24]).unwrap_err(), "unknown image format CMYK");
+        assert_eq!(check_image_buffer(u32::MAX, u32::MAX, "RGBA", &[]).unwrap_err(), "image dimensions overflow");
+    }
+}
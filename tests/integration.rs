@@ -0,0 +1,460 @@
+//! End-to-end checks of the publish/subscribe flow against an in-process
+//! rumqttd broker and a slave started with [`run_slave`]: one packet of every
+//! payload variant, each of which must be answered on `data/response`, and
+//! a status request, which must be answered with the slave's counters. The
+//! async slave is checked for answering messages it can't process. Acks
+//! must go out while the packet is still being processed. A slave's
+//! announcement must be retained for late subscribers and cleared when it
+//! shuts down. A slave given `--tls` must answer through the broker's TLS
+//! listener, which uses the test CA and `localhost` certificate in
+//! `fixtures/tls`. The master's `--record` file must hold exactly the
+//! packets it published, stamps included. Every binary must exit with
+//! status 2 on arguments it doesn't understand.
+//!
+//! Needs the broker, so it only builds with
+//! `cargo test --features integration-tests`.
+#![cfg(feature = "integration-tests")]
+
+use chrono::Utc;
+use mqtt::common::{
+    Ack, CoordinateSystem, DataPacket, DataPayload, DataResponse, ResponseStatus, SlaveOnline, SlaveStatus, TimeSeriesPoint,
+};
+use mqtt::hooks::ProcessingHooks;
+use mqtt::processing::DefaultProcessor;
+use mqtt::slave::{run_slave, run_slave_async, AsyncSlaveConfig, SlaveConfig};
+use mqtt::validation::ValidatorChain;
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use rumqttd::{Broker, Config, ConnectionSettings, RouterConfig, ServerSettings, TlsConfig};
+use std::collections::{HashMap, HashSet};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Stops the slave when the test ends, pass or fail.
+struct Slave(Arc<AtomicBool>);
+
+impl Drop for Slave {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn start_broker(port: u16) {
+    start_broker_with(HashMap::from([("v4".to_string(), listener("v4", port, None))]));
+}
+
+/// A broker with a plain listener on `port` and a TLS one on `tls_port`,
+/// using the test certificates in `fixtures/tls`.
+fn start_tls_broker(port: u16, tls_port: u16) {
+    let tls = TlsConfig::Rustls { capath: None, certpath: fixture("tls/server.pem"), keypath: fixture("tls/server-key.pem") };
+    start_broker_with(HashMap::from([
+        ("v4".to_string(), listener("v4", port, None)),
+        ("v4-tls".to_string(), listener("v4-tls", tls_port, Some(tls))),
+    ]));
+}
+
+fn fixture(path: &str) -> String {
+    format!("{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), path)
+}
+
+fn listener(name: &str, port: u16, tls: Option<TlsConfig>) -> ServerSettings {
+    ServerSettings {
+        name: name.to_string(),
+        listen: ([127, 0, 0, 1], port).into(),
+        tls,
+        next_connection_delay_ms: 1,
+        connections: ConnectionSettings {
+            connection_timeout_ms: 5000,
+            max_payload_size: 1024 * 1024,
+            max_inflight_count: 100,
+            auth: None,
+            external_auth: None,
+            dynamic_filters: true,
+        },
+    }
+}
+
+fn start_broker_with(listeners: HashMap<String, ServerSettings>) {
+    let config = Config {
+        router: RouterConfig {
+            max_connections: 10,
+            max_outgoing_packet_count: 200,
+            max_segment_size: 10 * 1024 * 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        },
+        v4: Some(listeners),
+        ..Default::default()
+    };
+    thread::spawn(move || Broker::new(config).start().unwrap());
+}
+
+/// Connects the test's own client, subscribes it to `topics` and hands every
+/// publish it receives to the returned channel as `(topic, payload)`.
+/// Returns once the broker has acknowledged the subscriptions, so nothing
+/// published afterwards is missed.
+fn connect(port: u16, topics: &[&str]) -> (Client, Receiver<(String, Vec<u8>)>) {
+    static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+    let client_id = format!("integration-test-{}", CLIENTS.fetch_add(1, Ordering::Relaxed));
+    let mut options = MqttOptions::new(client_id, "127.0.0.1", port);
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, mut connection) = Client::new(options, 20);
+    let (sender, received) = mpsc::channel();
+    let (subscribed, suback) = mpsc::channel();
+    thread::spawn(move || {
+        for event in connection.iter() {
+            match event {
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    if sender.send((publish.topic, publish.payload.to_vec())).is_err() {
+                        break;
+                    }
+                }
+                Ok(Event::Incoming(Packet::SubAck(_))) => {
+                    let _ = subscribed.send(());
+                }
+                Ok(_) => {}
+                // The broker may not be listening yet; the client retries.
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    });
+    for topic in topics {
+        client.subscribe(*topic, QoS::AtLeastOnce).unwrap();
+    }
+    for _ in topics {
+        suback.recv_timeout(TIMEOUT).expect("broker never acknowledged the subscriptions");
+    }
+    (client, received)
+}
+
+/// Starts the slave and waits for its announcement, which it sends once
+/// subscribed. `received` must already carry [`SlaveOnline::FILTER`].
+fn start_slave(port: u16, received: &Receiver<(String, Vec<u8>)>, deadline: Instant) -> Slave {
+    start_slave_with(port, received, deadline, &[], ProcessingHooks::new())
+}
+
+/// [`start_slave`] with extra flags and `hooks` in place of the default
+/// processing.
+fn start_slave_with(
+    port: u16,
+    received: &Receiver<(String, Vec<u8>)>,
+    deadline: Instant,
+    extra_args: &[&str],
+    hooks: ProcessingHooks,
+) -> Slave {
+    let port = port.to_string();
+    let args = ["--broker-host", "127.0.0.1", "--broker-port", &port, "--heartbeat-secs", "0", "--metrics-interval-secs", "0"];
+    let config = SlaveConfig::from_args(args.iter().chain(extra_args).map(|arg| arg.to_string())).unwrap();
+    let slave = Slave(config.shutdown.clone());
+    thread::spawn(move || {
+        run_slave(config, Box::new(DefaultProcessor::default()), hooks, ValidatorChain::new()).unwrap()
+    });
+    wait_online(received, deadline);
+    slave
+}
+
+/// Waits for a slave's announcement on `received`.
+fn wait_online(received: &Receiver<(String, Vec<u8>)>, deadline: Instant) {
+    loop {
+        let (topic, payload) = received
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .expect("slave never came online");
+        if SlaveOnline::slave_id_from_topic(&topic).is_some() && !payload.is_empty() {
+            return;
+        }
+    }
+}
+
+/// The next message on `topic`, skipping everything else.
+fn next_on(received: &Receiver<(String, Vec<u8>)>, topic: &str, deadline: Instant) -> Vec<u8> {
+    loop {
+        let (received_topic, payload) = received
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .unwrap_or_else(|_| panic!("nothing on {}", topic));
+        if received_topic == topic {
+            return payload;
+        }
+    }
+}
+
+fn samples() -> Vec<DataPayload> {
+    vec![
+        DataPayload::Text("integration".to_string()),
+        DataPayload::Number(42.into()),
+        DataPayload::Coordinates { x: 1.0, y: 2.0, z: 3.0, system: CoordinateSystem::Cartesian },
+        DataPayload::SensorData { sensor_id: "IT".to_string(), temperature: 21.5, humidity: 40.0, pressure: 1013.0 },
+        DataPayload::ImageData { width: 2, height: 2, format: "RGB".to_string(), data: vec![0; 12] },
+        DataPayload::LogEntry { level: "INFO".to_string(), message: "integration".to_string(), timestamp: Utc::now().to_rfc3339() },
+        DataPayload::TimeSeries {
+            series_id: "IT".to_string(),
+            points: vec![TimeSeriesPoint { timestamp: Utc::now().to_rfc3339(), value: 1.0 }],
+        },
+        DataPayload::Reference {
+            uri: "file:///dev/null".to_string(),
+            size: 0,
+            content_type: "text/plain".to_string(),
+            checksum: "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+        },
+        DataPayload::Json(serde_json::json!({ "integration": true })),
+        DataPayload::Batch(vec![DataPayload::Text("one".to_string()), DataPayload::Number(2.into())]),
+    ]
+}
+
+#[test]
+fn every_payload_variant_is_answered() {
+    let port = free_port();
+    start_broker(port);
+    let (client, received) = connect(port, &[SlaveOnline::FILTER, "data/response"]);
+
+    let deadline = Instant::now() + TIMEOUT;
+    let _slave = start_slave(port, &received, deadline);
+
+    let mut expected = HashMap::new();
+    for payload in samples() {
+        let packet = DataPacket::builder(payload).metadata("version", "1.0").build();
+        expected.insert(packet.id.clone(), packet.data_type.clone());
+        client.publish("data/request", QoS::AtLeastOnce, false, serde_json::to_vec(&packet).unwrap()).unwrap();
+    }
+
+    let mut answered = HashSet::new();
+    while answered.len() < expected.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let Ok((topic, payload)) = received.recv_timeout(remaining) else {
+            let missing: Vec<_> = expected.iter().filter(|(id, _)| !answered.contains(*id)).map(|(_, t)| t).collect();
+            panic!("no response for {:?}", missing);
+        };
+        if topic != "data/response" {
+            continue;
+        }
+        let response: DataResponse = serde_json::from_slice(&payload).unwrap();
+        let Some(data_type) = expected.get(&response.packet_id) else {
+            continue;
+        };
+        assert!(response.status.is_ok(), "{} packet answered {}", data_type, response.status);
+        answered.insert(response.packet_id);
+    }
+}
+
+#[test]
+fn status_request_reports_counters() {
+    let port = free_port();
+    start_broker(port);
+    let (client, received) = connect(port, &[SlaveOnline::FILTER, "data/response", SlaveStatus::RESPONSE_TOPIC]);
+    let deadline = Instant::now() + TIMEOUT;
+    let _slave = start_slave(port, &received, deadline);
+
+    // Something to count: one text packet, answered before asking.
+    let packet = DataPacket::builder(DataPayload::Text("status".to_string())).build();
+    client.publish("data/request", QoS::AtLeastOnce, false, serde_json::to_vec(&packet).unwrap()).unwrap();
+    let response: DataResponse = serde_json::from_slice(&next_on(&received, "data/response", deadline)).unwrap();
+    assert_eq!(response.packet_id, packet.id);
+
+    client.publish(SlaveStatus::REQUEST_TOPIC, QoS::AtLeastOnce, false, Vec::new()).unwrap();
+    let status: SlaveStatus = serde_json::from_slice(&next_on(&received, SlaveStatus::RESPONSE_TOPIC, deadline)).unwrap();
+    assert!(status.slave_id.starts_with("slave-node-"), "unexpected slave id {}", status.slave_id);
+    assert!(status.uptime_secs < TIMEOUT.as_secs());
+    assert_eq!(status.metrics.processed_count, 1);
+    assert_eq!(status.metrics.text_count, 1);
+    assert_eq!(status.metrics.number_count, 0);
+    // The announcement, the ack and the response, all at QoS 1.
+    assert_eq!(status.metrics.publish_attempts, 3);
+}
+
+#[test]
+fn async_slave_answers_what_it_cannot_process() {
+    let port = free_port();
+    start_broker(port);
+    let (client, received) = connect(port, &[SlaveOnline::FILTER, "data/response"]);
+    let deadline = Instant::now() + TIMEOUT;
+
+    let port_arg = port.to_string();
+    let args = ["--broker-host", "127.0.0.1", "--broker-port", &port_arg, "--concurrency", "2"];
+    let config = AsyncSlaveConfig::from_args(args.map(str::to_string)).unwrap();
+    let _slave = Slave(config.shutdown.clone());
+    let validators = ValidatorChain::from_names("sensor_range").unwrap();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(run_slave_async(config, Box::new(DefaultProcessor::default()), ProcessingHooks::new(), validators))
+            .unwrap()
+    });
+    wait_online(&received, deadline);
+
+    let valid = DataPacket::builder(DataPayload::Text("fine".to_string())).build();
+    let out_of_range = DataPacket::builder(DataPayload::SensorData {
+        sensor_id: "IT".to_string(),
+        temperature: 500.0,
+        humidity: 40.0,
+        pressure: 1013.0,
+    })
+    .build();
+    let messages = [
+        serde_json::to_vec(&valid).unwrap(),
+        serde_json::to_vec(&out_of_range).unwrap(),
+        br#"{"id": "no-payload"}"#.to_vec(),
+        br#"{"id": "unknown-payload", "payload": {"Unknown": 1}}"#.to_vec(),
+    ];
+    for message in messages {
+        client.publish("data/request", QoS::AtLeastOnce, false, message).unwrap();
+    }
+
+    let mut statuses = HashMap::new();
+    while statuses.len() < 4 {
+        let response: DataResponse = serde_json::from_slice(&next_on(&received, "data/response", deadline)).unwrap();
+        statuses.insert(response.packet_id, response.status);
+    }
+    assert!(statuses[&valid.id].is_ok(), "valid packet answered {}", statuses[&valid.id]);
+    assert!(matches!(statuses[&out_of_range.id], ResponseStatus::ValidationError(_)), "{}", statuses[&out_of_range.id]);
+    assert!(matches!(statuses["no-payload"], ResponseStatus::ParseError(_)), "{}", statuses["no-payload"]);
+    assert_eq!(statuses["unknown-payload"], ResponseStatus::ConversionError);
+}
+
+#[test]
+fn packets_are_acked_before_they_are_processed() {
+    let port = free_port();
+    start_broker(port);
+    let (client, received) = connect(port, &[SlaveOnline::FILTER, "data/ack", "data/response"]);
+    let deadline = Instant::now() + TIMEOUT;
+    // Text processing waits for the test's go-ahead.
+    let (release, released) = mpsc::channel::<()>();
+    let released = Mutex::new(released);
+    let hooks = ProcessingHooks::new().on_text(move |_| {
+        released.lock().unwrap().recv_timeout(TIMEOUT).unwrap();
+        ResponseStatus::Ok("released".to_string())
+    });
+    // One worker, so the second packet queues behind the first.
+    let _slave = start_slave_with(port, &received, deadline, &["--workers", "1"], hooks);
+
+    let packets: Vec<DataPacket> = (0..2).map(|_| DataPacket::builder(DataPayload::Text("hold".to_string())).build()).collect();
+    for packet in &packets {
+        client.publish("data/request", QoS::AtLeastOnce, false, serde_json::to_vec(packet).unwrap()).unwrap();
+    }
+    // Both are acked while the only worker is still stuck on the first.
+    for packet in &packets {
+        let (topic, payload) = received.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(topic, "data/ack", "a response came before the acks");
+        let ack: Ack = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(ack.packet_id, packet.id);
+    }
+
+    for packet in &packets {
+        release.send(()).unwrap();
+        let response: DataResponse = serde_json::from_slice(&next_on(&received, "data/response", deadline)).unwrap();
+        assert_eq!(response.packet_id, packet.id);
+        assert_eq!(response.status, ResponseStatus::Ok("released".to_string()));
+    }
+}
+
+#[test]
+fn announcement_is_retained_until_shutdown() {
+    let port = free_port();
+    start_broker(port);
+    let (_early, received) = connect(port, &[SlaveOnline::FILTER]);
+    let deadline = Instant::now() + TIMEOUT;
+    let slave = start_slave(port, &received, deadline);
+
+    // Subscribed only after the slave announced itself.
+    let (_late, late_received) = connect(port, &[SlaveOnline::FILTER]);
+    let (topic, payload) = late_received.recv_timeout(TIMEOUT).expect("announcement wasn't retained");
+    let announcement: SlaveOnline = serde_json::from_slice(&payload).unwrap();
+    assert_eq!(topic, SlaveOnline::topic(&announcement.slave_id));
+    assert_eq!(announcement.request_topic, format!("data/request/{}", announcement.slave_id));
+
+    drop(slave);
+    let cleared = next_on(&late_received, &topic, deadline);
+    assert!(cleared.is_empty(), "expected the announcement to be cleared, got {:?}", cleared);
+}
+
+#[test]
+fn slave_answers_over_tls() {
+    let (port, tls_port) = (free_port(), free_port());
+    start_tls_broker(port, tls_port);
+    // The test itself stays on the plain listener.
+    let (client, received) = connect(port, &[SlaveOnline::FILTER, "data/response"]);
+    let deadline = Instant::now() + TIMEOUT;
+
+    let tls_port = tls_port.to_string();
+    let ca = fixture("tls/ca.pem");
+    let args = ["--broker-host", "localhost", "--broker-port", &tls_port, "--tls", "--ca-cert", &ca, "--heartbeat-secs", "0"];
+    let config = SlaveConfig::from_args(args.map(str::to_string)).unwrap();
+    let _slave = Slave(config.shutdown.clone());
+    thread::spawn(move || {
+        run_slave(config, Box::new(DefaultProcessor::default()), ProcessingHooks::new(), ValidatorChain::new()).unwrap()
+    });
+    wait_online(&received, deadline);
+
+    let packet = DataPacket::builder(DataPayload::Text("secure".to_string())).build();
+    client.publish("data/request", QoS::AtLeastOnce, false, serde_json::to_vec(&packet).unwrap()).unwrap();
+    let response: DataResponse = serde_json::from_slice(&next_on(&received, "data/response", deadline)).unwrap();
+    assert_eq!(response.packet_id, packet.id);
+    assert!(response.status.is_ok(), "answered {}", response.status);
+}
+
+#[test]
+fn invalid_arguments_exit_with_status_2() {
+    let binaries = [
+        env!("CARGO_BIN_EXE_master"),
+        env!("CARGO_BIN_EXE_slave"),
+        env!("CARGO_BIN_EXE_slave_async"),
+        env!("CARGO_BIN_EXE_requeue"),
+        env!("CARGO_BIN_EXE_balancer"),
+    ];
+    for binary in binaries {
+        let output = std::process::Command::new(binary).arg("--no-such-flag").output().unwrap();
+        assert_eq!(output.status.code(), Some(2), "{} exited with {}", binary, output.status);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid arguments"));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn recorded_packets_match_what_was_published() {
+    let port = free_port();
+    start_broker(port);
+    let (_client, received) = connect(port, &["data/request"]);
+    let record = std::env::temp_dir().join(format!("integration-record-{}.jsonl", uuid::Uuid::new_v4()));
+    let mut master = std::process::Command::new(env!("CARGO_BIN_EXE_master"))
+        .args(["--broker-host", "127.0.0.1", "--broker-port", &port.to_string()])
+        .args(["--record", record.to_str().unwrap(), "--rate", "50", "--request-timeout-ms", "100"])
+        .args(["--payload-types", "text,number,sensor_data", "--compress-threshold-bytes", "0"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut published: Vec<DataPacket> =
+        (0..5).map(|_| serde_json::from_slice(&next_on(&received, "data/request", deadline)).unwrap()).collect();
+    // SIGTERM stops the send loop, which flushes the recording on its way out.
+    // SAFETY: kill has no memory-safety preconditions.
+    assert_eq!(unsafe { libc::kill(master.id() as libc::pid_t, libc::SIGTERM) }, 0);
+    assert!(master.wait().unwrap().success());
+
+    let recorded: Vec<DataPacket> = std::fs::read_to_string(&record)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&record).unwrap();
+    // Whatever went out between the fifth packet and the signal.
+    while published.len() < recorded.len() {
+        published.push(serde_json::from_slice(&next_on(&received, "data/request", deadline)).unwrap());
+    }
+    assert!(recorded.len() >= 5);
+    for (recorded, published) in recorded.iter().zip(&published) {
+        for key in ["master_id", "seq", "crc32"] {
+            assert!(recorded.metadata.contains_key(key), "{} missing from {}", key, recorded.id);
+        }
+        assert_eq!(serde_json::to_value(recorded).unwrap(), serde_json::to_value(published).unwrap());
+    }
+    assert_eq!(recorded.len(), published.len());
+}